@@ -0,0 +1,40 @@
+//! Times the interpreter on a tight counting loop.  Run with `cargo bench`;
+//! the script is run several times on each engine and the fastest and mean
+//! times reported.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+const ENGINES: [&str; 2] = ["tree", "vm"];
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn main() {
+    let script = concat!(env!("CARGO_MANIFEST_DIR"),
+                         "/benches/counting_loop.jsish");
+    for engine in &ENGINES {
+        let mut times = Vec::new();
+
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            let output = Command::new(env!("CARGO_BIN_EXE_jsish-rust"))
+                .arg(format!("--engine={}", engine))
+                .arg(script)
+                .output()
+                .expect("could not run the interpreter");
+            times.push(start.elapsed());
+
+            assert!(output.status.success() && output.stderr.is_empty(),
+                    "counting loop failed: {}",
+                    String::from_utf8_lossy(&output.stderr));
+        }
+
+        let best = times.iter().min().cloned().unwrap_or_default();
+        let total = times.iter().fold(Duration::new(0, 0), |acc, &t| acc + t);
+        println!("counting_loop ({}): best {:.1} ms, mean {:.1} ms over {} runs",
+                 engine, millis(best), millis(total / RUNS), RUNS);
+    }
+}
@@ -0,0 +1,113 @@
+"""Writes the malformed bytecode files run by tests/hw*/6_jsbc.
+
+Each file is named for the test whose .correct holds the expected output.
+The files are built at test time, in the format version src/jsbc.rs
+declares, so that a change to the version leaves each one testing what it
+was written to test.
+
+    python3 scripts/gen-jsbc-tests.py <directory>
+"""
+
+import re
+import struct
+import sys
+
+from os import path
+
+root = path.dirname(path.dirname(path.abspath(__file__)))
+
+with open(path.join(root, 'src', 'jsbc.rs')) as source:
+    version = int(re.search(r'FORMAT_VERSION: u32 = (\d+);',
+                            source.read()).group(1))
+
+
+def u8(n):
+    return bytes([n])
+
+
+def u32(n):
+    return struct.pack('<I', n)
+
+
+def string(s):
+    b = s.encode()
+    return u32(len(b)) + b
+
+
+def var(depth, index, name):
+    return u32(depth) + u32(index) + u32(name) + u8(0)
+
+
+# The opcodes used below, as jsbc.rs encodes them
+ops = {
+    'Const': lambda k: u8(0) + u32(k),
+    'Undefined': lambda: u8(1),
+    'True': lambda: u8(2),
+    'Pop': lambda: u8(6),
+    'Load': lambda depth, index, name: u8(8) + var(depth, index, name),
+    'Declare': lambda index, name: u8(10) + u32(index) + u32(name),
+    'PopScope': lambda: u8(13),
+    'JumpIf': lambda target: u8(21) + u32(target),
+    'Return': lambda: u8(28),
+    'Arg': lambda index: u8(29) + u32(index),
+    'Print': lambda: u8(32),
+    'Halt': lambda: u8(38),
+}
+
+
+# A module of no constants, the given names, and the given functions, each
+# unnamed and a list of instructions on line 1
+def module(names, functions):
+    body = u32(0) + u32(len(names)) + b''.join(string(n) for n in names)
+    body += u32(len(functions))
+    for code in functions:
+        body += u8(0) + u8(0) + u32(len(code))
+        body += b''.join(ops[op[0]](*op[1:]) for op in code)
+        body += b''.join(u32(1) for _ in code)
+    return body
+
+
+def checksum(body):
+    h = 0x811c9dc5
+    for b in body:
+        h = ((h ^ b) * 0x01000193) & 0xffffffff
+    return h
+
+
+def bytecode_file(body, version=version, length=None, sum=None):
+    return (b'JSBC' + u32(version)
+            + u32(len(body) if length is None else length)
+            + u32(checksum(body) if sum is None else sum)
+            + body)
+
+
+hello = module([], [[('Undefined',), ('Print',), ('Halt',)]])
+flipped = bytes([hello[0] ^ 1]) + hello[1:]
+
+cases = {
+    'badjsbc01': bytecode_file(module([], [[('Pop',), ('Halt',)]])),
+    'badjsbc02': bytecode_file(module(['x'], [[('Load', 5, 0, 0),
+                                               ('Print',),
+                                               ('Halt',)]])),
+    'badjsbc03': bytecode_file(module([], [[('Undefined',), ('Return',)]])),
+    'badjsbc04': b'JSBC' + u32(version) + b'\x05',
+    'badjsbc05': bytecode_file(module([], [[('Undefined',),
+                                            ('True',),
+                                            ('JumpIf', 4),
+                                            ('Pop',),
+                                            ('Halt',)]])),
+    'badjsbc06': bytecode_file(module([], [[('PopScope',), ('Halt',)]])),
+    'badjsbc07': bytecode_file(module(['x'], [[('Undefined',),
+                                               ('Declare', 4000000000, 0),
+                                               ('Halt',)]])),
+    'badjsbc08': bytecode_file(module([], [[('Arg', 0),
+                                            ('Print',),
+                                            ('Halt',)]])),
+    'badjsbc09': bytecode_file(hello, version=version + 1),
+    'badjsbc10': bytecode_file(flipped, sum=checksum(hello)),
+    'badjsbc11': bytecode_file(hello, length=len(hello) + 1),
+}
+
+for name, contents in cases.items():
+    with open(path.join(sys.argv[1], name + '.jsbc'), 'wb') as f:
+        f.write(contents)
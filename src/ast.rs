@@ -1,14 +1,25 @@
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BinaryOperator {
     BopPlus,
     BopMinus,
     BopTimes,
     BopDivide,
     BopMod,
+    BopPow,
+    BopBitAnd,
+    BopBitOr,
+    BopBitXor,
+    BopLshift,
+    BopRshift,
+    BopUrshift,
     BopEq,
     BopNe,
+    BopStrictEq,
+    BopStrictNe,
     BopLt,
     BopGt,
     BopGe,
@@ -27,8 +38,17 @@ impl fmt::Display for BinaryOperator {
             BopTimes => "*",
             BopDivide => "/",
             BopMod => "%",
+            BopPow => "**",
+            BopBitAnd => "&",
+            BopBitOr => "|",
+            BopBitXor => "^",
+            BopLshift => "<<",
+            BopRshift => ">>",
+            BopUrshift => ">>>",
             BopEq => "==",
             BopNe => "!=",
+            BopStrictEq => "===",
+            BopStrictNe => "!==",
             BopLt => "<",
             BopLe => "<=",
             BopGt => ">",
@@ -42,11 +62,12 @@ impl fmt::Display for BinaryOperator {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum UnaryOperator {
     UopNot,
     UopTypeof,
-    UopMinus
+    UopMinus,
+    UopBitNot
 }
 
 impl fmt::Display for UnaryOperator {
@@ -55,25 +76,101 @@ impl fmt::Display for UnaryOperator {
         let c = match *self {
             UopNot => "!",
             UopTypeof => "typeof ",
-            UopMinus => "-"
+            UopMinus => "-",
+            UopBitNot => "~"
+        };
+
+        write!(f, "{}", c)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateOperator {
+    UpdIncrement,
+    UpdDecrement
+}
+
+impl fmt::Display for UpdateOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::UpdateOperator::*;
+        let c = match *self {
+            UpdIncrement => "++",
+            UpdDecrement => "--"
         };
 
         write!(f, "{}", c)
     }
 }
 
+/// Where a variable lives at run time, as found by the resolver: how many
+/// scopes out from the running one, and its index among that scope's slots.
+/// An implicit global is one never declared, only assigned, so it may be
+/// read before it exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+    pub implicit: bool
+}
+
+/// A variable name, declared or used.  Its slot is None until the program is
+/// resolved; it prints as just the name.
+#[derive(Clone, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    pub slot: Option<Slot>
+}
+
+impl From<String> for Ident {
+    fn from(name: String) -> Ident {
+        Ident {name: name, slot: None}
+    }
+}
+
+impl fmt::Debug for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.name)
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct DeclInitData {
-    pub id: String,
+    pub id: Ident,
     pub src: Box<Expression>
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Declaration {
-    DeclId(String),
+    DeclId(Ident),
     DeclInit(DeclInitData)
 }
 
+impl Declaration {
+    pub fn id(&self) -> &str {
+        &self.ident().name
+    }
+
+    pub fn ident(&self) -> &Ident {
+        use self::Declaration::*;
+        match *self {
+            DeclId(ref id) | DeclInit(DeclInitData { ref id, .. }) => id
+        }
+    }
+
+    pub fn ident_mut(&mut self) -> &mut Ident {
+        use self::Declaration::*;
+        match *self {
+            DeclId(ref mut id) | DeclInit(DeclInitData { ref mut id, .. }) => id
+        }
+    }
+}
+
 impl fmt::Display for Declaration {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Declaration::*;
@@ -112,8 +209,220 @@ pub struct ExpAssignData {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum Expression {
-    ExpId(String),
+pub struct ExpCompoundAssignData {
+    pub opr: BinaryOperator,
+    pub lft: Box<Expression>,
+    pub rht: Box<Expression>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpUpdateData {
+    pub opr: UpdateOperator,
+    pub prefix: bool,
+    pub opnd: Box<Expression>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpDotData {
+    pub obj: Box<Expression>,
+    pub prop: String
+}
+
+/// A run of template literal text.  The cooked text has its escapes
+/// processed; the raw text is the source as written, for printing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemplateChunk {
+    pub cooked: String,
+    pub raw: String
+}
+
+/// The text chunks of a template literal and the substitutions between them,
+/// so there is always one more chunk than substitution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpTemplateData {
+    pub chunks: Vec<TemplateChunk>,
+    pub exps: Vec<Expression>
+}
+
+/// An arrow function parameter.  The default is evaluated at call time, when
+/// the argument is missing or undefined.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    pub name: Ident,
+    pub default: Option<Expression>
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.default {
+            Some(ref default) => write!(f, "{} = {}", self.name, default),
+            None => write!(f, "{}", self.name)
+        }
+    }
+}
+
+/// Function parameters and bodies are shared with the closures made from them
+/// rather than copied into each.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FunctionBody {
+    BodyExp(Rc<Expression>),
+    BodyBlock(Rc<Vec<SourceElement>>)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpArrowData {
+    pub params: Rc<Vec<Param>>,
+    pub rest: Option<Ident>,
+    pub body: FunctionBody
+}
+
+/// A function expression; a named one can call itself by its name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpFunctionData {
+    pub name: Option<Ident>,
+    pub params: Vec<Ident>,
+    pub body: Rc<Vec<SourceElement>>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpCallData {
+    pub func: Box<Expression>,
+    pub args: Vec<Expression>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpIndexData {
+    pub obj: Box<Expression>,
+    pub index: Box<Expression>
+}
+
+// Writes the elements of an argument or parameter list separated by commas
+fn write_list<T: fmt::Display>(
+    f: &mut fmt::Formatter,
+    list: &[T]
+    ) -> fmt::Result {
+
+    for (i, elem) in list.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", elem)?;
+    }
+    Ok(())
+}
+
+/// Escapes a string for a double-quoted jsish literal that reads back as the
+/// same string.
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\x08' => escaped.push_str("\\b"),
+            '\x0b' => escaped.push_str("\\v"),
+            '\x0c' => escaped.push_str("\\f"),
+            // "\0" followed by a digit would read as an octal escape
+            '\0' if !matches!(chars.peek(), Some(d) if d.is_ascii_digit()) =>
+                escaped.push_str("\\0"),
+            c if (c as u32) < 0x100 && c.is_control() =>
+                escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' =>
+                escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+/// A comment, kept so that the pretty-printer can reproduce it.  One on its
+/// own line began the line, or followed a comment that ended one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub own_line: bool
+}
+
+impl Comment {
+    // A line comment runs to the end of its line, so no code may follow it there
+    fn is_line(&self) -> bool {
+        self.text.starts_with("//")
+    }
+}
+
+/// The comments next to a node: those before it, those after it, and for a
+/// node with a body, those after the last element of the body.  For a
+/// function, the header holds those between its name and its body.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Comments {
+    pub before: Vec<Comment>,
+    pub after: Vec<Comment>,
+    pub inside: Vec<Comment>,
+    pub header: Vec<Comment>
+}
+
+impl Comments {
+    /// Adds the comments around a node to those of a part of it taking its
+    /// place
+    pub fn enclose(&mut self, outer: Comments) {
+        let before = mem::replace(&mut self.before, outer.before);
+        self.before.extend(before);
+        self.after.extend(outer.after);
+    }
+}
+
+// Writes comments between lines of code: one on its own line or after a
+// line comment starts a new line, and any other stays on the line before it
+fn write_comment_lines<'a, W, I>(w: &mut W, comments: I) -> fmt::Result
+    where W: fmt::Write, I: IntoIterator<Item = &'a Comment> {
+
+    let mut line_ended = false;
+    for c in comments {
+        let sep = if c.own_line || line_ended { "\n" } else { " " };
+        write!(w, "{}{}", sep, c.text)?;
+        line_ended = c.is_line();
+    }
+    Ok(())
+}
+
+// Writes comments in front of code, which never follows a line comment on
+// its line
+fn write_leading_comments<W: fmt::Write>(w: &mut W, comments: &[Comment]) -> fmt::Result {
+    for c in comments {
+        let sep = if c.own_line || c.is_line() { "\n" } else { " " };
+        write!(w, "{}{}", c.text, sep)?;
+    }
+    Ok(())
+}
+
+/// An expression, with the comments written next to it.  Comments play no
+/// part in running a program, and are left out of its debug form.
+#[derive(Clone, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub comments: Comments
+}
+
+impl From<ExpressionKind> for Expression {
+    fn from(kind: ExpressionKind) -> Expression {
+        Expression {kind: kind, comments: Comments::default()}
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionKind {
+    ExpId(Ident),
     ExpNum(i64),
     ExpString(String),
     ExpTrue,
@@ -122,16 +431,27 @@ pub enum Expression {
     ExpBinary(ExpBinaryData),
     ExpUnary(ExpUnaryData),
     ExpCond(ExpCondData),
-    ExpAssign(ExpAssignData)
+    ExpAssign(ExpAssignData),
+    ExpCompoundAssign(ExpCompoundAssignData),
+    ExpUpdate(ExpUpdateData),
+    ExpDot(ExpDotData),
+    ExpTemplate(ExpTemplateData),
+    ExpThis,
+    ExpInUse,
+    ExpArray(Vec<Expression>),
+    ExpIndex(ExpIndexData),
+    ExpCall(ExpCallData),
+    ExpArrow(ExpArrowData),
+    ExpFunction(ExpFunctionData)
 }
 
-impl fmt::Display for Expression {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    use self::Expression::*;
-        match *self {
+impl Expression {
+    fn write_kind(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ExpressionKind::*;
+        match self.kind {
             ExpId(ref s) => write!(f, "{}", s),
             ExpNum(ref n) => write!(f, "{}", n),
-            ExpString(ref s) => write!(f, "{:?}", s),
+            ExpString(ref s) => write!(f, "\"{}\"", escape_string(s)),
             ExpTrue => write!(f, "true"),
             ExpFalse => write!(f, "false"),
             ExpUndefined => write!(f, "undefined"),
@@ -143,10 +463,88 @@ impl fmt::Display for Expression {
                 write!(f, "({} ? {} : {})", guard, then_exp, else_exp),
             ExpAssign(ExpAssignData {ref lft, ref rht}) =>
                 write!(f, "({} = {})", lft, rht),
+            ExpCompoundAssign(ExpCompoundAssignData {ref opr, ref lft,
+                                                     ref rht}) =>
+                write!(f, "({} {}= {})", lft, opr, rht),
+            ExpUpdate(ExpUpdateData {ref opr, prefix: true, ref opnd}) =>
+                write!(f, "({}{})", opr, opnd),
+            ExpUpdate(ExpUpdateData {ref opr, prefix: false, ref opnd}) =>
+                write!(f, "({}{})", opnd, opr),
+            ExpDot(ExpDotData {ref obj, ref prop}) =>
+                write!(f, "{}.{}", obj, prop),
+            ExpTemplate(ExpTemplateData {ref chunks, ref exps}) => {
+                write!(f, "`{}", chunks[0].raw)?;
+                for (exp, chunk) in exps.iter().zip(&chunks[1..]) {
+                    write!(f, "${{{}}}{}", exp, chunk.raw)?;
+                }
+                write!(f, "`")
+            }
+            ExpThis => write!(f, "this"),
+            ExpInUse => write!(f, "inUse()"),
+            ExpArray(ref elems) => {
+                write!(f, "[")?;
+                write_list(f, elems)?;
+                write!(f, "]")
+            }
+            ExpIndex(ExpIndexData {ref obj, ref index}) =>
+                write!(f, "{}[{}]", obj, index),
+            ExpCall(ExpCallData {ref func, ref args}) => {
+                write!(f, "{}(", func)?;
+                write_list(f, args)?;
+                write!(f, ")")
+            }
+            ExpArrow(ExpArrowData {ref params, ref rest, ref body}) => {
+                write!(f, "((")?;
+                write_list(f, params)?;
+                if let Some(ref rest) = *rest {
+                    let sep = if params.is_empty() { "" } else { ", " };
+                    write!(f, "{}...{}", sep, rest)?;
+                }
+                match *body {
+                    FunctionBody::BodyExp(ref exp) => {
+                        write!(f, ") => ")?;
+                        write_leading_comments(f, &self.comments.header)?;
+                        write!(f, "{})", exp)
+                    }
+                    FunctionBody::BodyBlock(ref body) => {
+                        write!(f, ") =>")?;
+                        write_comment_lines(f, &self.comments.header)?;
+                        write!(f, "\n{{")?;
+                        write_source_elements(f, body, &self.comments.inside)?;
+                        write!(f, "\n}}\n)")
+                    }
+                }
+            }
+            ExpFunction(ExpFunctionData {ref name, ref params, ref body}) => {
+                write!(f, "(function {}(", name.as_ref().map_or("", |n| &n.name))?;
+                write_list(f, params)?;
+                write!(f, ")")?;
+                write_comment_lines(f, &self.comments.header)?;
+                write!(f, "\n{{")?;
+                write_source_elements(f, body, &self.comments.inside)?;
+                write!(f, "\n}}\n)")
+            }
         }
     }
 }
 
+// Comments print where they were written, except that code never follows a
+// line comment on its line
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_leading_comments(f, &self.comments.before)?;
+        self.write_kind(f)?;
+
+        for c in &self.comments.after {
+            write!(f, "{}{}", if c.own_line { "\n" } else { " " }, c.text)?;
+            if c.is_line() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct StIfData {
     pub guard: Expression,
@@ -161,70 +559,300 @@ pub struct StWhileData {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum Statement {
+pub struct CatchData {
+    pub param: Ident,
+    pub body: Box<Statement>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StTryData {
+    pub body: Box<Statement>,
+    pub catch: Option<CatchData>,
+    pub finally: Option<Box<Statement>>
+}
+
+/// A switch clause; the test is None for the default clause.  The comments
+/// are those before the clause.
+#[derive(Clone, PartialEq)]
+pub struct CaseData {
+    pub test: Option<Expression>,
+    pub body: Vec<Statement>,
+    pub comments: Vec<Comment>
+}
+
+impl fmt::Debug for CaseData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CaseData")
+            .field("test", &self.test)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StSwitchData {
+    pub disc: Expression,
+    pub cases: Vec<CaseData>
+}
+
+/// A statement, with the comments written next to it, as for an expression
+#[derive(Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub comments: Comments
+}
+
+impl From<StatementKind> for Statement {
+    fn from(kind: StatementKind) -> Statement {
+        Statement {kind: kind, comments: Comments::default()}
+    }
+}
+
+impl fmt::Debug for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatementKind {
     StExp(Expression),
     StPrint(Expression),
-    StBlock(Vec<Statement>),
+    StBlock(Vec<SourceElement>),
     StIf(StIfData),
-    StWhile(StWhileData)
+    StWhile(StWhileData),
+    StThrow(Expression),
+    StTry(StTryData),
+    StSwitch(StSwitchData),
+    StBreak,
+    StGc,
+    StHeapSnapshot(Expression),
+    StReturn(Option<Expression>)
+}
+
+// Writes a statement on a line of its own, between the comments before and
+// after it
+fn write_statement<W: fmt::Write>(w: &mut W, stmt: &Statement) -> fmt::Result {
+    write_comment_lines(w, &stmt.comments.before)?;
+    write!(w, "\n{}", stmt)?;
+    write_comment_lines(w, &stmt.comments.after)
+}
+
+// As write_statement, for an element of a block or function body
+fn write_source_element<W: fmt::Write>(w: &mut W, se: &SourceElement) -> fmt::Result {
+    let comments = &se.position().comments;
+    match *se {
+        SourceElement::Stmt(ref s, _) => {
+            write_comment_lines(w, comments.before.iter().chain(&s.comments.before))?;
+            write!(w, "\n{}", s)?;
+            write_comment_lines(w, s.comments.after.iter().chain(&comments.after))
+        }
+        _ => {
+            write_comment_lines(w, &comments.before)?;
+            write!(w, "\n{}", se)?;
+            write_comment_lines(w, &comments.after)
+        }
+    }
+}
+
+// Writes the elements of a block or function body, then the comments after
+// the last of them
+fn write_source_elements<W: fmt::Write>(
+    w: &mut W,
+    list: &[SourceElement],
+    inside: &[Comment]
+    ) -> fmt::Result {
+
+    for se in list {
+        write_source_element(w, se)?;
+    }
+    write_comment_lines(w, inside)
 }
 
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    use self::Statement::*;
-        match *self {
+    use self::StatementKind::*;
+        match self.kind {
             StExp(ref exp) => write!(f, "{};", exp),
             StPrint(ref exp) => write!(f, "print {};", exp),
             StBlock(ref list) => {
-                write!(f, "{}", "{\n")?;
-                for stmt in list {
-                    write!(f, "{}\n", stmt)?;
+                write!(f, "{}", "{")?;
+                write_source_elements(f, list, &self.comments.inside)?;
+                write!(f, "{}", "\n}")
+            }
+            StIf(StIfData { ref guard, ref th, ref el }) => {
+                write!(f, "if ({})", guard)?;
+                write_statement(f, th)?;
+                write!(f, "\nelse")?;
+                write_statement(f, el)
+            }
+            StWhile(StWhileData { ref guard, ref body }) => {
+                write!(f, "while ({})", guard)?;
+                write_statement(f, body)
+            }
+            StThrow(ref exp) => write!(f, "throw {};", exp),
+            StTry(StTryData { ref body, ref catch, ref finally }) => {
+                write!(f, "try")?;
+                write_statement(f, body)?;
+                if let Some(CatchData { ref param, ref body }) = *catch {
+                    write!(f, "\ncatch ({})", param)?;
+                    write_statement(f, body)?;
                 }
-                write!(f, "{}", "}")
+                if let Some(ref fin) = *finally {
+                    write!(f, "\nfinally")?;
+                    write_statement(f, fin)?;
+                }
+                Ok(())
+            }
+            StSwitch(StSwitchData { ref disc, ref cases }) => {
+                write!(f, "switch ({})\n{{", disc)?;
+                for case in cases {
+                    write_comment_lines(f, &case.comments)?;
+                    match case.test {
+                        Some(ref test) => write!(f, "\ncase {}:", test)?,
+                        None => write!(f, "\ndefault:")?
+                    }
+                    for stmt in &case.body {
+                        write_statement(f, stmt)?;
+                    }
+                }
+                write_comment_lines(f, &self.comments.inside)?;
+                write!(f, "\n}}")
             }
-            StIf(StIfData { ref guard, ref th, ref el }) =>
-                write!(f, "if ({})\n{}\nelse\n{}", guard, th, el),
-            StWhile(StWhileData { ref guard, ref body }) =>
-                write!(f, "while ({})\n{}", guard, body),
+            StBreak => write!(f, "break;"),
+            StGc => write!(f, "gc();"),
+            StHeapSnapshot(ref exp) => write!(f, "heapSnapshot({});", exp),
+            StReturn(Some(ref exp)) => write!(f, "return {};", exp),
+            StReturn(None) => write!(f, "return undefined;")
         }
     }
 }
 
 
 #[derive(Clone, Debug, PartialEq)]
+pub struct FunctionDeclData {
+    pub name: Ident,
+    pub params: Vec<Ident>,
+    pub body: Rc<Vec<SourceElement>>
+}
+
+/// Where a source element is: the line its first token is on, and the
+/// comments next to it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub comments: Comments
+}
+
+impl From<usize> for Position {
+    fn from(line: usize) -> Position {
+        Position {line: line, comments: Comments::default()}
+    }
+}
+
+/// An element of a body, with its position in the source
+#[derive(Clone, PartialEq)]
 pub enum SourceElement {
-    Stmt(Statement),
-    VarDecl(Vec<Declaration>)
+    Stmt(Box<Statement>, Position),
+    VarDecl(Vec<Declaration>, Position),
+    LetDecl(Vec<Declaration>, Position),
+    ConstDecl(Vec<Declaration>, Position),
+    FunctionDecl(FunctionDeclData, Position)
 }
 
-impl fmt::Display for SourceElement {
+impl SourceElement {
+    pub fn position(&self) -> &Position {
+        use self::SourceElement::*;
+        match *self {
+            Stmt(_, ref pos) | VarDecl(_, ref pos) | LetDecl(_, ref pos) |
+            ConstDecl(_, ref pos) | FunctionDecl(_, ref pos) => pos
+        }
+    }
+
+    pub fn position_mut(&mut self) -> &mut Position {
+        use self::SourceElement::*;
+        match *self {
+            Stmt(_, ref mut pos) | VarDecl(_, ref mut pos) |
+            LetDecl(_, ref mut pos) | ConstDecl(_, ref mut pos) |
+            FunctionDecl(_, ref mut pos) => pos
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        self.position().line
+    }
+}
+
+// The position is left out, so that the AST of a program does not depend on
+// its layout
+impl fmt::Debug for SourceElement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::SourceElement::*;
         match *self {
-            Stmt(ref s) => write!(f, "{}", s),
-            VarDecl(ref decls) => {
-                write!(f, "var ")?;
-                for decl in decls {
-                    write!(f, "{}, ", decl)?;
-                }
-                write!(f, "\n")
-            }
+            Stmt(ref s, _) => f.debug_tuple("Stmt").field(s).finish(),
+            VarDecl(ref decls, _) =>
+                f.debug_tuple("VarDecl").field(decls).finish(),
+            LetDecl(ref decls, _) =>
+                f.debug_tuple("LetDecl").field(decls).finish(),
+            ConstDecl(ref decls, _) =>
+                f.debug_tuple("ConstDecl").field(decls).finish(),
+            FunctionDecl(ref data, _) =>
+                f.debug_tuple("FunctionDecl").field(data).finish()
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl fmt::Display for SourceElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SourceElement::*;
+        let (keyword, decls) = match *self {
+            Stmt(ref s, _) => return write!(f, "{}", s),
+            FunctionDecl(FunctionDeclData {ref name, ref params, ref body},
+                         ref pos) => {
+                write!(f, "function {}(", name)?;
+                write_list(f, params)?;
+                write!(f, ")")?;
+                write_comment_lines(f, &pos.comments.header)?;
+                write!(f, "\n{{")?;
+                write_source_elements(f, body, &pos.comments.inside)?;
+                return write!(f, "\n}}");
+            }
+            VarDecl(ref decls, _) => ("var", decls),
+            LetDecl(ref decls, _) => ("let", decls),
+            ConstDecl(ref decls, _) => ("const", decls)
+        };
+
+        write!(f, "{} ", keyword)?;
+        write_list(f, decls)?;
+        write!(f, ";")
+    }
+}
+
+/// The elements of a program, and the comments after the last of them
+#[derive(Clone, PartialEq)]
 pub enum Program {
-    Prog(Vec<SourceElement>)
+    Prog(Vec<SourceElement>, Vec<Comment>)
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Program::Prog(ref se_list, _) = *self;
+        f.debug_tuple("Prog").field(se_list).finish()
+    }
 }
 
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Program::*;
-        let Prog(ref se_list) = *self;
+        let Prog(ref se_list, ref comments) = *self;
+
+        // Each element starts a new line, but the program starts on the first
+        let mut text = String::new();
+        write_source_elements(&mut text, se_list, comments)?;
+        let text = text.strip_prefix('\n').unwrap_or(&text);
 
-        for ref se in se_list {
-            write!(f, "{}\n", se)?;
+        if !text.is_empty() {
+            writeln!(f, "{}", text)?;
         }
 
         Ok(())
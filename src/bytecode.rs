@@ -0,0 +1,272 @@
+//! The instruction set run by the virtual machine of `--engine=vm`.
+//!
+//! A compiled program is a `Module`: a pool of constants, a table of names,
+//! and its functions, the first of which is the program itself.  Operands
+//! refer to these by index, so a module holds no part of the AST.
+//!
+//! Instructions work on a stack of values.  Variables live in the same
+//! scopes as the tree-walking interpreter's, in the slots the resolver gave
+//! them; jumps are to instruction indices within the function.
+//!
+//! A module displays as the disassembly printed by `--emit=bytecode`: each
+//! function in turn, one instruction per line with its offset, the source
+//! line it was compiled from (`|` when unchanged), its opcode and operands,
+//! and after a `;` what the operands refer to.
+//!
+//! ```text
+//! == function 0: <program> ==
+//! 0000     1  Hoist         0               ; x
+//! 0001     |  Const         0               ; 1
+//! 0002     |  Declare       0               ; x
+//! 0003     2  Load          0.0             ; x
+//! 0004     |  Print
+//! 0005     |  Halt
+//! ```
+//!
+//! Variables show as `depth.slot`, counting scopes out from the innermost,
+//! and jumps as `-> offset`.  Lines are those of the source elements of each
+//! body or block, so a statement in a case of a switch shows the line of the
+//! switch.
+
+use ast::{BinaryOperator, UnaryOperator, UpdateOperator};
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Constant {
+    Num(i64),
+    Str(String)
+}
+
+/// A variable resolved to a slot, with its name for error messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Var {
+    pub depth: u32,
+    pub index: u32,
+    /// Index into the module's names
+    pub name: u32,
+    pub implicit: bool
+}
+
+/// The construct a guard belongs to, for its type error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Guard {
+    If,
+    While,
+    Cond
+}
+
+impl fmt::Display for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Guard::If => "'if' statement",
+            Guard::While => "'while' statement",
+            Guard::Cond => "'cond' expression"
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// Pushes a constant
+    Const(u32),
+    Undefined,
+    True,
+    False,
+    This,
+    InUse,
+    Pop,
+    Dup,
+
+    /// Pushes the value of a variable
+    Load(Var),
+    /// Assigns the top of the stack to a variable, leaving it there
+    Store(Var),
+    /// Pops a value into a slot of the innermost scope, given its name
+    Declare(u32, u32),
+    /// Binds a var to undefined unless its slot is already bound
+    Hoist(u32, u32),
+    /// Runs what follows in a new scope, until the matching PopScope
+    PushScope,
+    PopScope,
+
+    Unary(UnaryOperator),
+    /// Pops the right operand, then the left
+    Binary(BinaryOperator),
+    /// Increments or decrements a variable, pushing the new value if prefix
+    /// and the old one otherwise
+    Update(UpdateOperator, bool, Var),
+    /// For '&&' and '||': jumps, keeping the left operand, if it decides the
+    /// result, otherwise pops it
+    ShortCircuit(BinaryOperator, u32),
+    /// Checks the right operand of '&&' or '||'
+    CheckBoolean(BinaryOperator),
+
+    Jump(u32),
+    /// Pops a guard, jumping if it is false
+    JumpUnless(Guard, u32),
+    /// Pops a value, jumping if it is true
+    JumpIf(u32),
+
+    /// Pops the given number of elements into a new array
+    Array(u32),
+    /// Pops an index, then the array
+    Index,
+    /// Pops an object, pushing the named property
+    Dot(u32),
+    /// Pops the given number of template chunks and substitutions, pushing
+    /// them joined as a string
+    Template(u32),
+
+    /// Pushes a closure of a function of the module over the current scope
+    Closure(u32),
+    /// Calls the function beneath the given number of arguments
+    Call(u32),
+    Return,
+    /// Pushes an argument of the running call, or undefined if missing
+    Arg(u32),
+    /// Jumps if the top of the stack is not undefined, otherwise pops it
+    JumpIfDefined(u32),
+    /// Pushes an array of the arguments from the given one on
+    Rest(u32),
+
+    Print,
+    Throw,
+    /// Until the matching EndTry, a thrown value is pushed and control
+    /// transferred to the target, in the scope the try began in
+    Try(u32),
+    EndTry,
+    Gc,
+    /// Pops the path of a heap snapshot to write
+    HeapSnapshot,
+    /// Ends the program
+    Halt
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    /// Index into the module's names, for heap snapshots
+    pub name: Option<u32>,
+    /// An arrow function keeps the 'this' of the scope it was created in
+    pub arrow: bool,
+    pub code: Vec<Op>,
+    /// The source line of each instruction
+    pub lines: Vec<u32>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    pub constants: Vec<Constant>,
+    pub names: Vec<String>,
+    /// The program followed by the functions it defines
+    pub functions: Vec<Function>
+}
+
+impl Module {
+    fn name(&self, index: u32) -> &str {
+        &self.names[index as usize]
+    }
+
+    fn var(&self, var: Var) -> (String, String) {
+        let name = if var.implicit {
+            format!("{} (implicit global)", self.name(var.name))
+        }
+        else {
+            self.name(var.name).to_string()
+        };
+
+        (format!("{}.{}", var.depth, var.index), name)
+    }
+
+    fn title(&self, function: &Function, index: usize) -> String {
+        match function.name {
+            Some(name) => self.name(name).to_string(),
+            None if index == 0 => String::from("<program>"),
+            None if function.arrow => String::from("<arrow>"),
+            None => String::from("<anonymous>")
+        }
+    }
+
+    // The operands of an instruction, then what they refer to
+    fn operands(&self, op: Op) -> (String, String) {
+        use self::Op::*;
+        match op {
+            Const(k) => {
+                let constant = match self.constants[k as usize] {
+                    Constant::Num(n) => n.to_string(),
+                    Constant::Str(ref s) => format!("{:?}", s)
+                };
+                (k.to_string(), constant)
+            }
+            Load(var) | Store(var) => self.var(var),
+            Declare(index, name) | Hoist(index, name) =>
+                (index.to_string(), self.name(name).to_string()),
+            Unary(opr) => (opr.to_string().trim().to_string(), String::new()),
+            Binary(opr) | CheckBoolean(opr) => (opr.to_string(), String::new()),
+            Update(opr, prefix, var) => {
+                let (slot, name) = self.var(var);
+                let fix = if prefix { "prefix" } else { "postfix" };
+                (format!("{} {} {}", opr, fix, slot), name)
+            }
+            ShortCircuit(opr, target) =>
+                (format!("{} -> {}", opr, target), String::new()),
+            Jump(target) | JumpIf(target) | JumpIfDefined(target) |
+            Try(target) => (format!("-> {}", target), String::new()),
+            JumpUnless(guard, target) =>
+                (format!("-> {}", target), guard.to_string()),
+            Dot(name) => (name.to_string(), self.name(name).to_string()),
+            Closure(f) => {
+                let function = &self.functions[f as usize];
+                (f.to_string(), self.title(function, f as usize))
+            }
+            Array(n) | Template(n) | Call(n) | Arg(n) | Rest(n) =>
+                (n.to_string(), String::new()),
+            Undefined | True | False | This | InUse | Pop | Dup | PushScope |
+            PopScope | Index | Return | Print | Throw | EndTry | Gc |
+            HeapSnapshot | Halt => (String::new(), String::new())
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, function) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "== function {}: {} ==",
+                     index, self.title(function, index))?;
+
+            let mut last_line = None;
+            for (offset, (&op, &line)) in function.code.iter()
+                .zip(&function.lines).enumerate() {
+
+                let shown_line = if last_line == Some(line) {
+                    String::from("|")
+                }
+                else {
+                    line.to_string()
+                };
+                last_line = Some(line);
+
+                let opcode = format!("{:?}", op);
+                let opcode = opcode.split('(').next().unwrap_or("");
+                let (operands, comment) = self.operands(op);
+
+                let text = if comment.is_empty() {
+                    format!("{:04} {:>5}  {:<13} {}",
+                            offset, shown_line, opcode, operands)
+                }
+                else {
+                    format!("{:04} {:>5}  {:<13} {:<15} ; {}",
+                            offset, shown_line, opcode, operands, comment)
+                };
+                writeln!(f, "{}", text.trim_end())?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1345 @@
+//! The static checker of `jsish check`: without running a program, finds the
+//! types each expression may have under the strict semantics, and reports
+//! the type errors the interpreter would raise, in its words.
+//!
+//! Types follow variables through assignments, branches and loops; a loop is
+//! checked until the types at its head stop growing.  An operation that
+//! fails for every type its operands may have is an error, raised whenever
+//! the operation runs.  One that fails for only some is a warning, unless an
+//! operand could be anything: a parameter, the result of a call, an element
+//! of an array, or a variable of an enclosing function, which may change
+//! between calls.  A call may also change any variable that some function
+//! assigns from outside itself, so those are forgotten at every call.
+//! Reading a variable that is declared nowhere and assigned nowhere is a
+//! warning, as the code reading it may never run.
+//!
+//! Calling a function declaration gives the types it may return, as found by
+//! earlier passes.  A catch parameter may hold what the try statement throws,
+//! and an error object if anything in it may fail.
+//!
+//! Along with its types, the checker follows the integers a number may be,
+//! as a range, and whether a boolean may be true or false.  A guard narrows
+//! the variables it compares on each of its branches, and the ranges at a
+//! loop head are widened while they grow, then narrowed by one more
+//! iteration.  From these come warnings of a division that must be by zero,
+//! of a guard that is always true or always false, and of a statement that
+//! cannot be reached, which is not checked further.
+
+mod ranges;
+
+use self::ranges::{Range, EMPTY, FULL};
+use interpreter::{bitwise_op, checked_power};
+use types::{Diagnostic, Severity};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use ast::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
+use ast::SourceElement::*;
+use ast::Program::*;
+use ast::BinaryOperator::*;
+use ast::UnaryOperator::*;
+use ast::UpdateOperator::*;
+use ast::Declaration::*;
+
+/// A set of the types a value may have, as `typeof` names them, so arrays and
+/// errors are both objects
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Types(u8);
+
+const NONE: Types = Types(0);
+const NUMBER: Types = Types(1);
+const STRING: Types = Types(2);
+const BOOLEAN: Types = Types(4);
+const UNDEFINED: Types = Types(8);
+const OBJECT: Types = Types(16);
+const FUNCTION: Types = Types(32);
+const UNKNOWN: Types = Types(63);
+
+const TYPE_NAMES: [(Types, &str); 6] = [(NUMBER, "number"),
+                                        (STRING, "string"),
+                                        (BOOLEAN, "boolean"),
+                                        (UNDEFINED, "undefined"),
+                                        (OBJECT, "object"),
+                                        (FUNCTION, "function")];
+
+impl Types {
+    fn union(self, other: Types) -> Types {
+        Types(self.0 | other.0)
+    }
+
+    fn has(self, ty: Types) -> bool {
+        self.0 & ty.0 != 0
+    }
+
+    fn without(self, ty: Types) -> Types {
+        Types(self.0 & !ty.0)
+    }
+
+    fn each(self) -> Vec<Types> {
+        TYPE_NAMES.iter()
+            .map(|&(ty, _)| ty)
+            .filter(|&ty| self.has(ty))
+            .collect()
+    }
+}
+
+impl fmt::Display for Types {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == UNKNOWN {
+            return write!(f, "unknown");
+        }
+
+        let names = TYPE_NAMES.iter()
+            .filter(|&&(ty, _)| self.has(ty))
+            .map(|&(_, name)| name)
+            .collect::<Vec<_>>();
+        if names.len() == 1 {
+            write!(f, "{}", names[0])
+        }
+        else {
+            write!(f, "({})", names.join(" | "))
+        }
+    }
+}
+
+// The type of the result of a binary operator other than '&&' and '||' on
+// operands of the given types, or None if it raises a type error
+fn binary_type(opr: BinaryOperator, lft: Types, rht: Types) -> Option<Types> {
+    let both = |ty| lft == ty && rht == ty;
+
+    match opr {
+        BopPlus if both(NUMBER) || both(STRING) => Some(lft),
+        BopMinus | BopTimes | BopDivide | BopMod | BopPow | BopBitAnd |
+        BopBitOr | BopBitXor | BopLshift | BopRshift | BopUrshift
+            if both(NUMBER) => Some(NUMBER),
+        BopEq | BopNe | BopStrictEq | BopStrictNe => Some(BOOLEAN),
+        BopLt | BopGt | BopGe | BopLe if both(NUMBER) || both(STRING) =>
+            Some(BOOLEAN),
+        BopComma => Some(rht),
+        _ => None
+    }
+}
+
+// The types a binary operator was found to be applied to.  An operand that
+// could be anything cannot decide that the operator fails, so only the other
+// one is named.
+fn found_operands(lft: Types, rht: Types) -> String {
+    match (lft == UNKNOWN, rht == UNKNOWN) {
+        (true, false) => format!("found {} as the right operand", rht),
+        (false, true) => format!("found {} as the left operand", lft),
+        _ => format!("found {} * {}", lft, rht)
+    }
+}
+
+/// The booleans a value may be
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Truths {
+    can_be_true: bool,
+    can_be_false: bool
+}
+
+const NEITHER: Truths = Truths {can_be_true: false, can_be_false: false};
+const EITHER: Truths = Truths {can_be_true: true, can_be_false: true};
+
+impl Truths {
+    fn only(b: bool) -> Truths {
+        Truths {can_be_true: b, can_be_false: !b}
+    }
+
+    fn allows(self, b: bool) -> bool {
+        if b { self.can_be_true } else { self.can_be_false }
+    }
+
+    fn union(self, other: Truths) -> Truths {
+        Truths {can_be_true: self.can_be_true || other.can_be_true,
+                can_be_false: self.can_be_false || other.can_be_false}
+    }
+
+    fn not(self) -> Truths {
+        Truths {can_be_true: self.can_be_false, can_be_false: self.can_be_true}
+    }
+}
+
+/// What the checker knows of a value: the types it may have, the integers
+/// it may be as a number, and the booleans it may be
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Value {
+    types: Types,
+    range: Range,
+    truths: Truths
+}
+
+impl Value {
+    // Any value of the given types
+    fn new(types: Types) -> Value {
+        Value::of(types, FULL, EITHER)
+    }
+
+    // A value of the given types, which is not a number if the range is
+    // empty, nor a boolean if it can be neither
+    fn of(types: Types, range: Range, truths: Truths) -> Value {
+        let mut types = types;
+        if range.is_empty() {
+            types = types.without(NUMBER);
+        }
+        if truths == NEITHER {
+            types = types.without(BOOLEAN);
+        }
+
+        Value {types: types,
+               range: if types.has(NUMBER) { range } else { EMPTY },
+               truths: if types.has(BOOLEAN) { truths } else { NEITHER }}
+    }
+
+    fn number(range: Range) -> Value {
+        Value::of(NUMBER, range, NEITHER)
+    }
+
+    fn boolean(truths: Truths) -> Value {
+        Value::of(BOOLEAN, EMPTY, truths)
+    }
+
+    fn join(self, other: Value) -> Value {
+        Value {types: self.types.union(other.types),
+               range: self.range.hull(other.range),
+               truths: self.truths.union(other.truths)}
+    }
+}
+
+// The integers the result of an arithmetic or bitwise operator may be
+fn number_range(opr: BinaryOperator, lft: Range, rht: Range) -> Range {
+    match opr {
+        BopPlus => lft.add(rht),
+        BopMinus => lft.sub(rht),
+        BopTimes => lft.mul(rht),
+        BopDivide => lft.div(rht),
+        BopMod => lft.rem(rht),
+        BopComma => rht,
+        _ => match (opr, lft.single(), rht.single()) {
+            (BopPow, Some(l), Some(r)) if r >= 0 =>
+                checked_power(l, r).map_or(EMPTY, Range::constant),
+            (BopBitAnd, Some(l), Some(r)) | (BopBitOr, Some(l), Some(r)) |
+            (BopBitXor, Some(l), Some(r)) | (BopLshift, Some(l), Some(r)) |
+            (BopRshift, Some(l), Some(r)) | (BopUrshift, Some(l), Some(r)) =>
+                Range::constant(bitwise_op(&opr, l, r)),
+            _ => FULL
+        }
+    }
+}
+
+// Whether a comparison or equality of the given values may be true, and
+// whether it may be false
+fn comparison_truths(opr: BinaryOperator, lft: Value, rht: Value) -> Truths {
+    let numbers = lft.types == NUMBER && rht.types == NUMBER;
+    let (l, r) = (lft.range, rht.range);
+
+    match opr {
+        BopLt if numbers => Truths {can_be_true: l.lo < r.hi,
+                                    can_be_false: l.hi >= r.lo},
+        BopLe if numbers => Truths {can_be_true: l.lo <= r.hi,
+                                    can_be_false: l.hi > r.lo},
+        BopGt => comparison_truths(BopLt, rht, lft),
+        BopGe => comparison_truths(BopLe, rht, lft),
+        BopEq | BopStrictEq => equality_truths(lft, rht),
+        BopNe | BopStrictNe => equality_truths(lft, rht).not(),
+        _ => EITHER
+    }
+}
+
+// Values of different types are never equal
+fn equality_truths(lft: Value, rht: Value) -> Truths {
+    if !lft.types.has(rht.types) {
+        return Truths::only(false);
+    }
+
+    let (l, r) = (lft.truths, rht.truths);
+    match (lft.types, rht.types) {
+        (NUMBER, NUMBER) => {
+            let same = lft.range.single().is_some() && lft.range == rht.range;
+            Truths {can_be_true: !lft.range.intersect(rht.range).is_empty(),
+                    can_be_false: !same}
+        }
+        (BOOLEAN, BOOLEAN) =>
+            Truths {can_be_true: (l.can_be_true && r.can_be_true) ||
+                                 (l.can_be_false && r.can_be_false),
+                    can_be_false: (l.can_be_true && r.can_be_false) ||
+                                  (l.can_be_false && r.can_be_true)},
+        (UNDEFINED, UNDEFINED) => Truths::only(true),
+        _ => EITHER
+    }
+}
+
+// Whether an expression reads nothing but variables and literals, so a
+// guard made of it tests its variables as they are once it is evaluated
+fn is_simple(exp: &Expression) -> bool {
+    match exp.kind {
+        ExpId(_) | ExpNum(_) | ExpString(_) | ExpTrue | ExpFalse |
+        ExpUndefined => true,
+        ExpUnary(ExpUnaryData {ref opnd, ..}) => is_simple(opnd),
+        ExpBinary(ExpBinaryData {ref lft, ref rht, ..}) =>
+            is_simple(lft) && is_simple(rht),
+        _ => false
+    }
+}
+
+/// A function as the checker knows it: by its body
+type FunctionId = *const Vec<SourceElement>;
+
+/// A variable as the checker follows it: its name, what it may hold, and
+/// the function declaration it holds, if that is all it may hold.  A slot
+/// with no name has not been bound or assigned yet.
+#[derive(Clone, Debug, PartialEq)]
+struct Var {
+    name: String,
+    value: Value,
+    function: Option<FunctionId>
+}
+
+impl Var {
+    fn new(name: &str, value: Value) -> Var {
+        Var {name: String::from(name), value: value, function: None}
+    }
+}
+
+/// The variables of each enclosing scope, in slot order, innermost last, at
+/// some point in the program, and whether that point can be reached
+#[derive(Clone, Debug, PartialEq)]
+struct Flow {
+    scopes: Vec<Vec<Var>>,
+    live: bool
+}
+
+impl Flow {
+    // Merges in the flow of another path to the same point, in the same
+    // scopes.  A variable only one of them has assigned could be anything.
+    fn join(&mut self, other: &Flow) {
+        if !other.live {
+            return;
+        }
+        if !self.live {
+            *self = other.clone();
+            return;
+        }
+
+        for (scope, other) in self.scopes.iter_mut().zip(&other.scopes) {
+            for (i, var) in scope.iter_mut().enumerate() {
+                match other.get(i) {
+                    Some(other) => {
+                        var.value = var.value.join(other.value);
+                        if var.function != other.function {
+                            var.function = None;
+                        }
+                        if var.name.is_empty() {
+                            var.name = other.name.clone();
+                        }
+                    }
+                    None => *var = Var::new(&var.name, Value::new(UNKNOWN))
+                }
+            }
+            for var in other.iter().skip(scope.len()) {
+                scope.push(Var::new(&var.name, Value::new(UNKNOWN)));
+            }
+        }
+    }
+
+    // Drops the bounds of the ranges that have grown since an earlier flow
+    // at the same loop head, so that checking the loop again and again ends
+    fn widen(&mut self, earlier: &Flow) {
+        if !self.live || !earlier.live {
+            return;
+        }
+
+        for (scope, earlier) in self.scopes.iter_mut().zip(&earlier.scopes) {
+            for (var, earlier) in scope.iter_mut().zip(earlier) {
+                var.value.range = earlier.value.range.widen(var.value.range);
+            }
+        }
+    }
+
+    // The flow as seen from one of its enclosing scopes
+    fn truncated(&self, depth: usize) -> Flow {
+        let mut flow = self.clone();
+        flow.scopes.truncate(depth);
+        flow
+    }
+}
+
+/// A try statement being checked: its scope depth, every flow its body has
+/// passed through, from any of which a thrown value may be caught, and the
+/// types it may throw
+struct Try {
+    depth: usize,
+    flows: Flow,
+    thrown: Types
+}
+
+impl Try {
+    fn new(flow: &Flow) -> Try {
+        Try {depth: flow.scopes.len(), flows: flow.clone(), thrown: NONE}
+    }
+}
+
+/// What a function body is checked apart from, to be restored after it
+struct Outside {
+    flow: Flow,
+    base: usize,
+    breaks: Vec<(usize, Flow)>,
+    tries: Vec<Try>,
+    returned: Types
+}
+
+struct Checker {
+    flow: Flow,
+    /// Index of the outermost scope of the function being checked
+    base: usize,
+    /// Names a function assigns outside itself, found by the first pass
+    captured: HashSet<String>,
+    /// Implicit globals some code assigns, found by the first pass
+    assigned: HashSet<String>,
+    /// The types each function declaration may return, as found by the pass
+    /// before, and by this one
+    known_returns: HashMap<FunctionId, Types>,
+    returns: HashMap<FunctionId, Types>,
+    /// The types the function being checked may return so far
+    returned: Types,
+    /// Whether diagnostics are kept, which they are not while a loop is
+    /// checked again and again to find the types at its head
+    reporting: bool,
+    line: usize,
+    /// For each enclosing loop or switch, its scope depth and the flows of
+    /// its breaks
+    breaks: Vec<(usize, Flow)>,
+    tries: Vec<Try>,
+    diagnostics: Vec<Diagnostic>
+}
+
+pub fn check(prog: &Program) -> Vec<Diagnostic> {
+    // A call may run a function declared only later in the program, so a
+    // first pass finds the variables functions assign outside themselves,
+    // and the implicit globals assigned anywhere
+    let mut gathering = Checker::new(HashSet::new(), HashSet::new(),
+                                     HashMap::new());
+    gathering.check_program(prog);
+
+    // Each pass then knows more of what the functions called return, and so
+    // of what the functions calling them return, until it learns nothing new
+    let (mut captured, mut returns) = (gathering.captured, HashMap::new());
+    loop {
+        let mut checker = Checker::new(captured, gathering.assigned.clone(),
+                                       returns);
+        checker.check_program(prog);
+        if checker.returns == checker.known_returns {
+            checker.diagnostics.sort_by_key(|d| d.line);
+            return checker.diagnostics;
+        }
+        captured = checker.captured;
+        returns = checker.returns;
+    }
+}
+
+impl Checker {
+    fn new(
+        captured: HashSet<String>,
+        assigned: HashSet<String>,
+        known_returns: HashMap<FunctionId, Types>
+        ) -> Checker {
+
+        Checker {flow: Flow {scopes: Vec::new(), live: true},
+                 base: 0,
+                 captured: captured,
+                 assigned: assigned,
+                 known_returns: known_returns,
+                 returns: HashMap::new(),
+                 returned: NONE,
+                 reporting: true,
+                 line: 1,
+                 breaks: Vec::new(),
+                 tries: Vec::new(),
+                 diagnostics: Vec::new()}
+    }
+
+    fn report(&mut self, severity: Severity, message: String) {
+        if self.reporting {
+            self.diagnostics.push(Diagnostic {severity: severity,
+                                              line: self.line,
+                                              message: message});
+        }
+    }
+
+    // Reports an operation that fails for every type of its operands as an
+    // error, and one that fails for some of them as a warning, and returns
+    // the types of its result.  An operand with no type is never reached.
+    fn operation(
+        &mut self,
+        results: Types,
+        fails: bool,
+        operands: &[Types],
+        message: String
+        ) -> Types {
+
+        if operands.contains(&NONE) {
+            return NONE;
+        }
+        if fails {
+            self.may_fail();
+        }
+        if results == NONE {
+            self.report(Severity::Error, message);
+            return NONE;
+        }
+
+        let unknown = operands.contains(&UNKNOWN);
+        if fails && !unknown {
+            self.report(Severity::Warning, message);
+        }
+        if unknown && results.each().len() > 1 { UNKNOWN } else { results }
+    }
+
+    // An operation requiring its operand to be of a single type
+    fn require(&mut self, types: Types, required: Types, message: String) -> Types {
+        let results = if types.has(required) { required } else { NONE };
+        self.operation(results, types != required, &[types], message)
+    }
+
+
+    fn binary_operation(
+        &mut self,
+        opr: BinaryOperator,
+        lft: Value,
+        rht: Value
+        ) -> Value {
+
+        let (mut results, mut fails) = (NONE, false);
+        for l in lft.types.each() {
+            for r in rht.types.each() {
+                match binary_type(opr, l, r) {
+                    Some(ty) => results = results.union(ty),
+                    None => fails = true
+                }
+            }
+        }
+
+        // Arithmetic may divide by zero or be out of range
+        let arithmetic = matches!(opr, BopPlus | BopMinus | BopTimes |
+                                       BopDivide | BopMod | BopPow);
+        if arithmetic && lft.types.has(NUMBER) && rht.types.has(NUMBER) {
+            self.may_fail();
+        }
+
+        let message = if opr == BopPlus {
+            format!("operator '+' requires number * number or string * \
+                    string, {}",
+                    found_operands(lft.types, rht.types))
+        }
+        else {
+            format!("operator '{}' requires number * number, {}",
+                    opr, found_operands(lft.types, rht.types))
+        };
+        let types = self.operation(results, fails, &[lft.types, rht.types],
+                                   message);
+
+        // Dividing by zero is always a range error
+        let divides = opr == BopDivide || opr == BopMod;
+        if divides && types != NONE && rht.types == NUMBER &&
+            rht.range.single() == Some(0) {
+
+            self.report(Severity::Warning,
+                        format!("operator '{}' divides by zero", opr));
+            return Value::new(NONE);
+        }
+
+        let truths = match opr {
+            BopComma => rht.truths,
+            _ => comparison_truths(opr, lft, rht)
+        };
+        Value::of(types, number_range(opr, lft.range, rht.range), truths)
+    }
+
+    // The index into the flow's scopes of a resolved variable, or None if it
+    // is outside the function being checked
+    fn scope_index(&self, id: &Ident) -> Option<usize> {
+        let slot = id.slot?;
+        let index = self.flow.scopes.len().checked_sub(slot.depth + 1)?;
+        if index < self.base { None } else { Some(index) }
+    }
+
+    // The variable, if it is in the function being checked and bound
+    fn var(&self, id: &Ident) -> Option<&Var> {
+        let scope = &self.flow.scopes[self.scope_index(id)?];
+        scope.get(id.slot?.index).filter(|var| !var.name.is_empty())
+    }
+
+    // A variable that could be anything may also not be bound yet, and one
+    // declared and assigned nowhere never is
+    fn read(&mut self, id: &Ident) -> Value {
+        if let Some(Slot {implicit: true, ..}) = id.slot {
+            if !self.assigned.contains(&id.name) {
+                self.report(Severity::Warning,
+                            format!("variable '{}' not found", id));
+            }
+        }
+
+        let value = self.var(id).map_or(Value::new(UNKNOWN), |var| var.value);
+        if value.types == UNKNOWN {
+            self.may_fail();
+        }
+        value
+    }
+
+    fn write(&mut self, id: &Ident, value: Value) {
+        self.bind(id, Var::new(&id.name, value));
+    }
+
+    fn bind(&mut self, id: &Ident, var: Var) {
+        if let Some(Slot {implicit: true, ..}) = id.slot {
+            self.assigned.insert(id.name.clone());
+        }
+
+        let (scope, index) = match (self.scope_index(id), id.slot) {
+            (Some(scope), Some(slot)) => (scope, slot.index),
+            _ => {
+                self.captured.insert(id.name.clone());
+                return;
+            }
+        };
+
+        let scope = &mut self.flow.scopes[scope];
+        if scope.len() <= index {
+            scope.resize(index + 1, Var::new("", Value::new(UNKNOWN)));
+        }
+        scope[index] = var;
+        self.record_try();
+    }
+
+    // Notes the flow as one a thrown value may leave the innermost try in
+    fn record_try(&mut self) {
+        if let Some(ref mut enclosing) = self.tries.last_mut() {
+            enclosing.flows.join(&self.flow.truncated(enclosing.depth));
+        }
+    }
+
+    // Forgets the types of the variables some function assigns from
+    // outside itself, which a call may run
+    fn forget_captured(&mut self) {
+        let captured = &self.captured;
+        for scope in &mut self.flow.scopes[self.base..] {
+            for var in scope.iter_mut().filter(|v| captured.contains(&v.name)) {
+                var.value = Value::new(UNKNOWN);
+                var.function = None;
+            }
+        }
+        self.record_try();
+    }
+
+    fn throw(&mut self, types: Types) {
+        if let Some(ref mut enclosing) = self.tries.last_mut() {
+            enclosing.thrown = enclosing.thrown.union(types);
+        }
+    }
+
+    // Notes that an error object may be thrown
+    fn may_fail(&mut self) {
+        self.throw(OBJECT);
+    }
+
+    fn push_scope(&mut self) {
+        self.flow.scopes.push(Vec::new());
+    }
+
+    fn pop_scope(&mut self) {
+        self.flow.scopes.pop();
+    }
+
+    // Warns of a guard that goes the same way whenever it is reached, unless
+    // it is a literal, which was meant to
+    fn check_guard(&mut self, construct: &str, guard: &Expression, value: Value) {
+        if value.types != BOOLEAN || matches!(guard.kind, ExpTrue | ExpFalse) {
+            return;
+        }
+
+        for &outcome in &[true, false] {
+            if !value.truths.allows(!outcome) {
+                self.report(Severity::Warning,
+                            format!("guard of {} is always {}",
+                                    construct, outcome));
+            }
+        }
+    }
+
+    // Returns the flow on the path where the guard has the given outcome,
+    // which is not reached if the guard never has it, leaving the flow as
+    // it was
+    fn branch(&mut self, guard: &Expression, value: Value, outcome: bool) -> Flow {
+        let flow = self.flow.clone();
+        if value.types.has(BOOLEAN) && !value.truths.allows(outcome) {
+            self.flow.live = false;
+        }
+        else if is_simple(guard) {
+            self.assume(guard, outcome);
+        }
+        mem::replace(&mut self.flow, flow)
+    }
+
+    // Narrows the variables a guard tests to what they must be for it to
+    // have the given outcome
+    fn assume(&mut self, guard: &Expression, outcome: bool) {
+        match guard.kind {
+            ExpId(ref id) => {
+                let truths = match self.var(id) {
+                    Some(var) if var.value.types == BOOLEAN => var.value.truths,
+                    _ => return
+                };
+                let truths = if truths.allows(outcome) { Truths::only(outcome) }
+                             else { NEITHER };
+                self.narrow(id, Value::boolean(truths));
+            }
+            ExpUnary(ExpUnaryData {opr: UopNot, ref opnd}) =>
+                self.assume(opnd, !outcome),
+            ExpBinary(ExpBinaryData {opr: BopAnd, ref lft, ref rht})
+                if outcome => {
+                self.assume(lft, true);
+                self.assume(rht, true);
+            }
+            ExpBinary(ExpBinaryData {opr: BopOr, ref lft, ref rht})
+                if !outcome => {
+                self.assume(lft, false);
+                self.assume(rht, false);
+            }
+            ExpBinary(ExpBinaryData {opr, ref lft, ref rht}) =>
+                self.assume_comparison(opr, lft, rht, outcome),
+            _ => ()
+        }
+    }
+
+    fn assume_comparison(
+        &mut self,
+        opr: BinaryOperator,
+        lft: &Expression,
+        rht: &Expression,
+        outcome: bool
+        ) {
+
+        let opr = match (opr, outcome) {
+            (opr, true) => opr,
+            (BopLt, false) => BopGe,
+            (BopGe, false) => BopLt,
+            (BopGt, false) => BopLe,
+            (BopLe, false) => BopGt,
+            (BopEq, false) | (BopStrictEq, false) => BopNe,
+            (BopNe, false) | (BopStrictNe, false) => BopEq,
+            _ => return
+        };
+        let (l, r) = match (self.peek(lft), self.peek(rht)) {
+            (Some(l), Some(r)) if l.types == NUMBER && r.types == NUMBER =>
+                (l.range, r.range),
+            _ => return
+        };
+
+        let (l, r) = match opr {
+            BopLt => (l.below(r), r.above(l)),
+            BopLe => (l.at_most(r), r.at_least(l)),
+            BopGt => (l.above(r), r.below(l)),
+            BopGe => (l.at_least(r), r.at_most(l)),
+            BopEq | BopStrictEq => (l.intersect(r), r.intersect(l)),
+            BopNe | BopStrictNe => (l.except(r), r.except(l)),
+            _ => return
+        };
+        for &(exp, range) in &[(lft, l), (rht, r)] {
+            match exp.kind {
+                ExpId(ref id) => self.narrow(id, Value::number(range)),
+                _ if range.is_empty() => self.flow.live = false,
+                _ => ()
+            }
+        }
+    }
+
+    // What a variable or number literal holds, without reading it
+    fn peek(&self, exp: &Expression) -> Option<Value> {
+        match exp.kind {
+            ExpId(ref id) => self.var(id).map(|var| var.value),
+            ExpNum(n) => Some(Value::number(Range::constant(n))),
+            ExpUnary(ExpUnaryData {opr: UopMinus, ref opnd}) => match opnd.kind {
+                ExpNum(n) => n.checked_neg()
+                    .map(|n| Value::number(Range::constant(n))),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // Sets what a variable holds on the path being checked, which cannot be
+    // taken if the variable can hold nothing there
+    fn narrow(&mut self, id: &Ident, value: Value) {
+        if value.types == NONE {
+            self.flow.live = false;
+            return;
+        }
+
+        if let (Some(scope), Some(slot)) = (self.scope_index(id), id.slot) {
+            if let Some(var) = self.flow.scopes[scope].get_mut(slot.index) {
+                var.value = value;
+            }
+        }
+    }
+
+    fn check_program(&mut self, prog: &Program) {
+        let Prog(ref ses, _) = *prog;
+
+        self.push_scope();
+        self.hoist(ses);
+        self.check_elements(ses);
+    }
+
+    // Binds what the interpreter binds on entering a program or function
+    // body: its var names, unless they are parameters, and its functions
+    fn hoist(&mut self, ses: &[SourceElement]) {
+        for se in ses {
+            match *se {
+                VarDecl(ref decls, _) => for decl in decls {
+                    if self.var(decl.ident()).is_none() {
+                        self.write(decl.ident(), Value::new(UNDEFINED));
+                    }
+                },
+                FunctionDecl(FunctionDeclData {ref name, ref body, ..}, _) =>
+                    self.bind(name, Var {function: Some(&**body),
+                                         ..Var::new(&name.name,
+                                                    Value::new(FUNCTION))}),
+                _ => ()
+            }
+        }
+    }
+
+    // A function declaration is checked even where it cannot be reached, as
+    // it is hoisted
+    fn check_elements(&mut self, ses: &[SourceElement]) {
+        let line = self.line;
+
+        let mut reported = false;
+        for se in ses {
+            self.line = se.line();
+            match *se {
+                Stmt(ref stmt, _) => self.check_reachable(stmt, &mut reported),
+                FunctionDecl(FunctionDeclData {ref params, ref body, ..}, _) =>
+                    self.check_function(params, body),
+                _ if !self.flow.live => self.unreachable(&mut reported),
+                VarDecl(ref decls, _) => for decl in decls {
+                    if let DeclInit(DeclInitData {ref id, ref src}) = *decl {
+                        let value = self.check_expression(src);
+                        self.write(id, value);
+                    }
+                },
+                LetDecl(ref decls, _) | ConstDecl(ref decls, _) =>
+                    for decl in decls {
+                        let value = match *decl {
+                            DeclId(_) => Value::new(UNDEFINED),
+                            DeclInit(DeclInitData {ref src, ..}) =>
+                                self.check_expression(src)
+                        };
+                        self.write(decl.ident(), value);
+                    }
+            }
+        }
+
+        self.line = line;
+    }
+
+    // Checks a statement if it can be reached.  Of those in a row that
+    // cannot, only the first is reported, and none is checked.
+    fn check_reachable(&mut self, stmt: &Statement, reported: &mut bool) {
+        if self.flow.live {
+            return self.check_statement(stmt);
+        }
+
+        match stmt.kind {
+            StBlock(ref ses) if ses.is_empty() => (),
+            // A block reports the first statement in it
+            StBlock(_) if !*reported => {
+                self.check_statement(stmt);
+                *reported = true;
+            }
+            _ => self.unreachable(reported)
+        }
+    }
+
+    fn unreachable(&mut self, reported: &mut bool) {
+        if !*reported {
+            self.report(Severity::Warning,
+                        String::from("unreachable statement"));
+            *reported = true;
+        }
+    }
+
+    // A function body is checked where it is defined, as it may be called
+    // anywhere after, and leaves the flow around it as it was
+    fn enter_function(&mut self) -> Outside {
+        let scopes = self.flow.scopes.len();
+        let outside = Outside {flow: self.flow.clone(),
+                               base: mem::replace(&mut self.base, scopes),
+                               breaks: mem::take(&mut self.breaks),
+                               tries: mem::take(&mut self.tries),
+                               returned: mem::replace(&mut self.returned, NONE)};
+        self.flow.live = true;
+        self.push_scope();
+        outside
+    }
+
+    // Returns the types the function may return
+    fn leave_function(&mut self, outside: Outside) -> Types {
+        let returned = if self.flow.live { self.returned.union(UNDEFINED) }
+                       else { self.returned };
+
+        self.flow = outside.flow;
+        self.base = outside.base;
+        self.breaks = outside.breaks;
+        self.tries = outside.tries;
+        self.returned = outside.returned;
+        returned
+    }
+
+    fn check_function(&mut self, params: &[Ident], body: &Rc<Vec<SourceElement>>) {
+        let outside = self.enter_function();
+        for param in params {
+            self.write(param, Value::new(UNKNOWN));
+        }
+        self.hoist(body);
+        self.check_elements(body);
+
+        let returned = self.leave_function(outside);
+        self.returns.insert(&**body, returned);
+    }
+
+    fn check_arrow(&mut self, arrow: &ExpArrowData) {
+        let outside = self.enter_function();
+        for param in arrow.params.iter() {
+            self.write(&param.name, Value::new(UNKNOWN));
+        }
+        if let Some(ref rest) = arrow.rest {
+            self.write(rest, Value::new(OBJECT));
+        }
+        for param in arrow.params.iter() {
+            if let Some(ref default) = param.default {
+                self.check_expression(default);
+            }
+        }
+
+        match arrow.body {
+            FunctionBody::BodyExp(ref exp) => {self.check_expression(exp);},
+            FunctionBody::BodyBlock(ref ses) => {
+                self.hoist(ses);
+                self.check_elements(ses);
+            }
+        }
+
+        self.leave_function(outside);
+    }
+
+    fn check_expression(&mut self, exp: &Expression) -> Value {
+        match exp.kind {
+            ExpId(ref id) => self.read(id),
+            ExpNum(n) => Value::number(Range::constant(n)),
+            ExpInUse => Value::new(NUMBER),
+            ExpString(_) => Value::new(STRING),
+            ExpTrue => Value::boolean(Truths::only(true)),
+            ExpFalse => Value::boolean(Truths::only(false)),
+            ExpUndefined => Value::new(UNDEFINED),
+            ExpThis => Value::new(UNKNOWN),
+            ExpUnary(ExpUnaryData {opr, ref opnd}) => {
+                let value = self.check_expression(opnd);
+                match opr {
+                    UopNot => {
+                        let types = self.require(value.types, BOOLEAN,
+                            format!("unary operator '!' requires boolean, \
+                                    found {}",
+                                    value.types));
+                        Value::of(types, EMPTY, value.truths.not())
+                    }
+                    UopMinus | UopBitNot => {
+                        let types = self.require(value.types, NUMBER,
+                            format!("unary operator '{}' requires number, \
+                                    found {}",
+                                    opr, value.types));
+                        // Only the least number has no negation
+                        if opr == UopMinus && value.range.lo == i64::MIN {
+                            self.may_fail();
+                        }
+                        let range = match (opr, value.range.single()) {
+                            (UopMinus, _) => value.range.neg(),
+                            (_, Some(n)) => Range::constant(!(n as i32) as i64),
+                            _ => FULL
+                        };
+                        Value::of(types, range, NEITHER)
+                    }
+                    UopTypeof if value.types == NONE => Value::new(NONE),
+                    UopTypeof => Value::new(STRING)
+                }
+            }
+            ExpBinary(ExpBinaryData {opr: opr @ BopAnd, ref lft, ref rht}) |
+            ExpBinary(ExpBinaryData {opr: opr @ BopOr, ref lft, ref rht}) =>
+                self.check_short_circuit(opr, lft, rht),
+            ExpBinary(ExpBinaryData {opr, ref lft, ref rht}) => {
+                let lft = self.check_expression(lft);
+                let rht = self.check_expression(rht);
+                self.binary_operation(opr, lft, rht)
+            }
+            ExpCond(ExpCondData {ref guard, ref then_exp, ref else_exp}) => {
+                let value = self.check_expression(guard);
+                let types = self.require(value.types, BOOLEAN,
+                    format!("boolean guard required for 'cond' expression, \
+                            found {}",
+                            value.types));
+                self.check_guard("'cond' expression", guard, value);
+
+                let then_flow = self.branch(guard, value, true);
+                let else_flow = self.branch(guard, value, false);
+                self.flow = then_flow;
+                let then_value = self.check_operand(then_exp);
+                let then_flow = mem::replace(&mut self.flow, else_flow);
+                let else_value = self.check_operand(else_exp);
+                self.flow.join(&then_flow);
+
+                if types == NONE { Value::new(NONE) }
+                else { then_value.join(else_value) }
+            }
+            ExpAssign(ExpAssignData {ref lft, ref rht}) => {
+                let value = self.check_expression(rht);
+                match lft.kind {
+                    ExpId(ref id) => {
+                        if self.var(id).is_none() {
+                            self.may_fail();
+                        }
+                        self.write(id, value);
+                        value
+                    }
+                    _ => self.target_error()
+                }
+            }
+            ExpCompoundAssign(ExpCompoundAssignData {opr, ref lft, ref rht}) =>
+                match lft.kind {
+                    ExpId(ref id) => {
+                        let lft = self.read(id);
+                        let rht = self.check_expression(rht);
+                        let value = self.binary_operation(opr, lft, rht);
+                        self.write(id, value);
+                        value
+                    }
+                    _ => self.target_error()
+                },
+            ExpUpdate(ExpUpdateData {opr, prefix, ref opnd}) => match opnd.kind {
+                ExpId(ref id) => {
+                    let bop = match opr {
+                        UpdIncrement => BopPlus,
+                        UpdDecrement => BopMinus
+                    };
+                    let value = self.read(id);
+                    let one = Value::number(Range::constant(1));
+                    let updated = self.binary_operation(bop, value, one);
+                    self.write(id, updated);
+                    if prefix || updated.types == NONE { updated } else { value }
+                }
+                _ => self.target_error()
+            },
+            ExpDot(ExpDotData {ref obj, ref prop}) => {
+                let types = self.check_expression(obj).types;
+                let obj = self.require(types, OBJECT,
+                    format!("attempt to access property '{}' of '{}' value",
+                            prop, types));
+                Value::new(if obj == NONE { NONE } else { UNKNOWN })
+            }
+            ExpIndex(ExpIndexData {ref obj, ref index}) => {
+                let obj_types = self.check_expression(obj).types;
+                let index_types = self.check_expression(index).types;
+                // An error object cannot be indexed
+                self.may_fail();
+                let obj = self.require(obj_types, OBJECT,
+                    format!("attempt to index '{}' value", obj_types));
+                if obj == NONE {
+                    return Value::new(NONE);
+                }
+                let index = self.require(index_types, NUMBER,
+                    format!("array index requires number, found {}",
+                            index_types));
+                Value::new(if index == NONE { NONE } else { UNKNOWN })
+            }
+            ExpTemplate(ExpTemplateData {ref exps, ..}) => {
+                for exp in exps {
+                    self.check_expression(exp);
+                }
+                Value::new(STRING)
+            }
+            ExpArray(ref elems) => {
+                for elem in elems {
+                    self.check_expression(elem);
+                }
+                Value::new(OBJECT)
+            }
+            ExpCall(ExpCallData {ref func, ref args}) => {
+                let function = match func.kind {
+                    ExpId(ref id) => self.var(id).and_then(|var| var.function),
+                    _ => None
+                };
+                let types = self.check_expression(func).types;
+                for arg in args {
+                    self.check_expression(arg);
+                }
+                let func = self.require(types, FUNCTION,
+                    format!("attempt to invoke '{}' value as a function",
+                            types));
+
+                // The function may throw anything, and assign variables
+                self.throw(UNKNOWN);
+                self.forget_captured();
+                Value::new(match function.and_then(|f| self.known_returns.get(&f)) {
+                    _ if func == NONE => NONE,
+                    Some(&returned) => returned,
+                    None => UNKNOWN
+                })
+            }
+            ExpArrow(ref arrow) => {
+                self.check_arrow(arrow);
+                Value::new(FUNCTION)
+            }
+            ExpFunction(ExpFunctionData {ref name, ref params, ref body}) => {
+                match *name {
+                    None => self.check_function(params, body),
+                    Some(ref name) => {
+                        self.push_scope();
+                        self.write(name, Value::new(FUNCTION));
+                        self.check_function(params, body);
+                        self.pop_scope();
+                    }
+                }
+                Value::new(FUNCTION)
+            }
+        }
+    }
+
+    fn target_error(&mut self) -> Value {
+        self.may_fail();
+        self.report(Severity::Error,
+                    String::from("unexpected target of assignment"));
+        Value::new(NONE)
+    }
+
+    // Checks an operand that runs only on the path being checked.  If that is
+    // never taken, the operand is not checked and gives no value.
+    fn check_operand(&mut self, exp: &Expression) -> Value {
+        if self.flow.live { self.check_expression(exp) } else { Value::new(NONE) }
+    }
+
+    // The right operand is checked on the path where the left does not
+    // decide the result
+    fn check_short_circuit(
+        &mut self,
+        opr: BinaryOperator,
+        lft: &Expression,
+        rht: &Expression
+        ) -> Value {
+
+        let value = self.check_expression(lft);
+        let types = self.require(value.types, BOOLEAN,
+            format!("operator '{}' requires boolean, found {}",
+                    opr, value.types));
+
+        let sc_value = opr == BopOr;
+        let decided = self.branch(lft, value, sc_value);
+        self.flow = self.branch(lft, value, !sc_value);
+        let rht = self.check_operand(rht);
+        self.require(rht.types, BOOLEAN,
+            format!("operator '{}' requires boolean * boolean, found \
+                    boolean * {}",
+                    opr, rht.types));
+        self.flow.join(&decided);
+
+        let decides = if value.truths.allows(sc_value) { Truths::only(sc_value) }
+                      else { NEITHER };
+        if types == NONE { Value::new(NONE) }
+        else { Value::boolean(decides.union(rht.truths)) }
+    }
+
+    fn check_statement(&mut self, stmt: &Statement) {
+        match stmt.kind {
+            StExp(ref exp) | StPrint(ref exp) => {self.check_expression(exp);},
+            StThrow(ref exp) => {
+                let types = self.check_expression(exp).types;
+                self.throw(types);
+                self.flow.live = false;
+            }
+            StReturn(ref exp) => {
+                let types = match *exp {
+                    Some(ref exp) => self.check_expression(exp).types,
+                    None => UNDEFINED
+                };
+                self.returned = self.returned.union(types);
+                self.flow.live = false;
+            }
+            StHeapSnapshot(ref exp) => {
+                let types = self.check_expression(exp).types;
+                self.require(types, STRING,
+                    format!("heapSnapshot requires string, found {}", types));
+                // Writing the file may fail
+                self.may_fail();
+            }
+            StBreak => {
+                if let Some(&mut (depth, ref mut flow)) = self.breaks.last_mut() {
+                    flow.join(&self.flow.truncated(depth));
+                }
+                self.flow.live = false;
+            }
+            StGc => (),
+            StBlock(ref ses) => {
+                let lexical = ses.iter()
+                    .any(|se| matches!(*se, LetDecl(..) | ConstDecl(..)));
+                if lexical {
+                    self.push_scope();
+                }
+                self.check_elements(ses);
+                if lexical {
+                    self.pop_scope();
+                }
+            }
+            StIf(StIfData {ref guard, ref th, ref el}) => {
+                let value = self.check_expression(guard);
+                self.require(value.types, BOOLEAN,
+                    format!("boolean guard required for 'if' statement, \
+                            found {}",
+                            value.types));
+                self.check_guard("'if' statement", guard, value);
+
+                let then_flow = self.branch(guard, value, true);
+                let else_flow = self.branch(guard, value, false);
+                self.flow = then_flow;
+                self.check_reachable(th, &mut false);
+                let then_flow = mem::replace(&mut self.flow, else_flow);
+                self.check_reachable(el, &mut false);
+                self.flow.join(&then_flow);
+            }
+            StWhile(StWhileData {ref guard, ref body}) =>
+                self.check_while(guard, body),
+            StTry(StTryData {ref body, ref catch, ref finally}) =>
+                self.check_try(body, catch, finally),
+            StSwitch(StSwitchData {ref disc, ref cases}) =>
+                self.check_switch(disc, cases)
+        }
+    }
+
+    // Checks the loop with diagnostics held back until the types at its head
+    // stop growing, widening the ranges there so that they do, then once
+    // more from there to take back what widening lost, and once more to
+    // report
+    fn check_while(&mut self, guard: &Expression, body: &Statement) {
+        let entry = self.flow.clone();
+        let reporting = mem::replace(&mut self.reporting, false);
+        loop {
+            let head = self.flow.clone();
+            self.check_iteration(guard, body);
+            self.flow.join(&head);
+            self.flow.widen(&head);
+            if self.flow == head {
+                break;
+            }
+        }
+        self.check_iteration(guard, body);
+        self.flow.join(&entry);
+        self.reporting = reporting;
+
+        self.flow = self.check_iteration(guard, body);
+    }
+
+    // Checks the guard and body once, leaving the flow at the end of the
+    // body, and returns the flow where the loop is left
+    fn check_iteration(&mut self, guard: &Expression, body: &Statement) -> Flow {
+        let value = self.check_expression(guard);
+        self.require(value.types, BOOLEAN,
+            format!("boolean guard required for 'while' statement, found {}",
+                    value.types));
+        self.check_guard("'while' statement", guard, value);
+
+        let mut exit = self.branch(guard, value, false);
+        self.flow = self.branch(guard, value, true);
+        let depth = self.flow.scopes.len();
+        self.breaks.push((depth, Flow {live: false, ..exit.clone()}));
+        self.check_reachable(body, &mut false);
+        let (_, breaks) = self.breaks.pop().expect("break target popped");
+
+        exit.join(&breaks);
+        exit
+    }
+
+    fn check_try(
+        &mut self,
+        body: &Statement,
+        catch: &Option<CatchData>,
+        finally: &Option<Box<Statement>>
+        ) {
+
+        self.tries.push(Try::new(&self.flow));
+        self.check_statement(body);
+        let mut thrown = self.tries.pop().expect("try popped");
+        let mut done = self.flow.clone();
+
+        if let Some(CatchData {ref param, ref body}) = *catch {
+            self.flow = Flow {live: true, ..thrown.flows.clone()};
+            self.tries.push(Try::new(&self.flow));
+            self.push_scope();
+            self.write(param, Value::new(thrown.thrown));
+            self.check_statement(body);
+            self.pop_scope();
+            thrown = self.tries.pop().expect("try popped");
+            done.join(&self.flow);
+        }
+
+        // What the catch does not handle is thrown on to any enclosing try
+        self.throw(thrown.thrown);
+        if let Some(ref mut enclosing) = self.tries.last_mut() {
+            enclosing.flows.join(&thrown.flows.truncated(enclosing.depth));
+        }
+
+        self.flow = done;
+        if let Some(ref fin) = *finally {
+            let live = self.flow.live;
+            self.flow.join(&Flow {live: true, ..thrown.flows});
+            self.check_statement(fin);
+            self.flow.live &= live;
+        }
+    }
+
+    // Each clause is entered from the test that matched it, or from the
+    // clause before falling through into it
+    fn check_switch(&mut self, disc: &Expression, cases: &[CaseData]) {
+        self.check_expression(disc);
+
+        let mut matched = Vec::new();
+        for case in cases {
+            matched.push(case.test.as_ref().map(|test| {
+                self.check_expression(test);
+                self.flow.clone()
+            }));
+        }
+        let unmatched = self.flow.clone();
+
+        let depth = self.flow.scopes.len();
+        let mut fall = Flow {live: false, ..unmatched.clone()};
+        self.breaks.push((depth, fall.clone()));
+        for (case, entry) in cases.iter().zip(matched) {
+            self.flow = entry.unwrap_or_else(|| unmatched.clone());
+            self.flow.join(&fall);
+            let mut reported = false;
+            for stmt in &case.body {
+                self.check_reachable(stmt, &mut reported);
+            }
+            fall = self.flow.clone();
+        }
+        let (_, breaks) = self.breaks.pop().expect("break target popped");
+
+        self.flow = fall;
+        self.flow.join(&breaks);
+        if cases.iter().all(|case| case.test.is_some()) {
+            self.flow.join(&unmatched);
+        }
+    }
+}
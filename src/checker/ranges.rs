@@ -0,0 +1,168 @@
+//! Ranges of integers, as the checker follows the numbers a value may be.
+//!
+//! Arithmetic on ranges is exact, less any result past the bounds of an i64,
+//! for which the interpreter raises a range error instead.
+
+use std::cmp;
+
+/// The integers from `lo` to `hi`.  A lower bound of `i64::MIN` or an upper
+/// bound of `i64::MAX` is no bound at all, and a range whose lower bound is
+/// above its upper one is empty.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub lo: i64,
+    pub hi: i64
+}
+
+pub const FULL: Range = Range {lo: i64::MIN, hi: i64::MAX};
+pub const EMPTY: Range = Range {lo: i64::MAX, hi: i64::MIN};
+
+// The range of the given bounds, less what is past those of i64
+fn exact(lo: i128, hi: i128) -> Range {
+    let lo = cmp::max(lo, i64::MIN as i128);
+    let hi = cmp::min(hi, i64::MAX as i128);
+    if lo > hi { EMPTY } else { Range {lo: lo as i64, hi: hi as i64} }
+}
+
+impl Range {
+    pub fn constant(n: i64) -> Range {
+        Range {lo: n, hi: n}
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.lo > self.hi
+    }
+
+    pub fn is_bounded(self) -> bool {
+        self.lo != i64::MIN && self.hi != i64::MAX
+    }
+
+    // The one integer in the range, if it holds just one and it is not a
+    // missing bound
+    pub fn single(self) -> Option<i64> {
+        if self.lo == self.hi && self.is_bounded() { Some(self.lo) } else { None }
+    }
+
+    pub fn hull(self, other: Range) -> Range {
+        if self.is_empty() {
+            return other;
+        }
+        if other.is_empty() {
+            return self;
+        }
+        Range {lo: cmp::min(self.lo, other.lo), hi: cmp::max(self.hi, other.hi)}
+    }
+
+    pub fn intersect(self, other: Range) -> Range {
+        let range = Range {lo: cmp::max(self.lo, other.lo),
+                           hi: cmp::min(self.hi, other.hi)};
+        if range.is_empty() { EMPTY } else { range }
+    }
+
+    // Drops a bound the next range has moved past, so a loop's ranges stop
+    // growing after a few iterations
+    pub fn widen(self, next: Range) -> Range {
+        if self.is_empty() || next.is_empty() {
+            return self.hull(next);
+        }
+        Range {lo: if next.lo < self.lo { i64::MIN } else { self.lo },
+               hi: if next.hi > self.hi { i64::MAX } else { self.hi }}
+    }
+
+    // The integers below every integer of the other range
+    pub fn below(self, other: Range) -> Range {
+        match other.hi.checked_sub(1) {
+            Some(hi) => self.intersect(Range {lo: i64::MIN, hi: hi}),
+            None => EMPTY
+        }
+    }
+
+    // The integers at most every integer of the other range
+    pub fn at_most(self, other: Range) -> Range {
+        self.intersect(Range {lo: i64::MIN, hi: other.hi})
+    }
+
+    pub fn above(self, other: Range) -> Range {
+        match other.lo.checked_add(1) {
+            Some(lo) => self.intersect(Range {lo: lo, hi: i64::MAX}),
+            None => EMPTY
+        }
+    }
+
+    pub fn at_least(self, other: Range) -> Range {
+        self.intersect(Range {lo: other.lo, hi: i64::MAX})
+    }
+
+    // The integers other than the single one of the other range, which can
+    // only be taken off an end
+    pub fn except(self, other: Range) -> Range {
+        match other.single() {
+            Some(n) if self.single() == Some(n) => EMPTY,
+            Some(n) if self.lo == n => Range {lo: n + 1, hi: self.hi},
+            Some(n) if self.hi == n => Range {lo: self.lo, hi: n - 1},
+            _ => self
+        }
+    }
+
+    pub fn neg(self) -> Range {
+        if self.is_empty() {
+            return EMPTY;
+        }
+        exact(-(self.hi as i128), -(self.lo as i128))
+    }
+
+    pub fn add(self, other: Range) -> Range {
+        self.combine(other, |l, r| l + r)
+    }
+
+    pub fn sub(self, other: Range) -> Range {
+        self.combine(other, |l, r| l - r)
+    }
+
+    pub fn mul(self, other: Range) -> Range {
+        self.combine(other, |l, r| l * r)
+    }
+
+    // Division rounds down, as the interpreter's does.  Only division by a
+    // single number is followed; by zero, there is no result.
+    pub fn div(self, other: Range) -> Range {
+        match other {
+            _ if other.single() == Some(0) => EMPTY,
+            _ if other.single().is_some() =>
+                self.combine(other, |l, r| {
+                    let quotient = l / r;
+                    if (l % r != 0) && ((l < 0) != (r < 0)) { quotient - 1 }
+                    else { quotient }
+                }),
+            _ => if self.is_empty() || other.is_empty() { EMPTY } else { FULL }
+        }
+    }
+
+    // A remainder takes the sign of the dividend and is smaller than the
+    // divisor
+    pub fn rem(self, other: Range) -> Range {
+        if self.is_empty() || other.is_empty() || other.single() == Some(0) {
+            return EMPTY;
+        }
+
+        let most = cmp::max((other.lo as i128).abs(), (other.hi as i128).abs()) - 1;
+        let lo = if self.lo >= 0 { 0 } else { -most };
+        let hi = if self.hi <= 0 { 0 } else { most };
+        exact(lo, hi)
+    }
+
+    // Applies an operation monotone in each operand to the ends of the ranges
+    fn combine<F>(self, other: Range, f: F) -> Range
+        where F: Fn(i128, i128) -> i128 {
+
+        if self.is_empty() || other.is_empty() {
+            return EMPTY;
+        }
+
+        let ends = [f(self.lo as i128, other.lo as i128),
+                    f(self.lo as i128, other.hi as i128),
+                    f(self.hi as i128, other.lo as i128),
+                    f(self.hi as i128, other.hi as i128)];
+        exact(*ends.iter().min().unwrap(), *ends.iter().max().unwrap())
+    }
+}
@@ -0,0 +1,601 @@
+//! Compiles a resolved program to a bytecode module for the virtual machine.
+//!
+//! A `break`, `return` or thrown value may leave blocks with scopes of their
+//! own, try statements and finally blocks.  The compiler tracks these as
+//! regions: leaving one closes its scope or handler, and leaving a finally
+//! region runs a copy of the finally block compiled in its place.  A thrown
+//! value instead finds its handler at run time, which runs the finally block
+//! and throws the value again.
+
+use bytecode::*;
+use bytecode::Op::*;
+
+use std::collections::HashMap;
+use std::mem;
+
+use ast::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
+use ast::SourceElement::*;
+use ast::Program::*;
+use ast::BinaryOperator::*;
+use ast::Declaration::*;
+
+enum Region<'a> {
+    /// A loop or switch, with the jumps of the breaks out of it
+    Breakable(Vec<usize>),
+    Scope,
+    Handler,
+    Finally(&'a Statement)
+}
+
+struct Compiler<'a> {
+    constants: Vec<Constant>,
+    constant_indices: HashMap<Constant, u32>,
+    names: Vec<String>,
+    name_indices: HashMap<String, u32>,
+    functions: Vec<Function>,
+    /// The code of the function being compiled, and the source line of each
+    /// instruction
+    code: Vec<Op>,
+    lines: Vec<u32>,
+    /// The line of the source element being compiled
+    line: u32,
+    /// The regions of that function enclosing the code, innermost last
+    regions: Vec<Region<'a>>
+}
+
+pub fn compile(prog: &Program) -> Module {
+    let Prog(ref ses, _) = *prog;
+
+    let mut compiler = Compiler {constants: Vec::new(),
+                                 constant_indices: HashMap::new(),
+                                 names: Vec::new(),
+                                 name_indices: HashMap::new(),
+                                 functions: Vec::new(),
+                                 code: Vec::new(),
+                                 lines: Vec::new(),
+                                 line: 1,
+                                 regions: Vec::new()};
+
+    compiler.function(None, false, |c| {
+        c.hoist(ses);
+        c.source_elements(ses);
+        c.line = ses.last().map_or(1, SourceElement::line) as u32;
+        c.emit(Halt);
+    });
+
+    Module {constants: compiler.constants,
+            names: compiler.names,
+            functions: compiler.functions}
+}
+
+fn slot(id: &Ident) -> Slot {
+    id.slot.unwrap_or_else(|| panic!("variable '{}' was not resolved", id))
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.lines.push(self.line);
+        self.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    // Points the jump at the given index to the next instruction
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match self.code[at] {
+            Jump(ref mut t) | JumpUnless(_, ref mut t) | JumpIf(ref mut t) |
+            JumpIfDefined(ref mut t) | ShortCircuit(_, ref mut t) |
+            Try(ref mut t) => *t = target,
+            op => panic!("cannot patch non-jump '{:?}'", op)
+        }
+    }
+
+    fn constant(&mut self, constant: Constant) -> u32 {
+        if let Some(&index) = self.constant_indices.get(&constant) {
+            return index;
+        }
+
+        let index = self.constants.len() as u32;
+        self.constants.push(constant.clone());
+        self.constant_indices.insert(constant, index);
+        index
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        if let Some(&index) = self.name_indices.get(name) {
+            return index;
+        }
+
+        let index = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.name_indices.insert(name.to_string(), index);
+        index
+    }
+
+    fn var(&mut self, id: &Ident) -> Var {
+        let slot = slot(id);
+        Var {depth: slot.depth as u32,
+             index: slot.index as u32,
+             name: self.name(&id.name),
+             implicit: slot.implicit}
+    }
+
+    fn declare(&mut self, id: &Ident) {
+        let name = self.name(&id.name);
+        self.emit(Declare(slot(id).index as u32, name));
+    }
+
+    // Compiles a function, returning its index in the module
+    fn function<F>(&mut self, name: Option<&Ident>, arrow: bool, body: F) -> u32
+        where F: FnOnce(&mut Compiler<'a>) {
+
+        let name = name.map(|id| self.name(&id.name));
+        let index = self.functions.len();
+        self.functions.push(Function {name: name,
+                                      arrow: arrow,
+                                      code: Vec::new(),
+                                      lines: Vec::new()});
+
+        let code = mem::take(&mut self.code);
+        let lines = mem::take(&mut self.lines);
+        let regions = mem::take(&mut self.regions);
+        let line = self.line;
+        body(self);
+        self.functions[index].code = mem::replace(&mut self.code, code);
+        self.functions[index].lines = mem::replace(&mut self.lines, lines);
+        self.regions = regions;
+        self.line = line;
+
+        index as u32
+    }
+
+    fn function_body(&mut self, params: &[Ident], body: &'a [SourceElement]) {
+        for (i, param) in params.iter().enumerate() {
+            self.emit(Arg(i as u32));
+            self.declare(param);
+        }
+
+        self.hoist(body);
+        self.source_elements(body);
+        self.emit(Undefined);
+        self.emit(Return);
+    }
+
+    fn arrow(&mut self, arrow: &'a ExpArrowData) -> u32 {
+        self.function(None, true, |c| {
+            for (i, param) in arrow.params.iter().enumerate() {
+                c.emit(Arg(i as u32));
+                if let Some(ref default) = param.default {
+                    let given = c.emit(JumpIfDefined(0));
+                    c.expression(default);
+                    c.patch(given);
+                }
+                c.declare(&param.name);
+            }
+
+            if let Some(ref rest) = arrow.rest {
+                c.emit(Rest(arrow.params.len() as u32));
+                c.declare(rest);
+            }
+
+            match arrow.body {
+                FunctionBody::BodyExp(ref exp) => c.expression(exp),
+                FunctionBody::BodyBlock(ref ses) => {
+                    c.hoist(ses);
+                    c.source_elements(ses);
+                    c.emit(Undefined);
+                }
+            }
+            c.emit(Return);
+        })
+    }
+
+    // As the interpreter binds var and function declarations on entering a
+    // body
+    fn hoist(&mut self, ses: &'a [SourceElement]) {
+        let line = self.line;
+
+        for se in ses {
+            self.line = se.line() as u32;
+            match *se {
+                VarDecl(ref decls, _) => for decl in decls {
+                    let name = self.name(decl.id());
+                    self.emit(Hoist(slot(decl.ident()).index as u32, name));
+                },
+                FunctionDecl(FunctionDeclData {ref name, ref params, ref body},
+                             _) => {
+                    let f = self.function(Some(name), false,
+                                          |c| c.function_body(params, body));
+                    self.emit(Closure(f));
+                    self.declare(name);
+                }
+                _ => ()
+            }
+        }
+
+        self.line = line;
+    }
+
+    // Compiles the elements of a body or block, each at its own line, then
+    // returns to the line of the element enclosing them
+    fn source_elements(&mut self, ses: &'a [SourceElement]) {
+        let line = self.line;
+
+        for se in ses {
+            self.line = se.line() as u32;
+            match *se {
+                Stmt(ref stmt, _) => self.statement(stmt),
+                LetDecl(ref decls, _) |
+                ConstDecl(ref decls, _) => for decl in decls {
+                    match *decl {
+                        DeclId(_) => {self.emit(Undefined);},
+                        DeclInit(DeclInitData {ref src, ..}) =>
+                            self.expression(src)
+                    }
+                    self.declare(decl.ident());
+                },
+                VarDecl(ref decls, _) => for decl in decls {
+                    if let DeclInit(DeclInitData {ref id, ref src}) = *decl {
+                        self.expression(src);
+                        self.declare(id);
+                    }
+                },
+                FunctionDecl(..) => ()
+            }
+        }
+
+        self.line = line;
+    }
+
+    // Leaves the regions from the innermost out to the given one
+    fn leave_regions(&mut self, outer: usize) {
+        for i in (outer..self.regions.len()).rev() {
+            match self.regions[i] {
+                Region::Breakable(_) => (),
+                Region::Scope => {self.emit(PopScope);},
+                Region::Handler => {self.emit(EndTry);},
+                Region::Finally(fin) => {
+                    let inner = self.regions.split_off(i);
+                    self.statement(fin);
+                    self.regions.extend(inner);
+                }
+            }
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let target = self.regions.iter()
+            .rposition(|r| matches!(*r, Region::Breakable(_)))
+            .expect("Parser allowed 'break' outside of a loop");
+
+        self.leave_regions(target + 1);
+        let jump = self.emit(Jump(0));
+        if let Region::Breakable(ref mut breaks) = self.regions[target] {
+            breaks.push(jump);
+        }
+    }
+
+    // Compiles the body of a loop or switch, then points its breaks at what
+    // follows
+    fn breakable<F>(&mut self, body: F) where F: FnOnce(&mut Compiler<'a>) {
+        self.regions.push(Region::Breakable(Vec::new()));
+        body(self);
+        if let Some(Region::Breakable(breaks)) = self.regions.pop() {
+            for jump in breaks {
+                self.patch(jump);
+            }
+        }
+    }
+
+    fn block_statement(&mut self, ses: &'a [SourceElement]) {
+        let lexical = ses.iter()
+            .any(|se| matches!(*se, LetDecl(..) | ConstDecl(..)));
+
+        if !lexical {
+            return self.source_elements(ses);
+        }
+
+        self.emit(PushScope);
+        self.regions.push(Region::Scope);
+        self.source_elements(ses);
+        self.regions.pop();
+        self.emit(PopScope);
+    }
+
+    fn try_statement(
+        &mut self,
+        body: &'a Statement,
+        catch: &'a Option<CatchData>,
+        finally: &'a Option<Box<Statement>>
+        ) {
+
+        if let Some(ref fin) = *finally {
+            self.regions.push(Region::Finally(fin));
+        }
+
+        let handler = self.emit(Try(0));
+        self.regions.push(Region::Handler);
+        self.statement(body);
+        self.regions.pop();
+        self.emit(EndTry);
+
+        // Jumps to the end, or to the finally block run on the way there
+        let mut completed = vec![self.emit(Jump(0))];
+        // Handlers for values thrown past the finally block
+        let mut rethrows = vec![handler];
+
+        if let Some(CatchData {ref param, ref body}) = *catch {
+            self.patch(handler);
+            rethrows.clear();
+
+            if finally.is_some() {
+                rethrows.push(self.emit(Try(0)));
+                self.regions.push(Region::Handler);
+            }
+
+            self.emit(PushScope);
+            self.regions.push(Region::Scope);
+            self.declare(param);
+            self.statement(body);
+            self.regions.pop();
+            self.emit(PopScope);
+
+            if finally.is_some() {
+                self.regions.pop();
+                self.emit(EndTry);
+                completed.push(self.emit(Jump(0)));
+            }
+        }
+
+        match *finally {
+            Some(ref fin) => {
+                self.regions.pop();
+
+                for jump in completed {
+                    self.patch(jump);
+                }
+                self.statement(fin);
+                let end = self.emit(Jump(0));
+
+                for handler in rethrows {
+                    self.patch(handler);
+                }
+                self.statement(fin);
+                self.emit(Throw);
+
+                self.patch(end);
+            }
+            None => for jump in completed {
+                self.patch(jump);
+            }
+        }
+    }
+
+    // The discriminant stays on the stack until the switch ends
+    fn switch_statement(&mut self, disc: &'a Expression, cases: &'a [CaseData]) {
+        self.expression(disc);
+
+        let mut entries = Vec::new();
+        for case in cases {
+            entries.push(case.test.as_ref().map(|test| {
+                self.emit(Dup);
+                self.expression(test);
+                self.emit(Binary(BopEq));
+                self.emit(JumpIf(0))
+            }));
+        }
+        let no_match = self.emit(Jump(0));
+        let mut default = None;
+
+        self.breakable(|c| {
+            for (case, entry) in cases.iter().zip(entries) {
+                match entry {
+                    Some(jump) => c.patch(jump),
+                    None => default = Some(c.here())
+                }
+                for stmt in &case.body {
+                    c.statement(stmt);
+                }
+            }
+        });
+
+        match default {
+            Some(target) => self.code[no_match] = Jump(target),
+            None => self.patch(no_match)
+        }
+        self.emit(Pop);
+    }
+
+    fn statement(&mut self, stmt: &'a Statement) {
+        match stmt.kind {
+            StExp(ref exp) => {
+                self.expression(exp);
+                self.emit(Pop);
+            }
+            StPrint(ref exp) => {
+                self.expression(exp);
+                self.emit(Print);
+            }
+            StBlock(ref ses) => self.block_statement(ses),
+            StIf(StIfData {ref guard, ref th, ref el}) => {
+                self.expression(guard);
+                let skip_th = self.emit(JumpUnless(Guard::If, 0));
+                self.statement(th);
+                let skip_el = self.emit(Jump(0));
+                self.patch(skip_th);
+                self.statement(el);
+                self.patch(skip_el);
+            }
+            StWhile(StWhileData {ref guard, ref body}) => {
+                let top = self.here();
+                self.expression(guard);
+                let exit = self.emit(JumpUnless(Guard::While, 0));
+                self.breakable(|c| {
+                    c.statement(body);
+                    c.emit(Jump(top));
+                    c.patch(exit);
+                });
+            }
+            StThrow(ref exp) => {
+                self.expression(exp);
+                self.emit(Throw);
+            }
+            StTry(StTryData {ref body, ref catch, ref finally}) =>
+                self.try_statement(body, catch, finally),
+            StSwitch(StSwitchData {ref disc, ref cases}) =>
+                self.switch_statement(disc, cases),
+            StBreak => self.break_statement(),
+            StGc => {self.emit(Gc);},
+            StHeapSnapshot(ref exp) => {
+                self.expression(exp);
+                self.emit(HeapSnapshot);
+            }
+            StReturn(ref exp) => {
+                match *exp {
+                    Some(ref exp) => self.expression(exp),
+                    None => {self.emit(Undefined);}
+                }
+                self.leave_regions(0);
+                self.emit(Return);
+            }
+        }
+    }
+
+    fn expression(&mut self, exp: &'a Expression) {
+        match exp.kind {
+            ExpId(ref id) => {
+                let var = self.var(id);
+                self.emit(Load(var));
+            }
+            ExpNum(n) => {
+                let k = self.constant(Constant::Num(n));
+                self.emit(Const(k));
+            }
+            ExpString(ref s) => {
+                let k = self.constant(Constant::Str(s.clone()));
+                self.emit(Const(k));
+            }
+            ExpTrue => {self.emit(True);},
+            ExpFalse => {self.emit(False);},
+            ExpUndefined => {self.emit(Undefined);},
+            ExpThis => {self.emit(This);},
+            ExpInUse => {self.emit(InUse);},
+            ExpUnary(ExpUnaryData {opr, ref opnd}) => {
+                self.expression(opnd);
+                self.emit(Unary(opr));
+            }
+            ExpBinary(ExpBinaryData {opr, ref lft, ref rht})
+                if opr == BopAnd || opr == BopOr => {
+                self.expression(lft);
+                let decided = self.emit(ShortCircuit(opr, 0));
+                self.expression(rht);
+                self.emit(CheckBoolean(opr));
+                self.patch(decided);
+            }
+            ExpBinary(ExpBinaryData {opr, ref lft, ref rht}) => {
+                self.expression(lft);
+                self.expression(rht);
+                self.emit(Binary(opr));
+            }
+            ExpCond(ExpCondData {ref guard, ref then_exp, ref else_exp}) => {
+                self.expression(guard);
+                let skip_then = self.emit(JumpUnless(Guard::Cond, 0));
+                self.expression(then_exp);
+                let skip_else = self.emit(Jump(0));
+                self.patch(skip_then);
+                self.expression(else_exp);
+                self.patch(skip_else);
+            }
+            ExpAssign(ExpAssignData {ref lft, ref rht}) => match lft.kind {
+                ExpId(ref id) => {
+                    self.expression(rht);
+                    let var = self.var(id);
+                    self.emit(Store(var));
+                }
+                _ => panic!("Parser allowed assignment to '{}'", lft)
+            },
+            ExpCompoundAssign(ExpCompoundAssignData {opr, ref lft, ref rht}) =>
+                match lft.kind {
+                    ExpId(ref id) => {
+                        let var = self.var(id);
+                        self.emit(Load(var));
+                        self.expression(rht);
+                        self.emit(Binary(opr));
+                        self.emit(Store(var));
+                    }
+                    _ => panic!("Parser allowed assignment to '{}'", lft)
+                },
+            ExpUpdate(ExpUpdateData {opr, prefix, ref opnd}) => match opnd.kind {
+                ExpId(ref id) => {
+                    let var = self.var(id);
+                    self.emit(Update(opr, prefix, var));
+                }
+                _ => panic!("Parser allowed assignment to '{}'", opnd)
+            },
+            ExpDot(ExpDotData {ref obj, ref prop}) => {
+                self.expression(obj);
+                let name = self.name(prop);
+                self.emit(Dot(name));
+            }
+            ExpTemplate(ExpTemplateData {ref chunks, ref exps}) => {
+                let mut chunks = chunks.iter();
+                let mut count = 0;
+                if let Some(chunk) = chunks.next() {
+                    let k = self.constant(Constant::Str(chunk.cooked.clone()));
+                    self.emit(Const(k));
+                    count += 1;
+                }
+                for (exp, chunk) in exps.iter().zip(chunks) {
+                    self.expression(exp);
+                    let k = self.constant(Constant::Str(chunk.cooked.clone()));
+                    self.emit(Const(k));
+                    count += 2;
+                }
+                self.emit(Template(count));
+            }
+            ExpArray(ref elems) => {
+                for elem in elems {
+                    self.expression(elem);
+                }
+                self.emit(Array(elems.len() as u32));
+            }
+            ExpIndex(ExpIndexData {ref obj, ref index}) => {
+                self.expression(obj);
+                self.expression(index);
+                self.emit(Index);
+            }
+            ExpCall(ExpCallData {ref func, ref args}) => {
+                self.expression(func);
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.emit(Call(args.len() as u32));
+            }
+            ExpArrow(ref arrow) => {
+                let f = self.arrow(arrow);
+                self.emit(Closure(f));
+            }
+            // A named function expression sees its name in a scope of its own
+            ExpFunction(ExpFunctionData {ref name, ref params, ref body}) => {
+                let f = self.function(name.as_ref(), false,
+                                      |c| c.function_body(params, body));
+                match *name {
+                    None => {self.emit(Closure(f));},
+                    Some(ref name) => {
+                        self.emit(PushScope);
+                        self.emit(Closure(f));
+                        self.emit(Dup);
+                        self.declare(name);
+                        self.emit(PopScope);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// The index of a heap cell.  Two references are equal only if they refer to
+/// the same cell, as with JavaScript objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HeapRef(usize);
+
+impl HeapRef {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Number of cells in use at which allocation first triggers a collection
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// Cells and values whose memory the heap accounts for
+pub trait HeapSize {
+    /// Approximate number of bytes the cell or value occupies, including data
+    /// it owns
+    fn heap_size(&self) -> usize;
+}
+
+/// Totals over the life of a heap, reported by --gc-stats.  Values are the
+/// strings and other data made outside of the cells, freed as soon as
+/// nothing holds them rather than by a collection; the bytes are those of
+/// cells and values together.
+#[derive(Clone, Debug, Default)]
+pub struct HeapStats {
+    pub collections: usize,
+    pub cells_allocated: usize,
+    pub values_allocated: usize,
+    pub bytes_allocated: usize,
+    pub cells_freed: usize,
+    pub values_freed: usize,
+    pub bytes_freed: usize,
+    pub peak_cells: usize,
+    pub peak_bytes: usize,
+    pub pause: Duration
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "collections: {}", self.collections)?;
+        writeln!(f, "allocated: {} cells, {} values, {} bytes",
+                 self.cells_allocated, self.values_allocated,
+                 self.bytes_allocated)?;
+        writeln!(f, "freed: {} cells, {} values, {} bytes",
+                 self.cells_freed, self.values_freed, self.bytes_freed)?;
+        writeln!(f, "peak live: {} cells, {} bytes",
+                 self.peak_cells, self.peak_bytes)?;
+        write!(f, "total pause: {:.3} ms",
+               self.pause.as_secs() as f64 * 1e3 +
+               self.pause.subsec_nanos() as f64 / 1e6)
+    }
+}
+
+/// Counts of the values made outside of the cells, shared by the heap with
+/// each of them so that dropping one is counted
+#[derive(Debug, Default)]
+struct ValueCounts {
+    allocated: Cell<usize>,
+    allocated_bytes: Cell<usize>,
+    freed: Cell<usize>,
+    freed_bytes: Cell<usize>
+}
+
+impl ValueCounts {
+    fn live_bytes(&self) -> usize {
+        self.allocated_bytes.get() - self.freed_bytes.get()
+    }
+}
+
+/// Data made outside of the cells, such as a string, which the heap counts
+/// from when it is made until the last reference to it is dropped
+pub struct Tracked<T> {
+    data: T,
+    bytes: usize,
+    counts: Rc<ValueCounts>
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T: PartialEq> PartialEq for Tracked<T> {
+    fn eq(&self, other: &Tracked<T>) -> bool {
+        self.data == other.data
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Tracked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.data.fmt(f)
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        let counts = &self.counts;
+        counts.freed.set(counts.freed.get() + 1);
+        counts.freed_bytes.set(counts.freed_bytes.get() + self.bytes);
+    }
+}
+
+/// What one collection found, reported by --gc-trace
+#[derive(Clone, Copy, Debug)]
+pub struct Collection {
+    pub cells_before: usize,
+    pub bytes_before: usize,
+    pub cells_after: usize,
+    pub bytes_after: usize
+}
+
+/// Cells allocated by a script, reclaimed by mark and sweep.  The heap does
+/// not know what a cell refers to: whoever collects supplies the roots and a
+/// function listing the references held by a cell.
+pub struct Heap<T> {
+    cells: Vec<Option<T>>,
+    /// Indices of swept cells, reused before the heap grows
+    free: Vec<usize>,
+    threshold: usize,
+    /// Bytes of the cells not yet swept
+    live_bytes: usize,
+    values: Rc<ValueCounts>,
+    /// Most bytes of cells and values live at once, which a value made
+    /// through a shared reference to the heap may raise
+    peak_bytes: Cell<usize>,
+    stats: HeapStats
+}
+
+impl<T: HeapSize> Heap<T> {
+    pub fn new() -> Heap<T> {
+        Heap {cells: Vec::new(),
+              free: Vec::new(),
+              threshold: INITIAL_THRESHOLD,
+              live_bytes: 0,
+              values: Rc::new(ValueCounts::default()),
+              peak_bytes: Cell::new(0),
+              stats: HeapStats::default()}
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let values = &self.values;
+        HeapStats {values_allocated: values.allocated.get(),
+                   bytes_allocated: self.stats.bytes_allocated +
+                       values.allocated_bytes.get(),
+                   values_freed: values.freed.get(),
+                   bytes_freed: self.stats.bytes_freed + values.freed_bytes.get(),
+                   peak_bytes: self.peak_bytes.get(),
+                   ..self.stats.clone()}
+    }
+
+    fn note_peak(&self) {
+        let live = self.live_bytes + self.values.live_bytes();
+        self.peak_bytes.set(self.peak_bytes.get().max(live));
+    }
+
+    pub fn alloc(&mut self, cell: T) -> HeapRef {
+        let size = cell.heap_size();
+        self.live_bytes += size;
+        self.stats.cells_allocated += 1;
+        self.stats.bytes_allocated += size;
+
+        let r = match self.free.pop() {
+            Some(i) => {
+                self.cells[i] = Some(cell);
+                HeapRef(i)
+            }
+            None => {
+                self.cells.push(Some(cell));
+                HeapRef(self.cells.len() - 1)
+            }
+        };
+
+        self.stats.peak_cells = self.stats.peak_cells.max(self.in_use());
+        self.note_peak();
+        r
+    }
+
+    /// Makes a value outside of the cells, which the heap does not hold and
+    /// never collects, but counts until it is dropped
+    pub fn track<V: HeapSize>(&self, data: V) -> Rc<Tracked<V>> {
+        let bytes = data.heap_size();
+        let values = &self.values;
+        values.allocated.set(values.allocated.get() + 1);
+        values.allocated_bytes.set(values.allocated_bytes.get() + bytes);
+        self.note_peak();
+
+        Rc::new(Tracked {data: data, bytes: bytes, counts: values.clone()})
+    }
+
+    pub fn get(&self, r: HeapRef) -> &T {
+        match self.cells[r.0] {
+            Some(ref cell) => cell,
+            None => panic!("reference to swept heap cell {}", r.0)
+        }
+    }
+
+    /// Number of cells allocated and not yet swept, whether or not they are
+    /// still reachable
+    pub fn in_use(&self) -> usize {
+        self.cells.len() - self.free.len()
+    }
+
+    /// Whether the next allocation should be preceded by a collection
+    pub fn is_full(&self) -> bool {
+        self.in_use() >= self.threshold
+    }
+
+    /// Sweeps every cell not reachable from the roots.  The next automatic
+    /// collection waits until the heap has doubled from what survived.
+    pub fn collect<F>(&mut self, roots: Vec<HeapRef>, mut trace: F) -> Collection
+        where F: FnMut(&T, &mut Vec<HeapRef>) {
+
+        let start = Instant::now();
+        let (cells_before, bytes_before) = (self.in_use(), self.live_bytes);
+
+        let mut marked = vec![false; self.cells.len()];
+        let mut pending = roots;
+
+        while let Some(r) = pending.pop() {
+            if !marked[r.0] {
+                marked[r.0] = true;
+                trace(self.get(r), &mut pending);
+            }
+        }
+
+        for (i, cell) in self.cells.iter_mut().enumerate() {
+            if !marked[i] {
+                if let Some(swept) = cell.take() {
+                    self.live_bytes -= swept.heap_size();
+                    self.free.push(i);
+                }
+            }
+        }
+
+        self.threshold = INITIAL_THRESHOLD.max(2 * self.in_use());
+
+        let collection = Collection {cells_before: cells_before,
+                                     bytes_before: bytes_before,
+                                     cells_after: self.in_use(),
+                                     bytes_after: self.live_bytes};
+        self.stats.collections += 1;
+        self.stats.cells_freed += cells_before - collection.cells_after;
+        self.stats.bytes_freed += bytes_before - collection.bytes_after;
+        self.stats.pause += start.elapsed();
+        collection
+    }
+}
@@ -1,39 +1,543 @@
 use types::{JsishResult, JsishError};
+use heap::{Heap, HeapRef, HeapSize, Tracked};
+use snapshot::{Snapshot, Node};
+use bytecode::Module;
 
 use ast::*;
-use ast::Expression::*;
-use ast::Statement::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
 use ast::SourceElement::*;
 use ast::Program::*;
 use ast::BinaryOperator::*;
 use ast::UnaryOperator::*;
+use ast::UpdateOperator::*;
+use ast::Declaration::*;
 
 use std::fmt;
+use std::mem;
+use std::iter;
+use std::fs::File;
+use std::io::BufWriter;
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+mod vm;
+
+#[derive(Clone, PartialEq)]
+struct ErrorData {
+    name: String,
+    message: String
+}
+
+/// A function together with the scope it was created in.  Arrow functions
+/// also keep the 'this' of that scope; other functions have an undefined one.
+struct Closure {
+    /// For heap snapshots; arrow and anonymous functions have none
+    name: Option<String>,
+    code: Code,
+    scope: ScopeRef,
+    this: Value
+}
+
+/// What a closure runs: a function of the AST for the interpreter, or one of
+/// the module compiled for the virtual machine
+enum Code {
+    Tree {
+        params: Rc<Vec<Param>>,
+        rest: Option<Ident>,
+        body: FunctionBody
+    },
+    Compiled(usize)
+}
 
 #[derive(Clone, PartialEq)]
 enum Value {
     NumValue(i64),
-    StringValue(String),
+    StringValue(Rc<Tracked<String>>),
     BoolValue(bool),
-    UndefinedValue
+    ErrorValue(Rc<Tracked<ErrorData>>),
+    ArrayValue(HeapRef),
+    FunctionValue(HeapRef),
+    UndefinedValue,
+    // Only produced by loose coercion, e.g. ToNumber("abc")
+    NaNValue
 }
 
 use self::Value::*;
 
-impl fmt::Display for Value {
+/// The data behind array and function values
+enum HeapCell {
+    ArrayCell(Vec<Value>),
+    ClosureCell(Rc<Closure>)
+}
+
+use self::HeapCell::*;
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl HeapSize for ErrorData {
+    fn heap_size(&self) -> usize {
+        self.name.len() + self.message.len()
+    }
+}
+
+// Bytes owned by a value outside of the value itself
+fn owned_bytes(val: &Value) -> usize {
+    match *val {
+        StringValue(ref s) => s.heap_size(),
+        ErrorValue(ref error) => error.heap_size(),
+        _ => 0
+    }
+}
+
+// Strings and errors are not heap cells, but the heap counts each from when
+// it is made until the last value holding it is dropped
+fn string_value(s: String, heap: &Heap<HeapCell>) -> Value {
+    StringValue(heap.track(s))
+}
+
+fn error_value(error: ErrorData, heap: &Heap<HeapCell>) -> Value {
+    ErrorValue(heap.track(error))
+}
+
+impl HeapSize for HeapCell {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<HeapCell>() + match *self {
+            ArrayCell(ref elems) => elems.len() * mem::size_of::<Value>(),
+            ClosureCell(ref closure) => mem::size_of::<Closure>() +
+                match closure.code {
+                    Code::Tree { ref params, .. } =>
+                        params.len() * mem::size_of::<Param>(),
+                    Code::Compiled(_) => 0
+                }
+        }
+    }
+}
+
+/// A value together with the heap holding what it refers to, for display
+struct Shown<'a>(&'a Value, &'a Heap<HeapCell>);
+
+impl<'a> fmt::Display for Shown<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        let Shown(val, heap) = *self;
+        match *val {
             NumValue(ref n) => write!(f, "{}", n),
             StringValue(ref s) => write!(f, "{}", s),
             BoolValue(ref b) => write!(f, "{}", b),
+            ErrorValue(ref error) =>
+                write!(f, "{}: {}", error.name, error.message),
+            ArrayValue(r) => {
+                for (i, elem) in array_elements(heap, r).iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", Shown(elem, heap))?;
+                }
+                Ok(())
+            }
+            FunctionValue(_) => write!(f, "function"),
             UndefinedValue => write!(f, "undefined"),
+            NaNValue => write!(f, "NaN"),
+        }
+    }
+}
+
+// Ways a statement or expression can complete other than normally.  Errors
+// raised by the interpreter itself become error values, which scripts can
+// catch, once they reach a handler.
+enum Abrupt {
+    Throw(Value),
+    Raise(ErrorData),
+    Break,
+    Return(Value)
+}
+
+use self::Abrupt::*;
+
+type EvalResult<T> = Result<T, Abrupt>;
+
+fn throw_error(name: &str, message: String) -> Abrupt {
+    Raise(ErrorData {name: String::from(name), message: message})
+}
+
+// Makes a raised error the value a handler receives
+fn raised<T>(result: EvalResult<T>, env: Environment) -> EvalResult<T> {
+    match result {
+        Err(Raise(error)) => Err(Throw(error_value(error, &env.heap))),
+        result => result
+    }
+}
+
+fn type_error(message: String) -> Abrupt {
+    throw_error("TypeError", message)
+}
+
+fn reference_error(message: String) -> Abrupt {
+    throw_error("ReferenceError", message)
+}
+
+fn range_error(message: String) -> Abrupt {
+    throw_error("RangeError", message)
+}
+
+/// How operators and guards treat operands of the wrong type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coercion {
+    /// The course semantics: mismatched operand types are type errors.
+    Strict,
+    /// JavaScript's implicit ToNumber/ToString/ToBoolean conversions.
+    Loose
+}
+
+/// What the interpreter reports about garbage collection, on stderr unless
+/// written to a file
+#[derive(Clone, Debug, Default)]
+pub struct GcReporting {
+    /// Heap statistics once the program finishes
+    pub stats: bool,
+    /// A line for each collection
+    pub trace: bool,
+    /// A file for a heap snapshot once the program finishes
+    pub snapshot: Option<String>
+}
+
+/// Variable bindings.  A function call, catch clause or block declaring let
+/// or const variables runs in a new scope whose parent is the scope it
+/// appears in.  Variables are held in the slots the resolver gave them; a
+/// slot is None until its declaration runs, or for an implicit global until
+/// it is first assigned.
+struct Scope {
+    vars: Vec<Option<Value>>,
+    /// The name of each slot, for heap snapshots
+    names: Vec<String>,
+    parent: Option<ScopeRef>
+}
+
+impl Scope {
+    fn set(&mut self, index: usize, name: &str, val: Value) {
+        if index >= self.vars.len() {
+            self.vars.resize(index + 1, None);
+            self.names.resize(index + 1, String::new());
+        }
+        if self.names[index].is_empty() {
+            self.names[index] = name.to_string();
+        }
+        self.vars[index] = Some(val);
+    }
+}
+
+type ScopeRef = Rc<RefCell<Scope>>;
+
+fn new_scope(parent: Option<ScopeRef>) -> ScopeRef {
+    Rc::new(RefCell::new(Scope {vars: Vec::new(),
+                                names: Vec::new(),
+                                parent: parent}))
+}
+
+struct State {
+    scope: ScopeRef,
+    this: Value,
+    coercion: Coercion,
+    gc_reporting: GcReporting,
+    heap: Heap<HeapCell>,
+    /// The scope and 'this' of each caller of the running function
+    frames: Vec<(ScopeRef, Value)>,
+    /// Values computed but not yet stored anywhere, such as the left operand
+    /// while the right one is evaluated
+    temps: Vec<Value>
+}
+
+type Environment<'a> = &'a mut State;
+
+fn array_elements(heap: &Heap<HeapCell>, r: HeapRef) -> &[Value] {
+    match *heap.get(r) {
+        ArrayCell(ref elems) => elems,
+        ClosureCell(_) => panic!("array value refers to a closure")
+    }
+}
+
+fn heap_closure(heap: &Heap<HeapCell>, r: HeapRef) -> Rc<Closure> {
+    match *heap.get(r) {
+        ClosureCell(ref closure) => closure.clone(),
+        ArrayCell(_) => panic!("function value refers to an array")
+    }
+}
+
+fn value_ref(val: &Value) -> Option<HeapRef> {
+    match *val {
+        ArrayValue(r) | FunctionValue(r) => Some(r),
+        _ => None
+    }
+}
+
+// Lists the cells referred to from a scope or its ancestors, skipping scopes
+// already visited
+fn trace_scope(
+    scope: &ScopeRef,
+    visited: &mut HashSet<*const RefCell<Scope>>,
+    refs: &mut Vec<HeapRef>
+    ) {
+
+    let mut current = Some(scope.clone());
+
+    while let Some(scope) = current {
+        if !visited.insert(&*scope as *const RefCell<Scope>) {
+            return;
+        }
+
+        let scope = scope.borrow();
+        refs.extend(scope.vars.iter()
+                    .filter_map(|val| val.as_ref().and_then(value_ref)));
+        current = scope.parent.clone();
+    }
+}
+
+// The roots are the scope chains and 'this' of the running function and its
+// callers, the temporaries, and whatever the cell about to be allocated, if
+// any, refers to
+fn collect_garbage(reason: &str, pending: Option<&HeapCell>, env: Environment) {
+    let mut visited = HashSet::new();
+    let mut roots = Vec::new();
+
+    trace_scope(&env.scope, &mut visited, &mut roots);
+    roots.extend(value_ref(&env.this));
+    for &(ref scope, ref this) in &env.frames {
+        trace_scope(scope, &mut visited, &mut roots);
+        roots.extend(value_ref(this));
+    }
+    roots.extend(env.temps.iter().filter_map(value_ref));
+
+    let mut trace = |cell: &HeapCell, refs: &mut Vec<HeapRef>| match *cell {
+        ArrayCell(ref elems) => refs.extend(elems.iter().filter_map(value_ref)),
+        ClosureCell(ref closure) => {
+            trace_scope(&closure.scope, &mut visited, refs);
+            refs.extend(value_ref(&closure.this));
+        }
+    };
+
+    if let Some(cell) = pending {
+        trace(cell, &mut roots);
+    }
+
+    let collection = env.heap.collect(roots, trace);
+
+    if env.gc_reporting.trace {
+        eprintln!("[gc {}] {}: {} cells ({} bytes) -> {} cells ({} bytes)",
+                  env.heap.stats().collections,
+                  reason,
+                  collection.cells_before,
+                  collection.bytes_before,
+                  collection.cells_after,
+                  collection.bytes_after);
+    }
+}
+
+// Allocation collects first if the heap is full
+fn allocate(cell: HeapCell, env: Environment) -> HeapRef {
+    if env.heap.is_full() {
+        collect_garbage("heap full", Some(&cell), env);
+    }
+    env.heap.alloc(cell)
+}
+
+enum Pending {
+    PendingCell(HeapRef),
+    PendingScope(ScopeRef)
+}
+
+use self::Pending::*;
+
+/// Adds each value reachable from the roots to a snapshot once, as described
+/// in the snapshot module
+struct SnapshotBuilder<'a> {
+    heap: &'a Heap<HeapCell>,
+    snapshot: Snapshot,
+    cells: HashMap<HeapRef, usize>,
+    scopes: HashMap<*const RefCell<Scope>, usize>,
+    /// Nodes whose edges are yet to be added
+    pending: Vec<(usize, Pending)>
+}
+
+impl<'a> SnapshotBuilder<'a> {
+    fn new(heap: &'a Heap<HeapCell>) -> SnapshotBuilder<'a> {
+        SnapshotBuilder {heap: heap,
+                         snapshot: Snapshot::new(),
+                         cells: HashMap::new(),
+                         scopes: HashMap::new(),
+                         pending: Vec::new()}
+    }
+
+    fn cell_node(&mut self, r: HeapRef) -> usize {
+        if let Some(&id) = self.cells.get(&r) {
+            return id;
+        }
+
+        let cell = self.heap.get(r);
+        let (kind, name) = match *cell {
+            ArrayCell(_) => ("array", None),
+            ClosureCell(ref closure) => ("function", closure.name.clone())
+        };
+        let id = self.snapshot.add_node(Node {kind: kind,
+                                              name: name,
+                                              value: None,
+                                              cell: Some(r.index()),
+                                              size: cell.heap_size()});
+        self.cells.insert(r, id);
+        self.pending.push((id, PendingCell(r)));
+        id
+    }
+
+    fn scope_node(&mut self, scope: &ScopeRef) -> usize {
+        let key = &**scope as *const RefCell<Scope>;
+        if let Some(&id) = self.scopes.get(&key) {
+            return id;
+        }
+
+        let size = mem::size_of::<Scope>() + scope.borrow().names.iter()
+            .map(|name| name.len() + mem::size_of::<(String, Option<Value>)>())
+            .sum::<usize>();
+        let id = self.snapshot.add_node(Node {kind: "scope",
+                                              name: None,
+                                              value: None,
+                                              cell: None,
+                                              size: size});
+        self.scopes.insert(key, id);
+        self.pending.push((id, PendingScope(scope.clone())));
+        id
+    }
+
+    // Numbers, booleans and undefined are not nodes
+    fn value_node(&mut self, val: &Value) -> Option<usize> {
+        let (kind, value) = match *val {
+            ArrayValue(r) | FunctionValue(r) => return Some(self.cell_node(r)),
+            StringValue(ref s) => ("string", s.to_string()),
+            ErrorValue(ref error) =>
+                ("error", format!("{}: {}", error.name, error.message)),
+            _ => return None
+        };
+
+        Some(self.snapshot.add_node(Node {kind: kind,
+                                          name: None,
+                                          value: Some(value),
+                                          cell: None,
+                                          size: owned_bytes(val)}))
+    }
+
+    fn add_value_edge(&mut self, from: usize, val: &Value, label: String) {
+        if let Some(to) = self.value_node(val) {
+            self.snapshot.add_edge(from, to, label);
+        }
+    }
+
+    fn add_root(&mut self, label: String, val: &Value) {
+        if let Some(node) = self.value_node(val) {
+            self.snapshot.add_root(label, node);
+        }
+    }
+
+    fn finish(mut self) -> Snapshot {
+        let heap = self.heap;
+
+        while let Some((id, pending)) = self.pending.pop() {
+            match pending {
+                PendingCell(r) => match *heap.get(r) {
+                    ArrayCell(ref elems) =>
+                        for (i, elem) in elems.iter().enumerate() {
+                            self.add_value_edge(id, elem, i.to_string());
+                        },
+                    ClosureCell(ref closure) => {
+                        let scope = self.scope_node(&closure.scope);
+                        self.snapshot.add_edge(id, scope, String::from("scope"));
+                        self.add_value_edge(id, &closure.this,
+                                            String::from("this"));
+                    }
+                },
+                PendingScope(scope) => {
+                    let scope = scope.borrow();
+
+                    for (name, val) in scope.names.iter().zip(&scope.vars) {
+                        if let Some(ref val) = *val {
+                            self.add_value_edge(id, val, name.clone());
+                        }
+                    }
+
+                    if let Some(ref parent) = scope.parent {
+                        let parent = self.scope_node(parent);
+                        self.snapshot.add_edge(id, parent,
+                                               String::from("parent"));
+                    }
+                }
+            }
         }
+
+        self.snapshot
     }
 }
 
-type Environment<'a> = &'a mut HashMap<String, Value>;
+// The roots are those of a collection, labelled as the snapshot module
+// describes
+fn take_snapshot(env: &State) -> Snapshot {
+    let mut builder = SnapshotBuilder::new(&env.heap);
+
+    let global = builder.scope_node(&global_scope(&env.scope));
+    builder.snapshot.add_root(String::from("global"), global);
+
+    let callers = env.frames.iter().rev().map(|frame| (&frame.0, &frame.1));
+    for (n, (scope, this)) in iter::once((&env.scope, &env.this))
+                                  .chain(callers)
+                                  .enumerate() {
+        let scope = builder.scope_node(scope);
+        builder.snapshot.add_root(format!("frame {}", n), scope);
+        builder.add_root(format!("frame {} this", n), this);
+    }
+
+    for temp in &env.temps {
+        builder.add_root(String::from("temporary"), temp);
+    }
+
+    builder.finish()
+}
+
+fn write_snapshot(path: &str, env: &State) -> Result<(), String> {
+    File::create(path)
+        .and_then(|file| take_snapshot(env)
+                  .write_json(&mut BufWriter::new(file)))
+        .map_err(|err| format!("cannot write heap snapshot '{}': {}",
+                               path, err))
+}
+
+// Roots a value while another expression is evaluated, since that may
+// allocate and so collect
+fn eval_rooted(val: &Value, exp: &Expression, env: Environment) -> EvalResult<Value> {
+    env.temps.push(val.clone());
+    let result = eval_expression(exp, env);
+    env.temps.pop();
+    result
+}
+
+// Evaluates expressions in order, rooting each value until all are computed
+fn eval_rooted_list(exps: &[Expression], env: Environment) -> EvalResult<Vec<Value>> {
+    let mark = env.temps.len();
+
+    for exp in exps {
+        match eval_expression(exp, env) {
+            Ok(val) => env.temps.push(val),
+            Err(abrupt) => {
+                env.temps.truncate(mark);
+                return Err(abrupt);
+            }
+        }
+    }
+
+    Ok(env.temps.split_off(mark))
+}
 
 // struct Result<'a> {
 //     val: Value,
@@ -45,7 +549,11 @@ fn value_type_strings(val: &Value) -> String {
         NumValue(_) => "number",
         StringValue(_) => "string",
         BoolValue(_) => "boolean",
-        UndefinedValue => "undefined"
+        ErrorValue(_) => "object",
+        ArrayValue(_) => "object",
+        FunctionValue(_) => "function",
+        UndefinedValue => "undefined",
+        NaNValue => "number"
     };
 
     String::from(s)
@@ -55,9 +563,9 @@ fn unary_error(
     symbol: &str,
     exp: &str,
     act: Value
-    ) -> JsishError {
+    ) -> Abrupt {
 
-    JsishError::from(format!("unary operator '{}' requires {}, found {}",
+    type_error(format!("unary operator '{}' requires {}, found {}",
                              symbol,
                              exp,
                              value_type_strings(&act)))
@@ -65,17 +573,70 @@ fn unary_error(
 
 fn eval_unary_expression(
     opr: UnaryOperator,
-    opnd: Expression,
+    opnd: &Expression,
     env: Environment
-    ) -> JsishResult<Value> {
+    ) -> EvalResult<Value> {
 
     let val = eval_expression(opnd, env)?;
+    apply_unary_operator(opr, val, env)
+}
+
+fn apply_unary_operator(
+    opr: UnaryOperator,
+    val: Value,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    if env.coercion == Coercion::Loose {
+        return loose_unary_operator(opr, val, &env.heap);
+    }
+
     match (opr, val) {
         (UopNot, BoolValue(b)) => Ok(BoolValue(!b)),
         (UopNot, val) => Err(unary_error("!", "boolean", val)),
-        (UopMinus, NumValue(n)) => Ok(NumValue(-n)),
+        (UopMinus, NumValue(n)) => negate(n),
         (UopMinus, val) => Err(unary_error("-", "number", val)),
-        (UopTypeof, v) => Ok(StringValue(value_type_strings(&v))),
+        (UopBitNot, NumValue(n)) => Ok(NumValue(!(n as i32) as i64)),
+        (UopBitNot, val) => Err(unary_error("~", "number", val)),
+        (UopTypeof, v) => Ok(string_value(value_type_strings(&v), &env.heap)),
+    }
+}
+
+// The negation of a number, which is out of range for the least one
+fn negate(n: i64) -> EvalResult<Value> {
+    n.checked_neg()
+        .map(NumValue)
+        .ok_or_else(|| range_error(String::from("result of unary operator \
+                                                '-' is out of range")))
+}
+
+// The result of an arithmetic operator on two numbers, or None if it
+// divides by zero or is out of range
+pub fn checked_arithmetic(opr: BinaryOperator, l: i64, r: i64) -> Option<i64> {
+    match opr {
+        BopPlus => l.checked_add(r),
+        BopMinus => l.checked_sub(r),
+        BopTimes => l.checked_mul(r),
+        BopDivide => l.checked_div(r).map(|_| special_divide(l, r)),
+        // Only the least number over -1 overflows, though the remainder fits
+        BopMod if r == -1 => Some(0),
+        BopMod => l.checked_rem(r),
+        BopPow if r >= 0 => checked_power(l, r),
+        BopPow => None,
+        _ => panic!("'{}' is not an arithmetic operator", opr)
+    }
+}
+
+// Arithmetic under either coercion, where a failure is a range error
+fn arithmetic(opr: BinaryOperator, l: i64, r: i64) -> EvalResult<Value> {
+    match checked_arithmetic(opr, l, r) {
+        Some(n) => Ok(NumValue(n)),
+        None if opr == BopPow => integer_pow(l, r),
+        None if r == 0 && (opr == BopDivide || opr == BopMod) =>
+            Err(range_error(String::from("division by zero"))),
+        None =>
+            Err(range_error(format!("result of operator '{}' is out of range",
+                                    opr)))
     }
 }
 
@@ -92,16 +653,72 @@ fn special_divide(num: i64, denom: i64) -> i64 {
     }
 }
 
+// Bitwise operators work on the low 32 bits of their operands, as in
+// JavaScript; only the low five bits of a shift count are used.
+pub fn bitwise_op(opr: &BinaryOperator, l: i64, r: i64) -> i64 {
+    let (l32, shift) = (l as i32, (r as u32) & 0x1f);
+
+    match *opr {
+        BopBitAnd => (l32 & r as i32) as i64,
+        BopBitOr => (l32 | r as i32) as i64,
+        BopBitXor => (l32 ^ r as i32) as i64,
+        BopLshift => l32.wrapping_shl(shift) as i64,
+        BopRshift => (l32 >> shift) as i64,
+        BopUrshift => ((l as u32) >> shift) as i64,
+        _ => panic!("'{}' is not a bitwise operator", opr)
+    }
+}
+
+// A non-negative power, or None if it is out of range
+pub fn checked_power(base: i64, exp: i64) -> Option<i64> {
+    let pow = if exp > u32::MAX as i64 { None }
+              else { base.checked_pow(exp as u32) };
+
+    match (pow, base) {
+        (Some(n), _) => Some(n),
+        (None, 0) | (None, 1) => Some(base),
+        (None, -1) => Some(if exp % 2 == 0 { 1 } else { -1 }),
+        (None, _) => None
+    }
+}
+
+fn integer_pow(base: i64, exp: i64) -> EvalResult<Value> {
+    if exp < 0 {
+        return Err(range_error(format!("operator '**' requires a \
+                                       non-negative exponent, found {}",
+                                       exp)));
+    }
+
+    match checked_power(base, exp) {
+        Some(n) => Ok(NumValue(n)),
+        None =>
+            Err(range_error(String::from("result of operator '**' is out of \
+                                         range")))
+    }
+}
+
+// Strings are ordered by UTF-16 code unit, as in JavaScript, rather than by
+// code point: characters outside the Basic Multilingual Plane sort before
+// U+E000..U+FFFF.
+pub fn compare_strings(l: &str, r: &str) -> Ordering {
+    l.encode_utf16().cmp(r.encode_utf16())
+}
+
 fn handle_short_circuit(
     sc_value: bool,
     symbol: &str,
-    lft: Expression,
-    rht: Expression,
+    lft: &Expression,
+    rht: &Expression,
     env: Environment
-    ) -> JsishResult<Value> {
+    ) -> EvalResult<Value> {
 
     let lft_val = eval_expression(lft, env)?;
 
+    if env.coercion == Coercion::Loose {
+        return if to_boolean(&lft_val) == sc_value { Ok(lft_val) }
+               else { eval_expression(rht, env) };
+    }
+
     if let BoolValue(b) = lft_val {
         if b == sc_value {
             Ok(BoolValue(sc_value))
@@ -112,27 +729,27 @@ fn handle_short_circuit(
                 Ok(BoolValue(b))
             }
             else {
-                Err(JsishError::from(format!("operator '{}' requires \
-                                             boolean * boolean, found {} * {}",
-                                             symbol,
-                                             value_type_strings(&lft_val),
-                                             value_type_strings(&rht_val))))
+                Err(type_error(format!("operator '{}' requires \
+                                       boolean * boolean, found {} * {}",
+                                       symbol,
+                                       value_type_strings(&lft_val),
+                                       value_type_strings(&rht_val))))
             }
         }
     }
     else {
-        Err(JsishError::from(format!("operator '{}' requires boolean, found {}",
-                                     symbol,
-                                     value_type_strings(&lft_val))))
+        Err(type_error(format!("operator '{}' requires boolean, found {}",
+                               symbol,
+                               value_type_strings(&lft_val))))
     }
 }
 
 fn eval_binary_expression(
     opr: BinaryOperator,
-    lft: Expression,
-    rht: Expression,
+    lft: &Expression,
+    rht: &Expression,
     env: Environment
-    ) -> JsishResult<Value> {
+    ) -> EvalResult<Value> {
 
     if opr == BopAnd {
         return handle_short_circuit(false, "&&", lft, rht, env);
@@ -143,118 +760,812 @@ fn eval_binary_expression(
     }
 
     let lft_val = eval_expression(lft, env)?;
-    let rht_val = eval_expression(rht, env)?;
+    let rht_val = eval_rooted(&lft_val, rht, env)?;
+
+    apply_binary_operator(opr, lft_val, rht_val, env)
+}
+
+fn apply_binary_operator(
+    opr: BinaryOperator,
+    lft_val: Value,
+    rht_val: Value,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    if env.coercion == Coercion::Loose {
+        return loose_binary_operator(opr, lft_val, rht_val, &env.heap);
+    }
 
     match (opr, lft_val, rht_val) {
-        (BopPlus, NumValue(l), NumValue(r)) => Ok(NumValue(l + r)),
-        (BopPlus, StringValue(l), StringValue(r)) => Ok(StringValue(l + &r)),
-        (BopMinus, NumValue(l), NumValue(r)) => Ok(NumValue(l - r)),
-        (BopTimes, NumValue(l), NumValue(r)) => Ok(NumValue(l * r)),
-        (BopDivide, NumValue(l), NumValue(r)) =>
-            Ok(NumValue(special_divide(l, r))),
-        (BopMod, NumValue(l), NumValue(r)) => Ok(NumValue(l % r)),
+        (opr @ BopPlus, NumValue(l), NumValue(r)) |
+        (opr @ BopMinus, NumValue(l), NumValue(r)) |
+        (opr @ BopTimes, NumValue(l), NumValue(r)) |
+        (opr @ BopDivide, NumValue(l), NumValue(r)) |
+        (opr @ BopMod, NumValue(l), NumValue(r)) |
+        (opr @ BopPow, NumValue(l), NumValue(r)) => arithmetic(opr, l, r),
+        (BopPlus, StringValue(l), StringValue(r)) =>
+            Ok(string_value(format!("{}{}", l, r), &env.heap)),
+        (opr @ BopBitAnd, NumValue(l), NumValue(r)) |
+        (opr @ BopBitOr, NumValue(l), NumValue(r)) |
+        (opr @ BopBitXor, NumValue(l), NumValue(r)) |
+        (opr @ BopLshift, NumValue(l), NumValue(r)) |
+        (opr @ BopRshift, NumValue(l), NumValue(r)) |
+        (opr @ BopUrshift, NumValue(l), NumValue(r)) =>
+            Ok(NumValue(bitwise_op(&opr, l, r))),
+        // '===' never coerces its operands.  Neither does '==' under the
+        // strict semantics, so the two agree: both compare type and contents
+        // and never raise a type error.
         (BopEq, l, r) => Ok(BoolValue(l == r)),
         (BopNe, l, r) => Ok(BoolValue(l != r)),
+        (BopStrictEq, l, r) => Ok(BoolValue(l == r)),
+        (BopStrictNe, l, r) => Ok(BoolValue(l != r)),
         (BopLt, NumValue(l), NumValue(r)) => Ok(BoolValue(l < r)),
         (BopGt, NumValue(l), NumValue(r)) => Ok(BoolValue(l > r)),
         (BopGe, NumValue(l), NumValue(r)) => Ok(BoolValue(l >= r)),
         (BopLe, NumValue(l), NumValue(r)) => Ok(BoolValue(l <= r)),
+        (BopLt, StringValue(l), StringValue(r)) =>
+            Ok(BoolValue(compare_strings(&l, &r) == Ordering::Less)),
+        (BopGt, StringValue(l), StringValue(r)) =>
+            Ok(BoolValue(compare_strings(&l, &r) == Ordering::Greater)),
+        (BopGe, StringValue(l), StringValue(r)) =>
+            Ok(BoolValue(compare_strings(&l, &r) != Ordering::Less)),
+        (BopLe, StringValue(l), StringValue(r)) =>
+            Ok(BoolValue(compare_strings(&l, &r) != Ordering::Greater)),
         (BopComma, _, r) => Ok(r),
         (BopPlus, l, r) =>
-            Err(JsishError::from(format!("operator '+' requires number * \
-                                         number or string * string, \
-                                         found {} * {}",
-                                         value_type_strings(&l),
-                                         value_type_strings(&r)))),
+            Err(type_error(format!("operator '+' requires number * \
+                                   number or string * string, \
+                                   found {} * {}",
+                                   value_type_strings(&l),
+                                   value_type_strings(&r)))),
         (opr, l, r) =>
-            Err(JsishError::from(format!("operator '{}' requires number * \
-                                         number, found {} * {}",
-                                         opr,
-                                         value_type_strings(&l),
-                                         value_type_strings(&r)))),
+            Err(type_error(format!("operator '{}' requires number * \
+                                   number, found {} * {}",
+                                   opr,
+                                   value_type_strings(&l),
+                                   value_type_strings(&r)))),
+    }
+}
+
+// Loose coercion follows the abstract operations of ECMAScript, restricted to
+// jsish's integer numbers: a string converts to a number only if it is a
+// decimal integer, or an unsigned one with the 0x, 0o or 0b prefix of a
+// literal (ignoring surrounding whitespace), and anything that would be
+// fractional or infinite in JavaScript is NaN or a RangeError instead.
+
+fn to_boolean(val: &Value) -> bool {
+    match *val {
+        NumValue(n) => n != 0,
+        StringValue(ref s) => !s.is_empty(),
+        BoolValue(b) => b,
+        ErrorValue(_) | ArrayValue(_) | FunctionValue(_) => true,
+        UndefinedValue | NaNValue => false
+    }
+}
+
+// None stands for NaN
+fn to_number(val: &Value, heap: &Heap<HeapCell>) -> Option<i64> {
+    match *val {
+        NumValue(n) => Some(n),
+        BoolValue(b) => Some(if b { 1 } else { 0 }),
+        StringValue(ref s) => string_to_number(s.trim()),
+        ArrayValue(_) => to_number(&to_primitive(val.clone(), heap), heap),
+        ErrorValue(_) | FunctionValue(_) | UndefinedValue | NaNValue => None
+    }
+}
+
+fn string_to_number(s: &str) -> Option<i64> {
+    let radix = match s.get(..2) {
+        _ if s.is_empty() => return Some(0),
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => return s.parse().ok()
+    };
+
+    // from_str_radix would also take a sign, which JavaScript does not
+    let digits = &s[2..];
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+fn number_value(n: Option<i64>) -> Value {
+    n.map_or(NaNValue, NumValue)
+}
+
+fn to_int32(val: &Value, heap: &Heap<HeapCell>) -> i64 {
+    to_number(val, heap).unwrap_or(0)
+}
+
+// Objects convert to their string form
+fn to_primitive(val: Value, heap: &Heap<HeapCell>) -> Value {
+    match val {
+        ErrorValue(_) | ArrayValue(_) | FunctionValue(_) =>
+            string_value(Shown(&val, heap).to_string(), heap),
+        val => val
+    }
+}
+
+fn coerce_guard(val: Value, env: Environment) -> Value {
+    match env.coercion {
+        Coercion::Strict => val,
+        Coercion::Loose => BoolValue(to_boolean(&val))
+    }
+}
+
+fn strict_equals(lft: &Value, rht: &Value) -> bool {
+    *lft != NaNValue && lft == rht
+}
+
+fn loose_equals(lft: Value, rht: Value, heap: &Heap<HeapCell>) -> bool {
+    match (lft, rht) {
+        (l @ NumValue(_), r @ StringValue(_)) |
+        (l @ StringValue(_), r @ NumValue(_)) |
+        (l @ NaNValue, r @ StringValue(_)) |
+        (l @ StringValue(_), r @ NaNValue) |
+        (l @ BoolValue(_), r) |
+        (l, r @ BoolValue(_)) =>
+            if value_type_strings(&l) == value_type_strings(&r) {
+                strict_equals(&l, &r)
+            }
+            else {
+                to_number(&l, heap).is_some() &&
+                    to_number(&l, heap) == to_number(&r, heap)
+            },
+        (l @ ErrorValue(_), r @ StringValue(_)) |
+        (l @ ErrorValue(_), r @ NumValue(_)) |
+        (l @ ArrayValue(_), r @ StringValue(_)) |
+        (l @ ArrayValue(_), r @ NumValue(_)) =>
+            loose_equals(to_primitive(l, heap), r, heap),
+        (l @ StringValue(_), r @ ErrorValue(_)) |
+        (l @ NumValue(_), r @ ErrorValue(_)) |
+        (l @ StringValue(_), r @ ArrayValue(_)) |
+        (l @ NumValue(_), r @ ArrayValue(_)) =>
+            loose_equals(l, to_primitive(r, heap), heap),
+        (l, r) => strict_equals(&l, &r)
+    }
+}
+
+fn loose_arithmetic(
+    opr: BinaryOperator,
+    l: Option<i64>,
+    r: Option<i64>
+    ) -> EvalResult<Value> {
+
+    match (opr, l, r) {
+        (BopDivide, Some(_), Some(0)) | (BopMod, Some(_), Some(0)) =>
+            Err(range_error(String::from("division by zero"))),
+        (BopPow, _, Some(0)) => Ok(NumValue(1)),
+        (_, None, _) | (_, _, None) => Ok(NaNValue),
+        (opr, Some(l), Some(r)) => arithmetic(opr, l, r)
+    }
+}
+
+fn loose_relational(
+    opr: BinaryOperator,
+    lft: Value,
+    rht: Value,
+    heap: &Heap<HeapCell>
+    ) -> bool {
+
+    let ord = match (to_primitive(lft, heap), to_primitive(rht, heap)) {
+        (StringValue(l), StringValue(r)) => Some(compare_strings(&l, &r)),
+        (l, r) => match (to_number(&l, heap), to_number(&r, heap)) {
+            (Some(l), Some(r)) => Some(l.cmp(&r)),
+            _ => None
+        }
+    };
+
+    match (opr, ord) {
+        (_, None) => false,
+        (BopLt, Some(ord)) => ord == Ordering::Less,
+        (BopGt, Some(ord)) => ord == Ordering::Greater,
+        (BopLe, Some(ord)) => ord != Ordering::Greater,
+        (BopGe, Some(ord)) => ord != Ordering::Less,
+        (opr, _) => panic!("'{}' is not a relational operator", opr)
+    }
+}
+
+fn loose_binary_operator(
+    opr: BinaryOperator,
+    lft: Value,
+    rht: Value,
+    heap: &Heap<HeapCell>
+    ) -> EvalResult<Value> {
+
+    match opr {
+        BopPlus => match (to_primitive(lft, heap), to_primitive(rht, heap)) {
+            (l @ StringValue(_), r) | (l, r @ StringValue(_)) =>
+                Ok(string_value(format!("{}{}", Shown(&l, heap), Shown(&r, heap)),
+                                heap)),
+            (l, r) => loose_arithmetic(BopPlus, to_number(&l, heap),
+                                       to_number(&r, heap))
+        },
+        BopMinus | BopTimes | BopDivide | BopMod | BopPow =>
+            loose_arithmetic(opr, to_number(&lft, heap), to_number(&rht, heap)),
+        BopBitAnd | BopBitOr | BopBitXor | BopLshift | BopRshift |
+        BopUrshift =>
+            Ok(NumValue(bitwise_op(&opr, to_int32(&lft, heap),
+                                   to_int32(&rht, heap)))),
+        BopEq => Ok(BoolValue(loose_equals(lft, rht, heap))),
+        BopNe => Ok(BoolValue(!loose_equals(lft, rht, heap))),
+        BopStrictEq => Ok(BoolValue(strict_equals(&lft, &rht))),
+        BopStrictNe => Ok(BoolValue(!strict_equals(&lft, &rht))),
+        BopLt | BopGt | BopLe | BopGe =>
+            Ok(BoolValue(loose_relational(opr, lft, rht, heap))),
+        BopComma => Ok(rht),
+        BopAnd | BopOr => panic!("'{}' must short-circuit", opr)
+    }
+}
+
+fn loose_unary_operator(
+    opr: UnaryOperator,
+    val: Value,
+    heap: &Heap<HeapCell>
+    ) -> EvalResult<Value> {
+
+    match opr {
+        UopNot => Ok(BoolValue(!to_boolean(&val))),
+        UopMinus => match to_number(&val, heap) {
+            Some(n) => negate(n),
+            None => Ok(NaNValue)
+        },
+        UopBitNot => Ok(NumValue(!(to_int32(&val, heap) as i32) as i64)),
+        UopTypeof => Ok(string_value(value_type_strings(&val), heap))
     }
 }
 
 fn eval_conditional_expression(
-    guard: Expression,
-    then_exp: Expression,
-    else_exp: Expression,
+    guard: &Expression,
+    then_exp: &Expression,
+    else_exp: &Expression,
     env: Environment
-    ) -> JsishResult<Value> {
+    ) -> EvalResult<Value> {
 
-    match eval_expression(guard, env)? {
+    match coerce_guard(eval_expression(guard, env)?, env) {
         BoolValue(true) => eval_expression(then_exp, env),
         BoolValue(false) => eval_expression(else_exp, env),
         g_val =>
-            Err(JsishError::from(format!("boolean guard required for 'cond' \
-                                         expression, found {}",
-                                         value_type_strings(&g_val))))
+            Err(type_error(format!("boolean guard required for 'cond' \
+                                   expression, found {}",
+                                   value_type_strings(&g_val))))
     }
 }
 
 fn eval_assignment_expression(
-    lft: Expression,
-    rht: Expression,
+    lft: &Expression,
+    rht: &Expression,
     env: Environment
-    ) -> JsishResult<Value> {
+    ) -> EvalResult<Value> {
 
     let rht_value = eval_expression(rht, env)?;
 
-    match lft {
-        ExpId(id) => {env.insert(id, rht_value.clone()); Ok(rht_value)}
-        _ => Err(JsishError::from("unexpected target of assignment\n"))
+    match lft.kind {
+        ExpId(ref id) => {
+            assign_variable(id, rht_value.clone(), env)?;
+            Ok(rht_value)
+        }
+        _ => Err(type_error(String::from("unexpected target of assignment")))
+    }
+}
+
+fn global_scope(scope: &ScopeRef) -> ScopeRef {
+    let mut current = scope.clone();
+
+    loop {
+        let parent = current.borrow().parent.clone();
+        match parent {
+            Some(p) => current = p,
+            None => return current
+        }
+    }
+}
+
+// The scope the given number of scopes out from this one
+fn enclosing_scope(scope: &ScopeRef, depth: usize) -> ScopeRef {
+    let mut current = scope.clone();
+
+    for _ in 0..depth {
+        let parent = current.borrow().parent.clone()
+            .expect("variable resolved beyond the global scope");
+        current = parent;
+    }
+    current
+}
+
+fn resolved_slot(id: &Ident) -> Slot {
+    id.slot.unwrap_or_else(|| panic!("variable '{}' was not resolved", id))
+}
+
+fn uninitialized_error(name: &str) -> Abrupt {
+    reference_error(format!("cannot access '{}' before initialization", name))
+}
+
+// An empty slot is an implicit global not yet assigned, or a variable whose
+// declaration has not yet run
+fn unbound_error(name: &str, slot: Slot) -> Abrupt {
+    if slot.implicit {
+        reference_error(format!("variable '{}' not found", name))
+    }
+    else {
+        uninitialized_error(name)
+    }
+}
+
+fn load_slot(slot: Slot, name: &str, env: Environment) -> EvalResult<Value> {
+    let scope = enclosing_scope(&env.scope, slot.depth);
+    let val = scope.borrow().vars.get(slot.index).cloned();
+
+    match val {
+        Some(Some(val)) => Ok(val),
+        _ => Err(unbound_error(name, slot))
+    }
+}
+
+fn lookup_variable(id: &Ident, env: Environment) -> EvalResult<Value> {
+    load_slot(resolved_slot(id), &id.name, env)
+}
+
+fn declare_variable(id: &Ident, val: Value, env: Environment) {
+    env.scope.borrow_mut().set(resolved_slot(id).index, &id.name, val);
+}
+
+// Binds a var to undefined unless already bound, e.g., as a parameter
+fn hoist_var(index: usize, name: &str, env: Environment) {
+    let mut scope = env.scope.borrow_mut();
+    if let None | Some(&None) = scope.vars.get(index) {
+        scope.set(index, name, UndefinedValue);
+    }
+}
+
+// Binds the var names of a program or function body and its function
+// declarations to their closures, before any of its statements run.  Its let
+// and const variables stay unbound until their declarations run.
+fn hoist_declarations(ses: &[SourceElement], env: Environment) {
+    for se in ses {
+        match *se {
+            VarDecl(ref decls, _) => for decl in decls {
+                hoist_var(resolved_slot(decl.ident()).index, decl.id(), env);
+            },
+            FunctionDecl(FunctionDeclData {ref name, ref params, ref body}, _) => {
+                let scope = env.scope.clone();
+                let closure = function_closure(Some(name.name.clone()), params,
+                                               body, scope, env);
+                declare_variable(name, closure, env);
+            }
+            _ => ()
+        }
+    }
+}
+
+// Only an implicit global may be assigned while its slot is empty, which
+// creates it
+fn store_slot(slot: Slot, name: &str, val: Value, env: Environment) -> EvalResult<()> {
+    let scope = enclosing_scope(&env.scope, slot.depth);
+    let mut scope = scope.borrow_mut();
+
+    match scope.vars.get_mut(slot.index) {
+        Some(&mut Some(ref mut var)) => *var = val,
+        _ if slot.implicit => scope.set(slot.index, name, val),
+        _ => return Err(uninitialized_error(name))
+    }
+    Ok(())
+}
+
+fn assign_variable(id: &Ident, val: Value, env: Environment) -> EvalResult<()> {
+    store_slot(resolved_slot(id), &id.name, val, env)
+}
+
+fn eval_compound_assignment_expression(
+    opr: BinaryOperator,
+    lft: &Expression,
+    rht: &Expression,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    match lft.kind {
+        ExpId(ref id) => {
+            let lft_value = lookup_variable(id, env)?;
+            let rht_value = eval_rooted(&lft_value, rht, env)?;
+            let value = apply_binary_operator(opr, lft_value, rht_value,
+                                              env)?;
+            assign_variable(id, value.clone(), env)?;
+            Ok(value)
+        }
+        _ => Err(type_error(String::from("unexpected target of assignment")))
+    }
+}
+
+fn eval_update_expression(
+    opr: UpdateOperator,
+    prefix: bool,
+    opnd: &Expression,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    match opnd.kind {
+        ExpId(ref id) =>
+            update_variable(opr, prefix, resolved_slot(id), &id.name, env),
+        _ => Err(type_error(String::from("unexpected target of assignment")))
+    }
+}
+
+fn update_variable(
+    opr: UpdateOperator,
+    prefix: bool,
+    slot: Slot,
+    name: &str,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    let bop = match opr {
+        UpdIncrement => BopPlus,
+        UpdDecrement => BopMinus
+    };
+
+    let mut old_value = load_slot(slot, name, env)?;
+
+    // Loose '++' and '--' convert to a number rather than concatenate
+    if env.coercion == Coercion::Loose {
+        old_value = number_value(to_number(&old_value, &env.heap));
+    }
+
+    let new_value = apply_binary_operator(bop, old_value.clone(), NumValue(1),
+                                          env)?;
+    store_slot(slot, name, new_value.clone(), env)?;
+    Ok(if prefix { new_value } else { old_value })
+}
+
+fn eval_dot_expression(
+    obj: &Expression,
+    prop: &str,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    let obj_val = eval_expression(obj, env)?;
+    dot_property(obj_val, prop, env)
+}
+
+fn dot_property(obj_val: Value, prop: &str, env: Environment) -> EvalResult<Value> {
+    match (obj_val, prop) {
+        (ErrorValue(error), "name") =>
+            Ok(string_value(error.name.clone(), &env.heap)),
+        (ErrorValue(error), "message") =>
+            Ok(string_value(error.message.clone(), &env.heap)),
+        (ErrorValue(_), _) => Ok(UndefinedValue),
+        (ArrayValue(r), "length") =>
+            Ok(NumValue(array_elements(&env.heap, r).len() as i64)),
+        (ArrayValue(_), _) => Ok(UndefinedValue),
+        (val, _) =>
+            Err(type_error(format!("attempt to access property '{}' of '{}' \
+                                   value",
+                                   prop,
+                                   value_type_strings(&val))))
     }
 }
 
-fn eval_expression(exp: Expression, env: Environment) -> JsishResult<Value> {
-    match exp {
-        ExpId(id) =>
-            match env.get(&id) {
-                None => Err(JsishError::from(
-                        format!("variable '{}' not found", id))),
-                Some(v) => Ok(v.clone())
+fn eval_template_expression(
+    chunks: &[TemplateChunk],
+    exps: &[Expression],
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    // The chunks are made as string literals, and kept with the values
+    // between them until the result is made, as the virtual machine does
+    let mark = env.temps.len();
+    let mut chunks = chunks.iter();
+    if let Some(chunk) = chunks.next() {
+        env.temps.push(string_value(chunk.cooked.clone(), &env.heap));
+    }
+
+    for (exp, chunk) in exps.iter().zip(chunks) {
+        match eval_expression(exp, env) {
+            Ok(val) => env.temps.push(val),
+            Err(abrupt) => {
+                env.temps.truncate(mark);
+                return Err(abrupt);
             }
+        }
+        env.temps.push(string_value(chunk.cooked.clone(), &env.heap));
+    }
+
+    let result = env.temps.split_off(mark).iter()
+        .map(|val| Shown(val, &env.heap).to_string())
+        .collect::<String>();
+    Ok(string_value(result, &env.heap))
+}
+
+fn eval_index_expression(
+    obj: &Expression,
+    index: &Expression,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    let obj_val = eval_expression(obj, env)?;
+    let index_val = eval_rooted(&obj_val, index, env)?;
+    index_value(obj_val, index_val, env)
+}
+
+fn index_value(obj_val: Value, index_val: Value, env: Environment) -> EvalResult<Value> {
+    match (obj_val, index_val) {
+        (ArrayValue(r), NumValue(i)) => {
+            let elems = array_elements(&env.heap, r);
+            Ok(if i < 0 { None } else { elems.get(i as usize) }
+               .cloned()
+               .unwrap_or(UndefinedValue))
+        }
+        (ArrayValue(_), index_val) =>
+            Err(type_error(format!("array index requires number, found {}",
+                                   value_type_strings(&index_val)))),
+        (obj_val, _) =>
+            Err(type_error(format!("attempt to index '{}' value",
+                                   value_type_strings(&obj_val))))
+    }
+}
+
+fn eval_arrow_expression(arrow: &ExpArrowData, env: Environment) -> Value {
+    let code = Code::Tree {params: arrow.params.clone(),
+                           rest: arrow.rest.clone(),
+                           body: arrow.body.clone()};
+    let closure = Closure {name: None,
+                           code: code,
+                           scope: env.scope.clone(),
+                           this: env.this.clone()};
+    FunctionValue(allocate(ClosureCell(Rc::new(closure)), env))
+}
+
+fn function_closure(
+    name: Option<String>,
+    params: &[Ident],
+    body: &Rc<Vec<SourceElement>>,
+    scope: ScopeRef,
+    env: Environment
+    ) -> Value {
+
+    let params = params.iter()
+        .map(|name| Param {name: name.clone(), default: None})
+        .collect();
+
+    let code = Code::Tree {params: Rc::new(params),
+                           rest: None,
+                           body: FunctionBody::BodyBlock(body.clone())};
+    let closure = Closure {name: name,
+                           code: code,
+                           scope: scope,
+                           this: UndefinedValue};
+    FunctionValue(allocate(ClosureCell(Rc::new(closure)), env))
+}
+
+// A named function expression sees its own name in a scope of its own,
+// between the closure's body and the scope it was created in
+fn eval_function_expression(func: &ExpFunctionData, env: Environment) -> Value {
+    match func.name {
+        None => {
+            let scope = env.scope.clone();
+            function_closure(None, &func.params, &func.body, scope, env)
+        }
+        Some(ref name) => {
+            let scope = new_scope(Some(env.scope.clone()));
+            let closure = function_closure(Some(name.name.clone()), &func.params,
+                                           &func.body, scope.clone(), env);
+            scope.borrow_mut().set(resolved_slot(name).index, &name.name,
+                                   closure.clone());
+            closure
+        }
+    }
+}
+
+// Binds the arguments in the new scope of a call.  Defaults are evaluated in
+// that scope, so they can refer to earlier parameters.  Arguments not yet
+// bound stay rooted, on the temporaries in reverse order, since evaluating a
+// default may allocate.
+fn bind_parameters(
+    params: &[Param],
+    rest: &Option<Ident>,
+    args: Vec<Value>,
+    env: Environment
+    ) -> EvalResult<()> {
+
+    let mark = env.temps.len();
+    env.temps.extend(args.into_iter().rev());
+
+    for param in params {
+        let arg = if env.temps.len() > mark { env.temps.pop() } else { None };
+        let val = match (arg, &param.default) {
+            (None, &Some(ref default)) |
+            (Some(UndefinedValue), &Some(ref default)) =>
+                match eval_expression(default, env) {
+                    Ok(val) => val,
+                    Err(abrupt) => {
+                        env.temps.truncate(mark);
+                        return Err(abrupt);
+                    }
+                },
+            (arg, _) => arg.unwrap_or(UndefinedValue)
+        };
+        declare_variable(&param.name, val, env);
+    }
+
+    let rest_vals: Vec<Value> = env.temps.drain(mark..).rev().collect();
+    if let Some(ref rest) = *rest {
+        let rest_val = ArrayValue(allocate(ArrayCell(rest_vals), env));
+        declare_variable(rest, rest_val, env);
+    }
+
+    Ok(())
+}
+
+fn eval_function_body(body: &FunctionBody, env: Environment) -> EvalResult<Value> {
+    match *body {
+        FunctionBody::BodyExp(ref exp) => eval_expression(exp, env),
+        FunctionBody::BodyBlock(ref ses) => {
+            hoist_declarations(ses, env);
+            for se in ses.iter() {
+                match eval_source_element(se, env) {
+                    Err(Return(val)) => return Ok(val),
+                    result => {result?;}
+                }
+            }
+            Ok(UndefinedValue)
+        }
+    }
+}
+
+fn call_function(
+    closure: &Closure,
+    args: Vec<Value>,
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    let (params, rest, body) = match closure.code {
+        Code::Tree { ref params, ref rest, ref body } => (params, rest, body),
+        Code::Compiled(_) =>
+            panic!("compiled function called by the tree-walking interpreter")
+    };
+
+    enter_call(closure, env)?;
+    let result = bind_parameters(params, rest, args, env)
+        .and_then(|_| eval_function_body(body, env));
+    leave_call(env);
+
+    result
+}
+
+// Calls nested deeper than this raise an error the script can catch, before
+// they can overflow the interpreter's own stack
+const MAX_CALL_DEPTH: usize = 1000;
+
+// Runs the closure's code in a new scope, with the caller's scope and 'this'
+// kept as a frame until the call returns
+fn enter_call(closure: &Closure, env: Environment) -> EvalResult<()> {
+    if env.frames.len() >= MAX_CALL_DEPTH {
+        return Err(range_error(String::from("maximum call stack size exceeded")));
+    }
+
+    let caller_scope = mem::replace(&mut env.scope,
+                                    new_scope(Some(closure.scope.clone())));
+    let caller_this = mem::replace(&mut env.this, closure.this.clone());
+    env.frames.push((caller_scope, caller_this));
+    Ok(())
+}
+
+fn leave_call(env: Environment) {
+    let (caller_scope, caller_this) = env.frames.pop()
+        .expect("call stack underflow");
+    env.scope = caller_scope;
+    env.this = caller_this;
+}
+
+fn eval_call_expression(
+    func: &Expression,
+    args: &[Expression],
+    env: Environment
+    ) -> EvalResult<Value> {
+
+    let func_val = eval_expression(func, env)?;
+
+    env.temps.push(func_val.clone());
+    let arg_vals = eval_rooted_list(args, env);
+    env.temps.pop();
+    let arg_vals = arg_vals?;
+
+    match func_val {
+        FunctionValue(r) => {
+            let closure = heap_closure(&env.heap, r);
+            call_function(&closure, arg_vals, env)
+        }
+        val => Err(invoke_error(&val))
+    }
+}
+
+fn invoke_error(val: &Value) -> Abrupt {
+    type_error(format!("attempt to invoke '{}' value as a function",
+                       value_type_strings(val)))
+}
+
+fn eval_expression(exp: &Expression, env: Environment) -> EvalResult<Value> {
+    match exp.kind {
+        ExpId(ref id) => lookup_variable(id, env),
         ExpNum(n) => Ok(NumValue(n)),
-        ExpString(s) => Ok(StringValue(s)),
+        ExpString(ref s) => Ok(string_value(s.clone(), &env.heap)),
         ExpTrue => Ok(BoolValue(true)),
         ExpFalse => Ok(BoolValue(false)),
         ExpUndefined => Ok(UndefinedValue),
-        ExpUnary(ExpUnaryData {opr, opnd})  =>
-            eval_unary_expression(opr, *opnd, env),
-        ExpBinary(ExpBinaryData {opr, lft, rht}) =>
-            eval_binary_expression(opr, *lft, *rht, env),
-        ExpCond(ExpCondData {guard, then_exp, else_exp}) =>
-            eval_conditional_expression(*guard, *then_exp, *else_exp, env),
-        ExpAssign(ExpAssignData {lft, rht}) =>
-            eval_assignment_expression(*lft, *rht, env),
+        ExpUnary(ExpUnaryData {opr, ref opnd})  =>
+            eval_unary_expression(opr, opnd, env),
+        ExpBinary(ExpBinaryData {opr, ref lft, ref rht}) =>
+            eval_binary_expression(opr, lft, rht, env),
+        ExpCond(ExpCondData {ref guard, ref then_exp, ref else_exp}) =>
+            eval_conditional_expression(guard, then_exp, else_exp, env),
+        ExpAssign(ExpAssignData {ref lft, ref rht}) =>
+            eval_assignment_expression(lft, rht, env),
+        ExpCompoundAssign(ExpCompoundAssignData {opr, ref lft, ref rht}) =>
+            eval_compound_assignment_expression(opr, lft, rht, env),
+        ExpUpdate(ExpUpdateData {opr, prefix, ref opnd}) =>
+            eval_update_expression(opr, prefix, opnd, env),
+        ExpDot(ExpDotData {ref obj, ref prop}) =>
+            eval_dot_expression(obj, prop, env),
+        ExpTemplate(ExpTemplateData {ref chunks, ref exps}) =>
+            eval_template_expression(chunks, exps, env),
+        ExpThis => Ok(env.this.clone()),
+        ExpArray(ref elems) => {
+            let vals = eval_rooted_list(elems, env)?;
+            Ok(ArrayValue(allocate(ArrayCell(vals), env)))
+        }
+        ExpInUse => Ok(NumValue(env.heap.in_use() as i64)),
+        ExpIndex(ExpIndexData {ref obj, ref index}) =>
+            eval_index_expression(obj, index, env),
+        ExpCall(ExpCallData {ref func, ref args}) =>
+            eval_call_expression(func, args, env),
+        ExpArrow(ref arrow) => Ok(eval_arrow_expression(arrow, env)),
+        ExpFunction(ref func) => Ok(eval_function_expression(func, env)),
         // _ => Ok(UndefinedValue)
     }
 }
 
 fn eval_block_statement(
-    stmts: Vec<Statement>,
+    ses: &[SourceElement],
     env: Environment
-    ) -> JsishResult<()> {
+    ) -> EvalResult<()> {
 
-    for stmt in stmts {
-        eval_statement(stmt, env)?;
+    let lexical = ses.iter().any(|se| matches!(*se, LetDecl(..) | ConstDecl(..)));
+
+    if !lexical {
+        for se in ses {
+            eval_source_element(se, env)?;
+        }
+        return Ok(());
     }
 
-    Ok(())
+    let parent = env.scope.clone();
+    env.scope = new_scope(Some(parent.clone()));
+
+    let mut result = Ok(());
+    for se in ses {
+        result = eval_source_element(se, env).map(|_| ());
+        if result.is_err() {
+            break;
+        }
+    }
+
+    env.scope = parent;
+    result
 }
 
 fn eval_if_statement(
-    guard: Expression,
-    th: Statement,
-    el: Statement,
+    guard: &Expression,
+    th: &Statement,
+    el: &Statement,
     env: Environment
-    ) -> JsishResult<()> {
+    ) -> EvalResult<()> {
 
-    match eval_expression(guard, env)? {
+    match coerce_guard(eval_expression(guard, env)?, env) {
         BoolValue(true) => {eval_statement(th, env)?;},
         BoolValue(false) => {eval_statement(el, env)?;},
         g_val =>
-            return Err(JsishError::from(
+            return Err(type_error(
                     format!("boolean guard required for 'if' statement, \
                             found {}",
                             value_type_strings(&g_val))))
@@ -263,17 +1574,20 @@ fn eval_if_statement(
 }
 
 fn eval_while_statement(
-    guard: Expression,
-    body: Statement,
+    guard: &Expression,
+    body: &Statement,
     env: Environment
-    ) -> JsishResult<()> {
+    ) -> EvalResult<()> {
 
     loop {
-        match eval_expression(guard.clone(), env)? {
-            BoolValue(true) => {eval_statement(body.clone(), env)?;},
+        match coerce_guard(eval_expression(guard, env)?, env) {
+            BoolValue(true) => match eval_statement(body, env) {
+                Err(Break) => break,
+                result => {result?;}
+            },
             BoolValue(false) => break,
             g_val =>
-                return Err(JsishError::from(
+                return Err(type_error(
                         format!("boolean guard required for 'while' \
                                 statement, found {}",
                                 value_type_strings(&g_val))))
@@ -282,49 +1596,235 @@ fn eval_while_statement(
     Ok(())
 }
 
+fn eval_catch_clause(
+    param: &Ident,
+    body: &Statement,
+    exn: Value,
+    env: Environment
+    ) -> EvalResult<()> {
+
+    let parent = env.scope.clone();
+    env.scope = new_scope(Some(parent.clone()));
+    declare_variable(param, exn, env);
+
+    let result = eval_statement(body, env).map(|_| ());
+    env.scope = parent;
+
+    result
+}
+
+fn eval_try_statement(
+    body: &Statement,
+    catch: &Option<CatchData>,
+    finally: &Option<Box<Statement>>,
+    env: Environment
+    ) -> EvalResult<()> {
+
+    let result = match (raised(eval_statement(body, env), env), catch) {
+        (Err(Throw(exn)), &Some(CatchData { ref param, ref body })) =>
+            eval_catch_clause(param, body, exn, env),
+        (result, _) => result.map(|_| ())
+    };
+
+    // An abrupt completion of the finally block replaces that of the try.
+    // Until then, a value thrown or returned stays rooted.
+    if let Some(ref fin) = *finally {
+        let mark = env.temps.len();
+        if let Err(Throw(ref val)) | Err(Return(ref val)) = result {
+            env.temps.push(val.clone());
+        }
+        let fin_result = eval_statement(fin, env).map(|_| ());
+        env.temps.truncate(mark);
+        fin_result?;
+    }
+
+    result
+}
+
+// Runs the clauses from the first whose test equals the discriminant, or else
+// from the default clause, falling through until a break
+fn eval_switch_statement(
+    disc: &Expression,
+    cases: &[CaseData],
+    env: Environment
+    ) -> EvalResult<()> {
+
+    let disc_val = eval_expression(disc, env)?;
+
+    let mut start = None;
+    for (i, case) in cases.iter().enumerate() {
+        if let Some(ref test) = case.test {
+            let test_val = eval_rooted(&disc_val, test, env)?;
+            let matched = apply_binary_operator(BopEq, disc_val.clone(),
+                                                test_val, env)?;
+            if matched == BoolValue(true) {
+                start = Some(i);
+                break;
+            }
+        }
+    }
+
+    let start = match start.or_else(|| cases.iter()
+                                    .position(|c| c.test.is_none())) {
+        Some(i) => i,
+        None => return Ok(())
+    };
+
+    for case in &cases[start..] {
+        for stmt in &case.body {
+            match eval_statement(stmt, env) {
+                Err(Break) => return Ok(()),
+                result => {result?;}
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn eval_statement(
-    stmt: Statement,
+    stmt: &Statement,
     env: Environment
-    ) -> JsishResult<(Environment)> {
-
-    match stmt {
-        StPrint(exp) => print!("{}", eval_expression(exp, env)?),
-        StExp(exp) => {eval_expression(exp, env)?;},
-        StBlock(stmts) => eval_block_statement(stmts, env)?,
-        StIf(StIfData { guard, th, el }) =>
-            eval_if_statement(guard, *th, *el, env)?,
-        StWhile(StWhileData { guard, body }) =>
-            eval_while_statement(guard, *body, env)?,
+    ) -> EvalResult<()> {
+
+    match stmt.kind {
+        StPrint(ref exp) => {
+            let val = eval_expression(exp, env)?;
+            print!("{}", Shown(&val, &env.heap));
+        }
+        StExp(ref exp) => {eval_expression(exp, env)?;},
+        StBlock(ref stmts) => eval_block_statement(stmts, env)?,
+        StIf(StIfData { ref guard, ref th, ref el }) =>
+            eval_if_statement(guard, th, el, env)?,
+        StWhile(StWhileData { ref guard, ref body }) =>
+            eval_while_statement(guard, body, env)?,
+        StThrow(ref exp) => return Err(Throw(eval_expression(exp, env)?)),
+        StTry(StTryData { ref body, ref catch, ref finally }) =>
+            eval_try_statement(body, catch, finally, env)?,
+        StSwitch(StSwitchData { ref disc, ref cases }) =>
+            eval_switch_statement(disc, cases, env)?,
+        StBreak => return Err(Break),
+        StGc => collect_garbage("gc()", None, env),
+        StHeapSnapshot(ref exp) => match eval_expression(exp, env)? {
+            StringValue(path) => write_snapshot(&path, env)
+                .map_err(|message| throw_error("Error", message))?,
+            val =>
+                return Err(type_error(format!("heapSnapshot requires string, \
+                                              found {}",
+                                              value_type_strings(&val))))
+        },
+        StReturn(ref exp) => {
+            let val = match *exp {
+                Some(ref exp) => eval_expression(exp, env)?,
+                None => UndefinedValue
+            };
+            return Err(Return(val));
+        }
         // _ => return Err(JsishError::from("Not yet implemented"))
     }
 
-    Ok(env)
+    Ok(())
 }
 
 fn eval_source_element(
-    se: SourceElement,
+    se: &SourceElement,
     env: Environment
-    ) -> JsishResult<(Environment)> {
-
-    match se {
-        Stmt(s) => eval_statement(s, env),
-        _ => Err(JsishError::from("Not yet implemented"))
+    ) -> EvalResult<()> {
 
+    match *se {
+        Stmt(ref s, _) => eval_statement(s, env),
+        LetDecl(ref decls, _) | ConstDecl(ref decls, _) => {
+            for decl in decls {
+                let (id, val) = match *decl {
+                    DeclId(ref id) => (id, UndefinedValue),
+                    DeclInit(DeclInitData {ref id, ref src}) =>
+                        (id, eval_expression(src, env)?)
+                };
+                declare_variable(id, val, env);
+            }
+            Ok(())
+        }
+        // The names were bound when the enclosing body was entered
+        VarDecl(ref decls, _) => {
+            for decl in decls {
+                if let DeclInit(DeclInitData {ref id, ref src}) = *decl {
+                    let val = eval_expression(src, env)?;
+                    declare_variable(id, val, env);
+                }
+            }
+            Ok(())
+        }
+        FunctionDecl(..) => Ok(())
     }
 }
 
-fn eval_program(prog: Program, env: Environment) -> JsishResult<Environment>{
-    let Prog(ses) = prog;
+fn eval_program(prog: Program, env: Environment) -> EvalResult<Environment>{
+    let Prog(ses, _) = prog;
+    hoist_declarations(&ses, env);
 
-    for se in ses {
+    for se in &ses {
         eval_source_element(se, env)?;
     }
 
     Ok(env)
 }
 
-pub fn interpret(p: Program) -> JsishResult<()> {
-    let mut tle = HashMap::new();
-    eval_program(p, &mut tle)?;
-    Ok(())
+fn new_state(coercion: Coercion, gc_reporting: GcReporting) -> State {
+    State {scope: new_scope(None),
+           this: UndefinedValue,
+           coercion: coercion,
+           gc_reporting: gc_reporting,
+           heap: Heap::new(),
+           frames: Vec::new(),
+           temps: Vec::new()}
+}
+
+// Reports what was asked for about the heap and how the program ended
+fn finish(result: EvalResult<()>, mut tle: State) -> JsishResult<()> {
+    let result = raised(result, &mut tle);
+    if tle.gc_reporting.stats {
+        eprintln!("{}", tle.heap.stats());
+    }
+
+    let snapshot = match tle.gc_reporting.snapshot {
+        Some(ref path) => write_snapshot(path, &tle).map_err(JsishError::from),
+        None => Ok(())
+    };
+
+    let outcome = match result {
+        Ok(_) => Ok(()),
+        Err(Throw(ErrorValue(error))) =>
+            Err(JsishError::from(error.message.clone())),
+        Err(Raise(ErrorData { message, .. })) => Err(JsishError::from(message)),
+        Err(Throw(val)) =>
+            Err(JsishError::from(format!("uncaught exception: {}",
+                                         Shown(&val, &tle.heap)))),
+        Err(Return(_)) => panic!("Parser allowed 'return' outside of a function"),
+        Err(Break) => panic!("Parser allowed 'break' outside of a loop")
+    };
+
+    outcome.and(snapshot)
+}
+
+pub fn interpret(
+    p: Program,
+    coercion: Coercion,
+    gc_reporting: GcReporting
+    ) -> JsishResult<()> {
+
+    let mut tle = new_state(coercion, gc_reporting);
+    let result = eval_program(p, &mut tle).map(|_| ());
+    finish(result, tle)
+}
+
+/// Runs a program compiled to bytecode, on the virtual machine
+pub fn execute(
+    module: &Module,
+    coercion: Coercion,
+    gc_reporting: GcReporting
+    ) -> JsishResult<()> {
+
+    let mut tle = new_state(coercion, gc_reporting);
+    let result = vm::run(module, &mut tle);
+    finish(result, tle)
 }
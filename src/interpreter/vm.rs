@@ -0,0 +1,353 @@
+//! The virtual machine of `--engine=vm`.  It runs a compiled module on the
+//! interpreter's own values, heap and scopes, and through the same operator
+//! functions, so the two engines print and fail alike.
+//!
+//! The operand stack is the interpreter's list of temporaries, so whatever
+//! it holds is rooted for the collector.  A call leaves the function and its
+//! arguments on the stack until it returns.
+
+use super::*;
+
+use bytecode::{Constant, Function, Op, Var};
+
+/// Where a function is running: the function, the next instruction, and the
+/// stack index of the function value, followed by its arguments
+#[derive(Clone, Copy)]
+struct Frame {
+    function: usize,
+    pc: usize,
+    base: usize,
+    argc: usize
+}
+
+/// A try statement being run: where to resume if a value is thrown, and the
+/// state to resume in
+struct Handler {
+    resume: Frame,
+    calls: usize,
+    stack: usize,
+    scope: ScopeRef,
+    this: Value
+}
+
+struct Vm<'a> {
+    module: &'a Module,
+    frame: Frame,
+    /// The frames of the callers of the running function
+    calls: Vec<Frame>,
+    handlers: Vec<Handler>
+}
+
+pub fn run(module: &Module, env: Environment) -> EvalResult<()> {
+    let mut vm = Vm {module: module,
+                     frame: Frame {function: 0, pc: 0, base: 0, argc: 0},
+                     calls: Vec::new(),
+                     handlers: Vec::new()};
+
+    loop {
+        match raised(vm.execute(env), env) {
+            Err(Throw(val)) => vm.catch(val, env)?,
+            result => return result
+        }
+    }
+}
+
+fn var_slot(var: Var) -> Slot {
+    Slot {depth: var.depth as usize,
+          index: var.index as usize,
+          implicit: var.implicit}
+}
+
+fn pop(env: Environment) -> Value {
+    env.temps.pop().expect("operand stack underflow")
+}
+
+fn top(env: &State) -> &Value {
+    env.temps.last().expect("operand stack underflow")
+}
+
+impl<'a> Vm<'a> {
+    fn function(&self) -> &'a Function {
+        &self.module.functions[self.frame.function]
+    }
+
+    fn name(&self, index: u32) -> &'a str {
+        &self.module.names[index as usize]
+    }
+
+    // Resumes at the innermost handler, or fails if there is none
+    fn catch(&mut self, val: Value, env: Environment) -> EvalResult<()> {
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return Err(Throw(val))
+        };
+
+        self.calls.truncate(handler.calls);
+        env.frames.truncate(handler.calls);
+        env.temps.truncate(handler.stack);
+        env.scope = handler.scope;
+        env.this = handler.this;
+        env.temps.push(val);
+        self.frame = handler.resume;
+        Ok(())
+    }
+
+    fn call(&mut self, argc: usize, env: Environment) -> EvalResult<()> {
+        let base = env.temps.len() - argc - 1;
+
+        let closure = match env.temps[base] {
+            FunctionValue(r) => heap_closure(&env.heap, r),
+            ref val => return Err(invoke_error(val))
+        };
+        let function = match closure.code {
+            Code::Compiled(function) => function,
+            Code::Tree { .. } =>
+                panic!("tree-walking function called by the virtual machine")
+        };
+
+        enter_call(&closure, env)?;
+        let caller = mem::replace(&mut self.frame, Frame {function: function,
+                                                          pc: 0,
+                                                          base: base,
+                                                          argc: argc});
+        self.calls.push(caller);
+        Ok(())
+    }
+
+    fn ret(&mut self, env: Environment) -> EvalResult<()> {
+        let val = pop(env);
+
+        let caller = match self.calls.pop() {
+            Some(caller) => caller,
+            None => return Err(Return(val))
+        };
+
+        env.temps.truncate(self.frame.base);
+        env.temps.push(val);
+        leave_call(env);
+        self.frame = caller;
+        Ok(())
+    }
+
+    fn closure(&self, function: usize, env: Environment) -> Value {
+        let f = &self.module.functions[function];
+        let closure = Closure {name: f.name.map(|n| self.name(n).to_string()),
+                               code: Code::Compiled(function),
+                               scope: env.scope.clone(),
+                               this: if f.arrow { env.this.clone() }
+                                     else { UndefinedValue }};
+        FunctionValue(allocate(ClosureCell(Rc::new(closure)), env))
+    }
+
+    // The arguments of the running call from the given one on
+    fn args_from(&self, start: usize, env: Environment) -> Vec<Value> {
+        let args = self.frame.base + 1;
+        env.temps[args + start.min(self.frame.argc)..args + self.frame.argc]
+            .to_vec()
+    }
+
+    // Runs until the program ends or a value is thrown
+    fn execute(&mut self, env: Environment) -> EvalResult<()> {
+        loop {
+            let op = self.function().code[self.frame.pc];
+            self.frame.pc += 1;
+
+            match op {
+                Op::Const(k) => {
+                    let val = match self.module.constants[k as usize] {
+                        Constant::Num(n) => NumValue(n),
+                        Constant::Str(ref s) => string_value(s.clone(), &env.heap)
+                    };
+                    env.temps.push(val);
+                }
+                Op::Undefined => env.temps.push(UndefinedValue),
+                Op::True => env.temps.push(BoolValue(true)),
+                Op::False => env.temps.push(BoolValue(false)),
+                Op::This => {
+                    let this = env.this.clone();
+                    env.temps.push(this);
+                }
+                Op::InUse => {
+                    let in_use = env.heap.in_use() as i64;
+                    env.temps.push(NumValue(in_use));
+                }
+                Op::Pop => {pop(env);},
+                Op::Dup => {
+                    let val = top(env).clone();
+                    env.temps.push(val);
+                }
+
+                Op::Load(var) => {
+                    let val = load_slot(var_slot(var), self.name(var.name), env)?;
+                    env.temps.push(val);
+                }
+                Op::Store(var) => {
+                    let val = top(env).clone();
+                    store_slot(var_slot(var), self.name(var.name), val, env)?;
+                }
+                Op::Declare(index, name) => {
+                    let val = pop(env);
+                    env.scope.borrow_mut()
+                        .set(index as usize, self.name(name), val);
+                }
+                Op::Hoist(index, name) =>
+                    hoist_var(index as usize, self.name(name), env),
+                Op::PushScope => env.scope = new_scope(Some(env.scope.clone())),
+                Op::PopScope => {
+                    let parent = env.scope.borrow().parent.clone()
+                        .expect("popped the global scope");
+                    env.scope = parent;
+                }
+
+                Op::Unary(opr) => {
+                    let val = pop(env);
+                    let result = apply_unary_operator(opr, val, env)?;
+                    env.temps.push(result);
+                }
+                Op::Binary(opr) => {
+                    let rht = pop(env);
+                    let lft = pop(env);
+                    let result = apply_binary_operator(opr, lft, rht, env)?;
+                    env.temps.push(result);
+                }
+                Op::Update(opr, prefix, var) => {
+                    let result = update_variable(opr, prefix, var_slot(var),
+                                                 self.name(var.name), env)?;
+                    env.temps.push(result);
+                }
+                Op::ShortCircuit(opr, target) => {
+                    let sc_value = opr == BopOr;
+                    let decided = if env.coercion == Coercion::Loose {
+                        to_boolean(top(env)) == sc_value
+                    }
+                    else {
+                        match *top(env) {
+                            BoolValue(b) => b == sc_value,
+                            ref val =>
+                                return Err(type_error(
+                                        format!("operator '{}' requires \
+                                                boolean, found {}",
+                                                opr,
+                                                value_type_strings(val))))
+                        }
+                    };
+
+                    if decided {
+                        self.frame.pc = target as usize;
+                    }
+                    else {
+                        pop(env);
+                    }
+                }
+                Op::CheckBoolean(opr) => match *top(env) {
+                    BoolValue(_) => (),
+                    _ if env.coercion == Coercion::Loose => (),
+                    ref val =>
+                        return Err(type_error(
+                                format!("operator '{}' requires boolean * \
+                                        boolean, found boolean * {}",
+                                        opr,
+                                        value_type_strings(val))))
+                },
+
+                Op::Jump(target) => self.frame.pc = target as usize,
+                Op::JumpUnless(guard, target) => {
+                    let val = pop(env);
+                    match coerce_guard(val, env) {
+                        BoolValue(true) => (),
+                        BoolValue(false) => self.frame.pc = target as usize,
+                        val =>
+                            return Err(type_error(
+                                    format!("boolean guard required for {}, \
+                                            found {}",
+                                            guard,
+                                            value_type_strings(&val))))
+                    }
+                }
+                Op::JumpIf(target) => if pop(env) == BoolValue(true) {
+                    self.frame.pc = target as usize;
+                },
+
+                Op::Array(n) => {
+                    let at = env.temps.len() - n as usize;
+                    let elems = env.temps.split_off(at);
+                    let array = ArrayValue(allocate(ArrayCell(elems), env));
+                    env.temps.push(array);
+                }
+                Op::Index => {
+                    let index = pop(env);
+                    let obj = pop(env);
+                    let result = index_value(obj, index, env)?;
+                    env.temps.push(result);
+                }
+                Op::Dot(name) => {
+                    let obj = pop(env);
+                    let result = dot_property(obj, self.name(name), env)?;
+                    env.temps.push(result);
+                }
+                Op::Template(n) => {
+                    let at = env.temps.len() - n as usize;
+                    let mut result = String::new();
+                    for val in &env.temps[at..] {
+                        result.push_str(&Shown(val, &env.heap).to_string());
+                    }
+                    env.temps.truncate(at);
+                    env.temps.push(string_value(result, &env.heap));
+                }
+
+                Op::Closure(function) => {
+                    let closure = self.closure(function as usize, env);
+                    env.temps.push(closure);
+                }
+                Op::Call(argc) => self.call(argc as usize, env)?,
+                Op::Return => self.ret(env)?,
+                Op::Arg(i) => {
+                    let arg = if (i as usize) < self.frame.argc {
+                        env.temps[self.frame.base + 1 + i as usize].clone()
+                    }
+                    else {
+                        UndefinedValue
+                    };
+                    env.temps.push(arg);
+                }
+                Op::JumpIfDefined(target) => if *top(env) != UndefinedValue {
+                    self.frame.pc = target as usize;
+                }
+                else {
+                    pop(env);
+                },
+                Op::Rest(start) => {
+                    let rest = self.args_from(start as usize, env);
+                    let array = ArrayValue(allocate(ArrayCell(rest), env));
+                    env.temps.push(array);
+                }
+
+                Op::Print => {
+                    let val = pop(env);
+                    print!("{}", Shown(&val, &env.heap));
+                }
+                Op::Throw => return Err(Throw(pop(env))),
+                Op::Try(target) => {
+                    let resume = Frame {pc: target as usize, ..self.frame};
+                    self.handlers.push(Handler {resume: resume,
+                                                calls: self.calls.len(),
+                                                stack: env.temps.len(),
+                                                scope: env.scope.clone(),
+                                                this: env.this.clone()});
+                }
+                Op::EndTry => {self.handlers.pop();},
+                Op::Gc => collect_garbage("gc()", None, env),
+                Op::HeapSnapshot => match pop(env) {
+                    StringValue(path) => write_snapshot(&path, env)
+                        .map_err(|message| throw_error("Error", message))?,
+                    val =>
+                        return Err(type_error(
+                                format!("heapSnapshot requires string, \
+                                        found {}",
+                                        value_type_strings(&val))))
+                },
+                Op::Halt => return Ok(())
+            }
+        }
+    }
+}
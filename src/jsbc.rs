@@ -0,0 +1,575 @@
+//! Bytecode files: a compiled module written by `--compile-to`, which
+//! `jsish` runs when given the file in place of a source file.
+//!
+//! A file is a header followed by the encoded module:
+//!
+//! ```text
+//! magic     4 bytes   "JSBC"
+//! version   u32       FORMAT_VERSION
+//! length    u32       length of the module in bytes
+//! checksum  u32       FNV-1a hash of the module
+//! module    length bytes
+//! ```
+//!
+//! Integers are little-endian.  The module is its constants, names and
+//! functions, each list a u32 count followed by the items.  A constant is a
+//! tag byte, 0 for a number as an i64 and 1 for a string; a string is its
+//! u32 length in bytes and then its UTF-8.  A function is its name, a byte 0
+//! for none or 1 and the name's index; a byte 1 if it is an arrow function;
+//! and its instructions, each an opcode byte and then the operands, followed
+//! by the source line of each.
+//!
+//! A file from another version of the format, or whose module does not match
+//! its checksum, is rejected with an error, as is one whose instructions
+//! refer to constants, names, functions or offsets that are not there.  So
+//! is one that could pop more values than it pushed, leave a scope or try
+//! statement it did not enter, or reach a variable beyond the global scope,
+//! none of which the virtual machine checks as it runs.
+
+use types::{JsishResult, JsishError};
+use bytecode::*;
+use ast::{BinaryOperator, UnaryOperator, UpdateOperator};
+use ast::BinaryOperator::*;
+use ast::UnaryOperator::*;
+use ast::UpdateOperator::*;
+
+use std::fs::File;
+use std::io::prelude::*;
+
+/// Changes whenever the encoding or the instruction set does
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"JSBC";
+const HEADER_LENGTH: usize = 16;
+
+const BINARY_OPERATORS: [BinaryOperator; 23] = [
+    BopPlus, BopMinus, BopTimes, BopDivide, BopMod, BopPow, BopBitAnd,
+    BopBitOr, BopBitXor, BopLshift, BopRshift, BopUrshift, BopEq, BopNe,
+    BopStrictEq, BopStrictNe, BopLt, BopGt, BopGe, BopLe, BopAnd, BopOr,
+    BopComma
+];
+const UNARY_OPERATORS: [UnaryOperator; 4] =
+    [UopNot, UopTypeof, UopMinus, UopBitNot];
+const UPDATE_OPERATORS: [UpdateOperator; 2] = [UpdIncrement, UpdDecrement];
+const GUARDS: [Guard; 3] = [Guard::If, Guard::While, Guard::Cond];
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn code_of<T: PartialEq>(table: &[T], item: T) -> u8 {
+    table.iter().position(|t| *t == item)
+        .expect("operator missing from table") as u8
+}
+
+struct Writer {
+    bytes: Vec<u8>
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn i64(&mut self, n: i64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u32(n as u32);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn var(&mut self, var: Var) {
+        self.u32(var.depth);
+        self.u32(var.index);
+        self.u32(var.name);
+        self.u8(var.implicit as u8);
+    }
+
+    fn op(&mut self, op: Op) {
+        use bytecode::Op::*;
+        match op {
+            Const(k) => {self.u8(0); self.u32(k);}
+            Undefined => self.u8(1),
+            True => self.u8(2),
+            False => self.u8(3),
+            This => self.u8(4),
+            InUse => self.u8(5),
+            Pop => self.u8(6),
+            Dup => self.u8(7),
+            Load(var) => {self.u8(8); self.var(var);}
+            Store(var) => {self.u8(9); self.var(var);}
+            Declare(index, name) => {self.u8(10); self.u32(index); self.u32(name);}
+            Hoist(index, name) => {self.u8(11); self.u32(index); self.u32(name);}
+            PushScope => self.u8(12),
+            PopScope => self.u8(13),
+            Unary(opr) => {self.u8(14); self.u8(code_of(&UNARY_OPERATORS, opr));}
+            Binary(opr) => {self.u8(15); self.u8(code_of(&BINARY_OPERATORS, opr));}
+            Update(opr, prefix, var) => {
+                self.u8(16);
+                self.u8(code_of(&UPDATE_OPERATORS, opr));
+                self.u8(prefix as u8);
+                self.var(var);
+            }
+            ShortCircuit(opr, target) => {
+                self.u8(17);
+                self.u8(code_of(&BINARY_OPERATORS, opr));
+                self.u32(target);
+            }
+            CheckBoolean(opr) => {
+                self.u8(18);
+                self.u8(code_of(&BINARY_OPERATORS, opr));
+            }
+            Jump(target) => {self.u8(19); self.u32(target);}
+            JumpUnless(guard, target) => {
+                self.u8(20);
+                self.u8(code_of(&GUARDS, guard));
+                self.u32(target);
+            }
+            JumpIf(target) => {self.u8(21); self.u32(target);}
+            Array(n) => {self.u8(22); self.u32(n);}
+            Index => self.u8(23),
+            Dot(name) => {self.u8(24); self.u32(name);}
+            Template(n) => {self.u8(25); self.u32(n);}
+            Closure(f) => {self.u8(26); self.u32(f);}
+            Call(n) => {self.u8(27); self.u32(n);}
+            Return => self.u8(28),
+            Arg(i) => {self.u8(29); self.u32(i);}
+            JumpIfDefined(target) => {self.u8(30); self.u32(target);}
+            Rest(start) => {self.u8(31); self.u32(start);}
+            Print => self.u8(32),
+            Throw => self.u8(33),
+            Try(target) => {self.u8(34); self.u32(target);}
+            EndTry => self.u8(35),
+            Gc => self.u8(36),
+            HeapSnapshot => self.u8(37),
+            Halt => self.u8(38)
+        }
+    }
+
+    fn module(&mut self, module: &Module) {
+        self.len(module.constants.len());
+        for constant in &module.constants {
+            match *constant {
+                Constant::Num(n) => {self.u8(0); self.i64(n);}
+                Constant::Str(ref s) => {self.u8(1); self.str(s);}
+            }
+        }
+
+        self.len(module.names.len());
+        for name in &module.names {
+            self.str(name);
+        }
+
+        self.len(module.functions.len());
+        for function in &module.functions {
+            match function.name {
+                None => self.u8(0),
+                Some(name) => {self.u8(1); self.u32(name);}
+            }
+            self.u8(function.arrow as u8);
+            self.len(function.code.len());
+            for &op in &function.code {
+                self.op(op);
+            }
+            for &line in &function.lines {
+                self.u32(line);
+            }
+        }
+    }
+}
+
+pub fn write(module: &Module, path: &str) -> JsishResult<()> {
+    let mut body = Writer {bytes: Vec::new()};
+    body.module(module);
+
+    let mut file = Writer {bytes: MAGIC.to_vec()};
+    file.u32(FORMAT_VERSION);
+    file.len(body.bytes.len());
+    file.u32(checksum(&body.bytes));
+    file.bytes.extend(body.bytes);
+
+    File::create(path)
+        .and_then(|mut f| f.write_all(&file.bytes))
+        .map_err(|err| JsishError::from(
+                format!("cannot write bytecode file '{}': {}", path, err)))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+type ReadResult<T> = Result<T, String>;
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> ReadResult<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(String::from("unexpected end of file"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> ReadResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> ReadResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("invalid flag {}", b))
+        }
+    }
+
+    fn u32(&mut self) -> ReadResult<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn i64(&mut self) -> ReadResult<i64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(buf))
+    }
+
+    fn len(&mut self) -> ReadResult<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> ReadResult<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| String::from("invalid UTF-8 in string"))
+    }
+
+    fn coded<T: Copy>(&mut self, table: &[T], what: &str) -> ReadResult<T> {
+        let code = self.u8()?;
+        table.get(code as usize).cloned()
+            .ok_or_else(|| format!("unknown {} {}", what, code))
+    }
+
+    fn var(&mut self) -> ReadResult<Var> {
+        Ok(Var {depth: self.u32()?,
+                index: self.u32()?,
+                name: self.u32()?,
+                implicit: self.bool()?})
+    }
+
+    fn op(&mut self) -> ReadResult<Op> {
+        use bytecode::Op::*;
+        Ok(match self.u8()? {
+            0 => Const(self.u32()?),
+            1 => Undefined,
+            2 => True,
+            3 => False,
+            4 => This,
+            5 => InUse,
+            6 => Pop,
+            7 => Dup,
+            8 => Load(self.var()?),
+            9 => Store(self.var()?),
+            10 => Declare(self.u32()?, self.u32()?),
+            11 => Hoist(self.u32()?, self.u32()?),
+            12 => PushScope,
+            13 => PopScope,
+            14 => Unary(self.coded(&UNARY_OPERATORS, "operator")?),
+            15 => Binary(self.coded(&BINARY_OPERATORS, "operator")?),
+            16 => Update(self.coded(&UPDATE_OPERATORS, "operator")?,
+                         self.bool()?,
+                         self.var()?),
+            17 => ShortCircuit(self.coded(&BINARY_OPERATORS, "operator")?,
+                               self.u32()?),
+            18 => CheckBoolean(self.coded(&BINARY_OPERATORS, "operator")?),
+            19 => Jump(self.u32()?),
+            20 => JumpUnless(self.coded(&GUARDS, "guard")?, self.u32()?),
+            21 => JumpIf(self.u32()?),
+            22 => Array(self.u32()?),
+            23 => Index,
+            24 => Dot(self.u32()?),
+            25 => Template(self.u32()?),
+            26 => Closure(self.u32()?),
+            27 => Call(self.u32()?),
+            28 => Return,
+            29 => Arg(self.u32()?),
+            30 => JumpIfDefined(self.u32()?),
+            31 => Rest(self.u32()?),
+            32 => Print,
+            33 => Throw,
+            34 => Try(self.u32()?),
+            35 => EndTry,
+            36 => Gc,
+            37 => HeapSnapshot,
+            38 => Halt,
+            code => return Err(format!("unknown opcode {}", code))
+        })
+    }
+
+    fn module(&mut self) -> ReadResult<Module> {
+        let mut constants = Vec::new();
+        for _ in 0..self.len()? {
+            constants.push(match self.u8()? {
+                0 => Constant::Num(self.i64()?),
+                1 => Constant::Str(self.str()?),
+                tag => return Err(format!("unknown constant tag {}", tag))
+            });
+        }
+
+        let mut names = Vec::new();
+        for _ in 0..self.len()? {
+            names.push(self.str()?);
+        }
+
+        let mut functions = Vec::new();
+        for _ in 0..self.len()? {
+            let name = if self.bool()? { Some(self.u32()?) } else { None };
+            let arrow = self.bool()?;
+            let len = self.len()?;
+            let mut code = Vec::new();
+            for _ in 0..len {
+                code.push(self.op()?);
+            }
+            let mut lines = Vec::new();
+            for _ in 0..len {
+                lines.push(self.u32()?);
+            }
+            functions.push(Function {name: name,
+                                     arrow: arrow,
+                                     code: code,
+                                     lines: lines});
+        }
+
+        if self.pos != self.bytes.len() {
+            return Err(String::from("unexpected data after the module"));
+        }
+
+        Ok(Module {constants: constants, names: names, functions: functions})
+    }
+}
+
+/// What is known of a running function before one of its instructions: the
+/// values it has pushed, and the scopes and try statements it has entered
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Shape {
+    stack: usize,
+    scopes: usize,
+    handlers: usize
+}
+
+// The number of values an instruction pops and pushes, when it does not jump
+fn stack_effect(op: Op) -> (usize, usize) {
+    use bytecode::Op::*;
+    match op {
+        Const(_) | Undefined | True | False | This | InUse | Load(_) |
+        Update(..) | Closure(_) | Arg(_) | Rest(_) => (0, 1),
+        Pop | Declare(..) | ShortCircuit(..) | JumpUnless(..) | JumpIf(_) |
+        JumpIfDefined(_) | Return | Print | Throw | HeapSnapshot => (1, 0),
+        Store(_) | Unary(_) | CheckBoolean(_) | Dot(_) => (1, 1),
+        Dup => (1, 2),
+        Binary(_) | Index => (2, 1),
+        Array(n) | Template(n) => (n as usize, 1),
+        Call(argc) => (argc as usize + 1, 1),
+        Hoist(..) | PushScope | PopScope | Jump(_) | Try(_) | EndTry | Gc |
+        Halt => (0, 0)
+    }
+}
+
+// Follows every path through a function, checking that the stack, scopes
+// and try statements are the same wherever paths meet, and that variables
+// are within the scopes around them.  outer is the number of scopes around
+// the global one when the function starts; the result lists the functions
+// it creates, each with that number for it.
+fn check_paths(
+    module: &Module,
+    index: usize,
+    outer: usize
+    ) -> ReadResult<Vec<(usize, usize)>> {
+
+    use bytecode::Op::*;
+
+    let code = &module.functions[index].code;
+    let mut shapes: Vec<Option<Shape>> = vec![None; code.len()];
+    let mut pending = vec![0];
+    let mut created = Vec::new();
+
+    if code.is_empty() {
+        return Err(format!("function {} does not end", index));
+    }
+    shapes[0] = Some(Shape {stack: 0, scopes: 0, handlers: 0});
+
+    while let Some(pc) = pending.pop() {
+        let shape = shapes[pc].expect("Instruction reached without a shape");
+        let op = code[pc];
+        let invalid = |why: &str| {
+            format!("{} at offset {} of function {}", why, pc, index)
+        };
+
+        let (pops, pushes) = stack_effect(op);
+        if shape.stack < pops {
+            return Err(invalid("operand stack underflow"));
+        }
+        let mut next = Shape {stack: shape.stack - pops + pushes, ..shape};
+
+        match op {
+            Load(var) | Store(var) | Update(_, _, var)
+                if var.depth as usize > outer + shape.scopes =>
+                return Err(invalid("variable beyond the global scope")),
+            PushScope => next.scopes += 1,
+            PopScope if shape.scopes == 0 =>
+                return Err(invalid("scope left that was not entered")),
+            PopScope => next.scopes -= 1,
+            Try(_) => next.handlers += 1,
+            EndTry if shape.handlers == 0 =>
+                return Err(invalid("try ended that was not begun")),
+            EndTry => next.handlers -= 1,
+            Closure(f) =>
+                created.push((f as usize, outer + shape.scopes + 1)),
+            Arg(_) | Rest(_) if index == 0 =>
+                return Err(invalid("argument outside a function")),
+            Return if index == 0 =>
+                return Err(invalid("return outside a function")),
+            Halt if index > 0 =>
+                return Err(invalid("halt inside a function")),
+            Return | Halt if shape.handlers > 0 =>
+                return Err(invalid("try not ended")),
+            _ => ()
+        }
+
+        // A jump that keeps its operand leaves the stack as it was, and a
+        // thrown value is caught with the stack and scope of its try
+        let (falls, target) = match op {
+            Jump(t) => (false, Some((t, next))),
+            JumpUnless(_, t) | JumpIf(t) => (true, Some((t, next))),
+            ShortCircuit(_, t) | JumpIfDefined(t) => (true, Some((t, shape))),
+            Try(t) => (true, Some((t, Shape {stack: shape.stack + 1,
+                                             ..shape}))),
+            Return | Throw | Halt => (false, None),
+            _ => (true, None)
+        };
+
+        if falls && pc + 1 == code.len() {
+            return Err(format!("function {} does not end", index));
+        }
+
+        let fall = if falls { Some((pc as u32 + 1, next)) } else { None };
+        for (t, shape) in target.into_iter().chain(fall) {
+            match shapes[t as usize] {
+                None => {
+                    shapes[t as usize] = Some(shape);
+                    pending.push(t as usize);
+                }
+                Some(seen) if seen != shape =>
+                    return Err(format!("paths meet with different stacks or \
+                                       scopes at offset {} of function {}",
+                                       t, index)),
+                Some(_) => ()
+            }
+        }
+    }
+
+    Ok(created)
+}
+
+// Checks that every instruction refers to something in the module, and
+// that every path through each function keeps to its stack and scopes.  A
+// function is created only by those before it, and a scope holds one slot
+// for each name.
+fn check(module: &Module) -> ReadResult<()> {
+    use bytecode::Op::*;
+
+    if module.functions.is_empty() {
+        return Err(String::from("no program"));
+    }
+
+    let name = |index: u32| index < module.names.len() as u32;
+    let mut outers: Vec<Option<usize>> = vec![None; module.functions.len()];
+    outers[0] = Some(0);
+
+    for (index, function) in module.functions.iter().enumerate() {
+        // A function takes one Arg for each parameter
+        let params = function.code.iter()
+            .filter(|op| matches!(**op, Arg(_)))
+            .count() as u32;
+        let target = |t: u32| t < function.code.len() as u32;
+        let valid = |op: Op| match op {
+            Const(k) => k < module.constants.len() as u32,
+            Load(var) | Store(var) | Update(_, _, var) =>
+                name(var.name) && name(var.index),
+            Declare(i, n) | Hoist(i, n) => name(i) && name(n),
+            Dot(n) => name(n),
+            Arg(i) => i < params,
+            Rest(i) => i <= params,
+            Closure(f) =>
+                f as usize > index && f < module.functions.len() as u32,
+            ShortCircuit(_, t) | Jump(t) | JumpUnless(_, t) | JumpIf(t) |
+            JumpIfDefined(t) | Try(t) => target(t),
+            _ => true
+        };
+
+        if let Some(op) = function.code.iter().find(|&&op| !valid(op)) {
+            return Err(format!("invalid instruction {:?} in function {}",
+                               op, index));
+        }
+        if function.name.is_some_and(|n| !name(n)) {
+            return Err(format!("invalid name of function {}", index));
+        }
+
+        let outer = outers[index]
+            .ok_or_else(|| format!("function {} is never created", index))?;
+        for (f, scopes) in check_paths(module, index, outer)? {
+            outers[f] = Some(outers[f].map_or(scopes, |s| s.min(scopes)));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read(path: &str) -> JsishResult<Module> {
+    let mut bytes = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut bytes))?;
+
+    if !bytes.starts_with(MAGIC) {
+        return Err(JsishError::from(
+                format!("'{}' is not a bytecode file", path)));
+    }
+
+    let mut header = Reader {bytes: &bytes[MAGIC.len()..], pos: 0};
+    let corrupt = |why: String| JsishError::from(
+        format!("bytecode file '{}' is corrupt: {}", path, why));
+    let version = header.u32().map_err(&corrupt)?;
+    let length = header.len().map_err(&corrupt)?;
+    let sum = header.u32().map_err(&corrupt)?;
+
+    if version != FORMAT_VERSION {
+        return Err(JsishError::from(
+                format!("bytecode file '{}' has format version {}, but this \
+                        jsish reads version {}; compile it again",
+                        path, version, FORMAT_VERSION)));
+    }
+
+    let body = &bytes[HEADER_LENGTH..];
+    if body.len() != length {
+        return Err(corrupt(format!("expected {} bytes of bytecode, found {}",
+                                   length, body.len())));
+    }
+    if checksum(body) != sum {
+        return Err(corrupt(String::from("checksum mismatch")));
+    }
+
+    let module = Reader {bytes: body, pos: 0}.module().map_err(&corrupt)?;
+    check(&module).map_err(&corrupt)?;
+    Ok(module)
+}
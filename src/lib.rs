@@ -1,9 +1,18 @@
 #[macro_use]
 extern crate clap;
+extern crate unicode_xid;
 
 mod tokenizer;
 mod parser;
+mod resolver;
+mod checker;
+mod bytecode;
+mod compiler;
+mod jsbc;
+mod optimizer;
 mod interpreter;
+mod heap;
+mod snapshot;
 pub mod types;
 mod ast;
 
@@ -13,11 +22,24 @@ use types::*;
 enum Mode {
     Ast,
     Print,
+    Emit,
+    CompileTo(String),
+    Check,
     Interpret
 }
 
+#[derive(Debug)]
+enum Engine {
+    Tree,
+    Vm
+}
+
 pub struct Config {
     mode: Mode,
+    engine: Engine,
+    optimize: bool,
+    coercion: interpreter::Coercion,
+    gc_reporting: interpreter::GcReporting,
     filename: String
 }
 
@@ -26,30 +48,146 @@ impl Config {
         let matches = clap_app!(jsish =>
             (@arg AST: -a --ast "print debug-style AST instead of interpretting")
             (@arg PRINT: -p --print "Pretty print AST instead of interpretting")
-            (@arg FILENAME: +required "Specifies the input file to use")
+            (@arg LOOSE: -l --loose "Coerce operands as JavaScript does instead of reporting type errors")
+            (@arg EMIT: --emit +takes_value possible_values(&["bytecode"]) "Print the compiled bytecode instead of interpretting")
+            (@arg COMPILE_TO: --("compile-to") +takes_value "Write the compiled bytecode to the given .jsbc file instead of interpretting")
+            (@arg OPTIMIZE: -O --optimize "Fold constant expressions and remove branches that never run before printing or running")
+            (@arg ENGINE: --engine +takes_value possible_values(&["tree", "vm"]) "Run by walking the AST (the default) or on a bytecode virtual machine")
+            (@arg GC_STATS: --("gc-stats") "Print heap and collection statistics when the program finishes")
+            (@arg GC_TRACE: --("gc-trace") "Print the live heap before and after each collection")
+            (@arg HEAP_SNAPSHOT: --("heap-snapshot") +takes_value "Write a heap snapshot to the given file when the program finishes")
+            (@arg FILENAME: +required "Specifies the input file to use, source or a compiled .jsbc file")
+            (@setting SubcommandsNegateReqs)
+            (@subcommand check =>
+                (about: "Report type errors without running the program")
+                (@arg FILENAME: +required "Specifies the input file to check"))
         ).get_matches();
 
+        if let Some(check) = matches.subcommand_matches("check") {
+            return Ok(Config {filename: String::from(check.value_of("FILENAME").unwrap()),
+                              mode: Mode::Check,
+                              engine: Engine::Tree,
+                              optimize: false,
+                              coercion: interpreter::Coercion::Strict,
+                              gc_reporting: interpreter::GcReporting::default()});
+        }
+
         let filename = String::from(matches.value_of("FILENAME").unwrap());
 
-        let mode = match (matches.is_present("AST"), 
-                          matches.is_present("PRINT")) {
-            (true, true) => 
-                return Err(JsishError::from("Only specify one mode")),
-            (true, false) => Mode::Ast,
-            (false, true) => Mode::Print,
-            (false, false) => Mode::Interpret
+        let modes = ["AST", "PRINT", "EMIT", "COMPILE_TO"].iter()
+            .filter(|&&arg| matches.is_present(arg))
+            .count();
+        if modes > 1 {
+            return Err(JsishError::from("Only specify one mode"));
+        }
+
+        let mode = if matches.is_present("AST") {
+            Mode::Ast
+        }
+        else if matches.is_present("PRINT") {
+            Mode::Print
+        }
+        else if matches.is_present("EMIT") {
+            Mode::Emit
+        }
+        else if let Some(path) = matches.value_of("COMPILE_TO") {
+            Mode::CompileTo(String::from(path))
+        }
+        else {
+            Mode::Interpret
+        };
+
+        let engine = match matches.value_of("ENGINE") {
+            Some("vm") => Engine::Vm,
+            _ => Engine::Tree
+        };
+
+        let coercion = if matches.is_present("LOOSE") {
+            interpreter::Coercion::Loose
+        }
+        else {
+            interpreter::Coercion::Strict
+        };
+
+        let gc_reporting = interpreter::GcReporting {
+            stats: matches.is_present("GC_STATS"),
+            trace: matches.is_present("GC_TRACE"),
+            snapshot: matches.value_of("HEAP_SNAPSHOT").map(String::from)
         };
 
-        Ok(Config {filename: filename, mode: mode})
+        Ok(Config {filename: filename,
+                   mode: mode,
+                   engine: engine,
+                   optimize: matches.is_present("OPTIMIZE"),
+                   coercion: coercion,
+                   gc_reporting: gc_reporting})
+    }
+}
+
+// A compiled file is not parsed again, and always runs on the virtual
+// machine
+fn run_bytecode(config: Config) -> JsishResult<()> {
+    let module = jsbc::read(&config.filename)?;
+
+    match config.mode {
+        Mode::Ast | Mode::Print =>
+            Err(JsishError::from("A bytecode file has no AST to print")),
+        Mode::Check =>
+            Err(JsishError::from("A bytecode file has no source to check")),
+        Mode::Emit => {
+            print!("{}", module);
+            Ok(())
+        }
+        Mode::CompileTo(ref path) => jsbc::write(&module, path),
+        Mode::Interpret =>
+            interpreter::execute(&module, config.coercion, config.gc_reporting)
     }
 }
 
 pub fn run(config: Config) -> JsishResult<()> {
-    let prog = parser::parse(&config.filename)?;
+    if config.filename.ends_with(".jsbc") {
+        return run_bytecode(config);
+    }
+
+    let mut prog = parser::parse(&config.filename)?;
+
+    // Variables are resolved before the optimizer removes any code, so that
+    // what remains runs and fails as it would have
+    match config.mode {
+        Mode::Ast | Mode::Print => (),
+        _ => resolver::resolve(&mut prog)
+    }
+    if config.optimize {
+        optimizer::optimize(&mut prog);
+    }
 
     match config.mode {
-        Mode::Ast => Ok(println!("{:?}", prog)),
-        Mode::Print => Ok(print!("{}", prog)),
-        Mode::Interpret => interpreter::interpret(prog)
+        Mode::Ast => {
+            println!("{:?}", prog);
+            Ok(())
+        }
+        Mode::Print => {
+            print!("{}", prog);
+            Ok(())
+        }
+        Mode::Emit => {
+            print!("{}", compiler::compile(&prog));
+            Ok(())
+        }
+        Mode::CompileTo(ref path) => jsbc::write(&compiler::compile(&prog), path),
+        Mode::Check => {
+            for diagnostic in checker::check(&prog) {
+                eprintln!("{}", diagnostic);
+            }
+            Ok(())
+        }
+        Mode::Interpret => match config.engine {
+            Engine::Tree =>
+                interpreter::interpret(prog, config.coercion,
+                                       config.gc_reporting),
+            Engine::Vm =>
+                interpreter::execute(&compiler::compile(&prog),
+                                     config.coercion, config.gc_reporting)
+        }
     }
 }
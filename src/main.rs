@@ -1,15 +1,31 @@
 extern crate jsish_rust as jsish;
 
 use std::process;
+use std::thread;
 use jsish::*;
 
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
 fn main() {
     let config = Config::new().unwrap_or_else(|err| {
         println!("Problem parsing arguments: {}", err);
         process::exit(1);
     });
 
-    if let Err(e) = run(config) {
+    // Scripts recurse on the interpreter's stack, which must hold as many
+    // calls as the interpreter allows even in a debug build
+    let interpreter = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(config))
+        .unwrap_or_else(|err| {
+            println!("Problem starting the interpreter: {}", err);
+            process::exit(1);
+        });
+
+    // A panic has already been reported by the thread that panicked
+    let result = interpreter.join().unwrap_or_else(|_| process::exit(101));
+
+    if let Err(e) = result {
         match e {
             jsish::types::JsishError::Message(e) => eprintln!("{}", e),
             e => eprintln!("{}", e)
@@ -0,0 +1,304 @@
+//! The optimizer of `--optimize`: folds operators and conditionals whose
+//! operands are constants, and removes the branches and loops that their
+//! guards rule out.
+//!
+//! An operation is folded only if it gives the same value under strict and
+//! loose coercion and cannot fail.  One that would raise a type or range
+//! error is left in place, so the program still fails as it did, at the same
+//! point and with the same message.  A negative
+//! result is written as a negated literal, as the parser would read it.
+//!
+//! What replaces an expression or statement keeps the comments around it;
+//! those on code folded or removed go with the code.
+
+use interpreter::{bitwise_op, checked_arithmetic, compare_strings};
+
+use std::cmp::Ordering;
+use std::mem;
+use std::rc::Rc;
+
+use ast::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
+use ast::SourceElement::*;
+use ast::Program::*;
+use ast::BinaryOperator::*;
+use ast::UnaryOperator::*;
+use ast::Declaration::*;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Num(i64),
+    Str(String),
+    Bool(bool),
+    Undefined
+}
+
+use self::Literal::*;
+
+impl Literal {
+    fn type_string(&self) -> &'static str {
+        match *self {
+            Num(_) => "number",
+            Str(_) => "string",
+            Bool(_) => "boolean",
+            Undefined => "undefined"
+        }
+    }
+
+    fn expression(self) -> Option<Expression> {
+        self.kind().map(Expression::from)
+    }
+
+    fn kind(self) -> Option<ExpressionKind> {
+        match self {
+            Num(n) if n < 0 => n.checked_neg().map(|n| {
+                ExpUnary(ExpUnaryData {opr: UopMinus,
+                                       opnd: Box::new(Expression::from(ExpNum(n)))})
+            }),
+            Num(n) => Some(ExpNum(n)),
+            Str(s) => Some(ExpString(s)),
+            Bool(true) => Some(ExpTrue),
+            Bool(false) => Some(ExpFalse),
+            Undefined => Some(ExpUndefined)
+        }
+    }
+}
+
+fn literal(exp: &Expression) -> Option<Literal> {
+    match exp.kind {
+        ExpNum(n) => Some(Num(n)),
+        ExpString(ref s) => Some(Str(s.clone())),
+        ExpTrue => Some(Bool(true)),
+        ExpFalse => Some(Bool(false)),
+        ExpUndefined => Some(Undefined),
+        ExpUnary(ExpUnaryData {opr: UopMinus, ref opnd}) => match opnd.kind {
+            ExpNum(n) => n.checked_neg().map(Num),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+pub fn optimize(prog: &mut Program) {
+    let Prog(ref mut ses, _) = *prog;
+    optimize_elements(ses);
+}
+
+fn fold_unary(opr: UnaryOperator, val: Literal) -> Option<Literal> {
+    match (opr, val) {
+        (UopNot, Bool(b)) => Some(Bool(!b)),
+        (UopMinus, Num(n)) => n.checked_neg().map(Num),
+        (UopBitNot, Num(n)) => Some(Num(!(n as i32) as i64)),
+        (UopTypeof, val) => Some(Str(String::from(val.type_string()))),
+        _ => None
+    }
+}
+
+fn fold_binary(opr: BinaryOperator, lft: Literal, rht: Literal) -> Option<Literal> {
+    match (opr, lft, rht) {
+        (opr @ BopPlus, Num(l), Num(r)) |
+        (opr @ BopMinus, Num(l), Num(r)) |
+        (opr @ BopTimes, Num(l), Num(r)) |
+        (opr @ BopDivide, Num(l), Num(r)) |
+        (opr @ BopMod, Num(l), Num(r)) |
+        (opr @ BopPow, Num(l), Num(r)) =>
+            checked_arithmetic(opr, l, r).map(Num),
+        (BopPlus, Str(l), Str(r)) => Some(Str(l + &r)),
+        (opr @ BopBitAnd, Num(l), Num(r)) |
+        (opr @ BopBitOr, Num(l), Num(r)) |
+        (opr @ BopBitXor, Num(l), Num(r)) |
+        (opr @ BopLshift, Num(l), Num(r)) |
+        (opr @ BopRshift, Num(l), Num(r)) |
+        (opr @ BopUrshift, Num(l), Num(r)) => Some(Num(bitwise_op(&opr, l, r))),
+        // '==' coerces operands of different types under loose coercion
+        (BopEq, ref l, ref r) if l.type_string() == r.type_string() =>
+            Some(Bool(l == r)),
+        (BopNe, ref l, ref r) if l.type_string() == r.type_string() =>
+            Some(Bool(l != r)),
+        (BopStrictEq, l, r) => Some(Bool(l == r)),
+        (BopStrictNe, l, r) => Some(Bool(l != r)),
+        (opr, Num(l), Num(r)) => compare(opr, l.cmp(&r)),
+        (opr, Str(l), Str(r)) => compare(opr, compare_strings(&l, &r)),
+        _ => None
+    }
+}
+
+fn compare(opr: BinaryOperator, ord: Ordering) -> Option<Literal> {
+    match opr {
+        BopLt => Some(Bool(ord == Ordering::Less)),
+        BopGt => Some(Bool(ord == Ordering::Greater)),
+        BopLe => Some(Bool(ord != Ordering::Greater)),
+        BopGe => Some(Bool(ord != Ordering::Less)),
+        _ => None
+    }
+}
+
+// Folds '&&' or '||' if its left operand decides it, or if both operands
+// are booleans, as otherwise the right one must be checked when it runs
+fn fold_short_circuit(
+    sc_value: bool,
+    lft: &Expression,
+    rht: &Expression
+    ) -> Option<Literal> {
+
+    match (literal(lft), literal(rht)) {
+        (Some(Bool(l)), _) if l == sc_value => Some(Bool(l)),
+        (Some(Bool(_)), Some(Bool(r))) => Some(Bool(r)),
+        _ => None
+    }
+}
+
+fn optimize_elements(ses: &mut Vec<SourceElement>) {
+    ses.retain_mut(|se| match *se {
+        Stmt(ref mut stmt, _) => !optimize_statement(stmt),
+        VarDecl(ref mut decls, _) | LetDecl(ref mut decls, _) |
+        ConstDecl(ref mut decls, _) => {
+            for decl in decls {
+                if let DeclInit(DeclInitData {ref mut src, ..}) = *decl {
+                    optimize_expression(src);
+                }
+            }
+            true
+        }
+        FunctionDecl(FunctionDeclData {ref mut body, ..}, _) => {
+            optimize_elements(Rc::make_mut(body));
+            true
+        }
+    });
+}
+
+// Returns whether the statement was removed, leaving nothing to run
+fn optimize_statement(stmt: &mut Statement) -> bool {
+    match stmt.kind {
+        StExp(ref mut exp) | StPrint(ref mut exp) | StThrow(ref mut exp) |
+        StHeapSnapshot(ref mut exp) | StReturn(Some(ref mut exp)) =>
+            optimize_expression(exp),
+        StReturn(None) | StBreak | StGc => (),
+        StBlock(ref mut ses) => optimize_elements(ses),
+        StIf(StIfData {ref mut guard, ref mut th, ref mut el}) => {
+            optimize_expression(guard);
+            optimize_statement(th);
+            optimize_statement(el);
+        }
+        StWhile(StWhileData {ref mut guard, ref mut body}) => {
+            optimize_expression(guard);
+            optimize_statement(body);
+        }
+        StTry(StTryData {ref mut body, ref mut catch, ref mut finally}) => {
+            optimize_statement(body);
+            if let Some(CatchData {ref mut body, ..}) = *catch {
+                optimize_statement(body);
+            }
+            if let Some(ref mut fin) = *finally {
+                optimize_statement(fin);
+            }
+        }
+        StSwitch(StSwitchData {ref mut disc, ref mut cases}) => {
+            optimize_expression(disc);
+            for case in cases {
+                if let Some(ref mut test) = case.test {
+                    optimize_expression(test);
+                }
+                case.body.retain_mut(|stmt| !optimize_statement(stmt));
+            }
+        }
+    }
+
+    // A branch is a block, so it keeps any scope of its own in place of
+    // the if statement
+    let replacement = match stmt.kind {
+        StIf(StIfData {ref guard, ref mut th, ..}) if guard.kind == ExpTrue =>
+            mem::replace(&mut **th, Statement::from(StBlock(Vec::new()))),
+        StIf(StIfData {ref guard, ref mut el, ..}) if guard.kind == ExpFalse =>
+            mem::replace(&mut **el, Statement::from(StBlock(Vec::new()))),
+        StWhile(StWhileData {ref guard, ..}) if guard.kind == ExpFalse =>
+            Statement::from(StBlock(Vec::new())),
+        _ => return false
+    };
+
+    let replaced = mem::replace(stmt, replacement);
+    stmt.comments.enclose(replaced.comments);
+    matches!(stmt.kind, StBlock(ref ses) if ses.is_empty())
+}
+
+fn optimize_expression(exp: &mut Expression) {
+    match exp.kind {
+        ExpId(_) | ExpNum(_) | ExpString(_) | ExpTrue | ExpFalse |
+        ExpUndefined | ExpThis | ExpInUse => (),
+        ExpUnary(ExpUnaryData {ref mut opnd, ..}) |
+        ExpUpdate(ExpUpdateData {ref mut opnd, ..}) |
+        ExpDot(ExpDotData {obj: ref mut opnd, ..}) => optimize_expression(opnd),
+        ExpBinary(ExpBinaryData {ref mut lft, ref mut rht, ..}) |
+        ExpAssign(ExpAssignData {ref mut lft, ref mut rht}) |
+        ExpCompoundAssign(ExpCompoundAssignData {ref mut lft,
+                                                 ref mut rht, ..}) |
+        ExpIndex(ExpIndexData {obj: ref mut lft, index: ref mut rht}) => {
+            optimize_expression(lft);
+            optimize_expression(rht);
+        }
+        ExpCond(ExpCondData {ref mut guard, ref mut then_exp,
+                             ref mut else_exp}) => {
+            optimize_expression(guard);
+            optimize_expression(then_exp);
+            optimize_expression(else_exp);
+        }
+        ExpTemplate(ExpTemplateData {exps: ref mut elems, ..}) |
+        ExpArray(ref mut elems) => for elem in elems {
+            optimize_expression(elem);
+        },
+        ExpCall(ExpCallData {ref mut func, ref mut args}) => {
+            optimize_expression(func);
+            for arg in args {
+                optimize_expression(arg);
+            }
+        }
+        ExpArrow(ref mut arrow) => {
+            for param in Rc::make_mut(&mut arrow.params) {
+                if let Some(ref mut default) = param.default {
+                    optimize_expression(default);
+                }
+            }
+            match arrow.body {
+                FunctionBody::BodyExp(ref mut exp) =>
+                    optimize_expression(Rc::make_mut(exp)),
+                FunctionBody::BodyBlock(ref mut ses) =>
+                    optimize_elements(Rc::make_mut(ses))
+            }
+        }
+        ExpFunction(ExpFunctionData {ref mut body, ..}) =>
+            optimize_elements(Rc::make_mut(body))
+    }
+
+    let folded = match exp.kind {
+        ExpUnary(ExpUnaryData {opr, ref opnd}) => literal(opnd)
+            .and_then(|val| fold_unary(opr, val))
+            .and_then(Literal::expression),
+        ExpBinary(ExpBinaryData {opr: BopAnd, ref lft, ref rht}) =>
+            fold_short_circuit(false, lft, rht).and_then(Literal::expression),
+        ExpBinary(ExpBinaryData {opr: BopOr, ref lft, ref rht}) =>
+            fold_short_circuit(true, lft, rht).and_then(Literal::expression),
+        // The left operand of ',' is a constant with nothing to run
+        ExpBinary(ExpBinaryData {opr: BopComma, ref lft, ref mut rht})
+            if literal(lft).is_some() =>
+            Some(mem::replace(&mut **rht, Expression::from(ExpUndefined))),
+        ExpBinary(ExpBinaryData {opr, ref lft, ref rht}) =>
+            match (literal(lft), literal(rht)) {
+                (Some(l), Some(r)) =>
+                    fold_binary(opr, l, r).and_then(Literal::expression),
+                _ => None
+            },
+        ExpCond(ExpCondData {ref guard, ref mut then_exp, ..})
+            if guard.kind == ExpTrue =>
+            Some(mem::replace(&mut **then_exp, Expression::from(ExpUndefined))),
+        ExpCond(ExpCondData {ref guard, ref mut else_exp, ..})
+            if guard.kind == ExpFalse =>
+            Some(mem::replace(&mut **else_exp, Expression::from(ExpUndefined))),
+        _ => None
+    };
+
+    if let Some(folded) = folded {
+        let replaced = mem::replace(exp, folded);
+        exp.comments.enclose(replaced.comments);
+    }
+}
@@ -1,15 +1,19 @@
 use types::{FStream, JsishResult, JsishError};
 
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use tokenizer::*;
 use tokenizer::Token::*;
 
 use ast::*;
-use ast::Expression::*;
-use ast::Statement::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
 use ast::SourceElement::*;
 use ast::Program::*;
 use ast::BinaryOperator::*;
 use ast::UnaryOperator::*;
+use ast::UpdateOperator::*;
 use ast::Declaration::*;
 
 fn match_tk(
@@ -42,6 +46,11 @@ fn match_id(itr: &mut FStream, tk: Token) -> JsishResult<(String, Token)> {
     }
 }
 
+// A variable name, as opposed to a property name
+fn match_ident(itr: &mut FStream, tk: Token) -> JsishResult<(Ident, Token)> {
+    match_id(itr, tk).map(|(id, tk1)| (Ident::from(id), tk1))
+}
+
 // First Sets
 fn is_expression(tk: &Token) -> bool {
     match *tk {
@@ -49,16 +58,23 @@ fn is_expression(tk: &Token) -> bool {
         TkId(_) => true,
         TkNum(_) => true,
         TkString(_) => true,
+        TkTemplate(_) => true,
+        TkTemplateHead(_) => true,
         TkTrue => true,
         TkFalse => true,
         TkUndefined => true,
         TkNot => true,
         TkTypeof => true,
         TkMinus => true,
+        TkBitNot => true,
+        TkIncrement => true,
+        TkDecrement => true,
         TkFunction => true,
         TkNew => true,
         TkLbrace => true,
+        TkLbracket => true,
         TkThis => true,
+        TkInUse => true,
         _ => false
     }
 }
@@ -68,8 +84,8 @@ fn is_expression_statement(tk: &Token) -> bool {
 }
 
 fn is_valid_lhs(tk: &Expression) -> bool {
-    match tk {
-        &ExpId(_) => true,
+    match tk.kind {
+        ExpId(_) => true,
         _ => false
     }
 }
@@ -80,12 +96,25 @@ fn is_statement(tk: &Token) -> bool {
         &TkLbrace => true,
         &TkIf => true,
         &TkWhile => true,
+        &TkThrow => true,
+        &TkTry => true,
+        &TkSwitch => true,
+        &TkBreak => true,
+        &TkReturn => true,
+        &TkGc => true,
+        &TkHeapSnapshot => true,
         tk => is_expression_statement(tk)
     }
 }
 
+// 'var' and function declarations are only allowed at the top level of a
+// program or function
+fn is_block_element(tk: &Token) -> bool {
+    is_statement(tk) || *tk == TkLet || *tk == TkConst
+}
+
 fn is_source_element(tk: &Token) -> bool {
-    is_statement(tk) || *tk == TkVar
+    is_block_element(tk) || *tk == TkVar || *tk == TkFunction
 }
 
 
@@ -105,6 +134,26 @@ fn search_for_op<'a, T>(
     None
 }
 
+// The pending comments before the current token
+fn leading_comments(itr: &mut FStream) -> Vec<Comment> {
+    let index = itr.tokens - 1;
+    take_comments(itr, index)
+}
+
+// The pending comments that a construct just parsed should keep after it:
+// any left inside it, and those on the line it ended on
+fn trailing_comments(itr: &mut FStream) -> Vec<Comment> {
+    let index = itr.tokens - 1;
+    let mut comments = take_comments(itr, index - 1);
+
+    while itr.comments.front()
+        .is_some_and(|&(i, ref c)| i == index && !c.own_line) {
+        comments.push(itr.comments.pop_front().expect("Comment Failure").1);
+    }
+
+    comments
+}
+
 fn parse_repetition<T>(
     itr: &mut FStream,
     tk: Token,
@@ -162,8 +211,8 @@ fn parse_binary_expression(
             let tk2 = next_token(itr)?;
             let (rht, tk3) = parse_opnd(itr, tk2.clone())?;
 
-            lft = ExpBinary(ExpBinaryData {opr: opr.clone(), lft: Box::new(lft),
-                rht:Box::new(rht)});
+            lft = ExpBinary(ExpBinaryData {opr: *opr, lft: Box::new(lft),
+                rht:Box::new(rht)}).into();
 
             tk_cursor = tk3;
         }
@@ -189,19 +238,37 @@ fn parse_assignment_expression(
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
+    let compound_pairs = vec![(TkPlusAssign, BopPlus),
+                              (TkMinusAssign, BopMinus),
+                              (TkTimesAssign, BopTimes),
+                              (TkDivideAssign, BopDivide),
+                              (TkModAssign, BopMod)];
+
     let (lhs, tk1) = parse_conditional_expression(itr, tk)?;
 
-    if tk1 != TkAssign {
+    let compound = search_for_op(&tk1, &compound_pairs)
+        .map(|&(_, ref opr)| *opr);
+
+    if tk1 != TkAssign && compound.is_none() {
         Ok((lhs, tk1))
     } 
     else if !is_valid_lhs(&lhs) {
-        Err(JsishError::from("unexpected token '='"))
+        Err(JsishError::from(format!("unexpected token '{}'", tk1)))
     }
     else {
-        let tk2 = match_tk(itr, tk1, TkAssign)?;
+        let tk2 = next_token(itr)?;
         let (rhs, tk3) = parse_assignment_expression(itr, tk2)?;
-        Ok(((ExpAssign(ExpAssignData {lft: Box::new(lhs), 
-            rht: Box::new(rhs)})), tk3))
+
+        let exp = match compound {
+            None => ExpAssign(ExpAssignData {lft: Box::new(lhs),
+                                             rht: Box::new(rhs)}),
+            Some(opr) =>
+                ExpCompoundAssign(ExpCompoundAssignData {opr: opr,
+                                                         lft: Box::new(lhs),
+                                                         rht: Box::new(rhs)})
+        };
+
+        Ok((exp.into(), tk3))
     }
 }
 
@@ -219,7 +286,8 @@ fn parse_conditional_expression(
         let (else_exp, tk5) = parse_assignment_expression(itr, tk4)?;
 
         Ok((ExpCond(ExpCondData {guard: Box::new(guard), 
-            then_exp: Box::new(then_exp), else_exp: Box::new(else_exp)}), tk5))
+            then_exp: Box::new(then_exp), else_exp: Box::new(else_exp)}).into(),
+            tk5))
     }
     else {
         Ok((guard, tk1))
@@ -241,17 +309,46 @@ fn parse_logical_and_expression(
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    parse_binary_expression(itr, tk, &parse_equality_expression,
+    parse_binary_expression(itr, tk, &parse_bitwise_or_expression,
                             vec![(TkAnd, BopAnd)])
 }
 
+fn parse_bitwise_or_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    parse_binary_expression(itr, tk, &parse_bitwise_xor_expression,
+                            vec![(TkBitOr, BopBitOr)])
+}
+
+fn parse_bitwise_xor_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    parse_binary_expression(itr, tk, &parse_bitwise_and_expression,
+                            vec![(TkBitXor, BopBitXor)])
+}
+
+fn parse_bitwise_and_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    parse_binary_expression(itr, tk, &parse_equality_expression,
+                            vec![(TkBitAnd, BopBitAnd)])
+}
+
 fn parse_equality_expression(
     itr: &mut FStream,
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
     parse_binary_expression(itr, tk, &parse_relational_expression,
-                            vec![(TkNe, BopNe), (TkEq, BopEq)])
+                            vec![(TkNe, BopNe), (TkEq, BopEq),
+                                 (TkStrictNe, BopStrictNe),
+                                 (TkStrictEq, BopStrictEq)])
 }
 
 fn parse_relational_expression(
@@ -259,13 +356,24 @@ fn parse_relational_expression(
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    parse_binary_expression(itr, tk, &parse_additive_expression,
+    parse_binary_expression(itr, tk, &parse_shift_expression,
                             vec![(TkLe, BopLe),
                                  (TkLt, BopLt),
                                  (TkGt, BopGt),
                                  (TkGe, BopGe)])
 }
 
+fn parse_shift_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    parse_binary_expression(itr, tk, &parse_additive_expression,
+                            vec![(TkLshift, BopLshift),
+                                 (TkRshift, BopRshift),
+                                 (TkUrshift, BopUrshift)])
+}
+
 fn parse_additive_expression(
     itr: &mut FStream,
     tk: Token
@@ -282,29 +390,115 @@ fn parse_multiplicative_expression(
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    parse_binary_expression(itr, tk, &parse_unary_expression,
+    parse_binary_expression(itr, tk, &parse_exponentiation_expression,
                             vec![(TkTimes, BopTimes),
                                  (TkDivide, BopDivide),
                                  (TkMod, BopMod)])
 }
 
+fn unary_operators() -> Vec<(Token, UnaryOperator)> {
+    vec![(TkNot, UopNot), (TkTypeof, UopTypeof), (TkMinus, UopMinus),
+         (TkBitNot, UopBitNot)]
+}
+
+// '**' is right associative, and as in JavaScript an unparenthesized unary
+// expression may not be its left operand.
+fn parse_exponentiation_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    if search_for_op(&tk, &unary_operators()).is_some() {
+        return parse_unary_expression(itr, tk);
+    }
+
+    let (lft, tk1) = parse_unary_expression(itr, tk)?;
+
+    if tk1 == TkPow {
+        let tk2 = match_tk(itr, tk1, TkPow)?;
+        let (rht, tk3) = parse_exponentiation_expression(itr, tk2)?;
+        Ok((ExpBinary(ExpBinaryData {opr: BopPow, lft: Box::new(lft),
+                                     rht: Box::new(rht)}).into(), tk3))
+    }
+    else {
+        Ok((lft, tk1))
+    }
+}
+
+// An operand takes the comments before it and those up to the next token,
+// so that each comment is printed where it was written
 fn parse_unary_expression(
     itr: &mut FStream,
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    let op_pairs = vec![(TkNot, UopNot), (TkTypeof, UopTypeof),
-                        (TkMinus, UopMinus)];
+    let before = leading_comments(itr);
+    let (mut exp, tk1) = parse_bare_unary_expression(itr, tk)?;
+
+    let index = itr.tokens - 1;
+    exp.comments.before.splice(0..0, before);
+    exp.comments.after.extend(take_comments(itr, index));
+    Ok((exp, tk1))
+}
+
+fn parse_bare_unary_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    let op_pairs = unary_operators();
+    let update_pairs = update_operators();
 
     if let Some(&(_, ref opr)) = search_for_op(&tk, &op_pairs) {
         let tk1 = next_token(itr)?;
-        let (opnd, tk2) = parse_left_hand_side_expression(itr, tk1)?;
-        Ok((ExpUnary(ExpUnaryData {opr: (*opr).clone(), 
-                                   opnd: Box::new(opnd)}), 
+        let (opnd, tk2) = parse_postfix_expression(itr, tk1)?;
+        Ok((ExpUnary(ExpUnaryData {opr: *opr, 
+                                   opnd: Box::new(opnd)}).into(), 
+            tk2))
+    }
+    else if let Some(&(_, ref opr)) = search_for_op(&tk, &update_pairs) {
+        let tk1 = next_token(itr)?;
+        let (opnd, tk2) = parse_postfix_expression(itr, tk1)?;
+
+        if !is_valid_lhs(&opnd) {
+            return Err(JsishError::from(
+                    format!("invalid target for '{}'", opr)));
+        }
+
+        Ok((ExpUpdate(ExpUpdateData {opr: *opr, prefix: true,
+                                     opnd: Box::new(opnd)}).into(),
+            tk2))
+    }
+    else {
+        parse_postfix_expression(itr, tk)
+    }
+}
+
+fn update_operators() -> Vec<(Token, UpdateOperator)> {
+    vec![(TkIncrement, UpdIncrement), (TkDecrement, UpdDecrement)]
+}
+
+fn parse_postfix_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    let update_pairs = update_operators();
+    let (opnd, tk1) = parse_left_hand_side_expression(itr, tk)?;
+
+    if let Some(&(_, ref opr)) = search_for_op(&tk1, &update_pairs) {
+        if !is_valid_lhs(&opnd) {
+            return Err(JsishError::from(
+                    format!("invalid target for '{}'", opr)));
+        }
+
+        let tk2 = next_token(itr)?;
+        Ok((ExpUpdate(ExpUpdateData {opr: *opr, prefix: false,
+                                     opnd: Box::new(opnd)}).into(),
             tk2))
     }
     else {
-        parse_left_hand_side_expression(itr, tk)
+        Ok((opnd, tk1))
     }
 }
 
@@ -316,32 +510,356 @@ fn parse_left_hand_side_expression(
     parse_call_expression(itr, tk)
 }
 
+fn parse_arguments(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Vec<Expression>, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkLparen)?;
+
+    let (args, tk2) = if is_expression(&tk1) {
+        parse_comma_repetition(itr, tk1, &parse_assignment_expression)?
+    }
+    else {
+        (Vec::new(), tk1)
+    };
+
+    let tk3 = match_tk(itr, tk2, TkRparen)?;
+    Ok((args, tk3))
+}
+
 fn parse_call_expression(
     itr: &mut FStream,
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    parse_member_expression(itr, tk)
+    let (mut exp, mut tk_cursor) = parse_member_expression(itr, tk)?;
+
+    loop {
+        if tk_cursor == TkLparen {
+            let (args, tk1) = parse_arguments(itr, tk_cursor)?;
+            exp = ExpCall(ExpCallData {func: Box::new(exp), args: args}).into();
+            tk_cursor = tk1;
+        }
+        else if tk_cursor == TkDot || tk_cursor == TkLbracket {
+            let (member, tk1) = parse_member_suffix(itr, exp, tk_cursor)?;
+            exp = member;
+            tk_cursor = tk1;
+        }
+        else {
+            break;
+        }
+    }
+
+    Ok((exp, tk_cursor))
 }
 
-fn parse_member_expression(
+// Parses a single '.id' or '[exp]' access of obj
+fn parse_member_suffix(
     itr: &mut FStream,
+    obj: Expression,
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
-    parse_primary_expression(itr, tk)
+    if tk == TkDot {
+        let tk1 = match_tk(itr, tk, TkDot)?;
+        let (prop, tk2) = match_id(itr, tk1)?;
+        Ok((ExpDot(ExpDotData {obj: Box::new(obj), prop: prop}).into(), tk2))
+    }
+    else {
+        let tk1 = match_tk(itr, tk, TkLbracket)?;
+        let (index, tk2) = parse_expression(itr, tk1)?;
+        let tk3 = match_tk(itr, tk2, TkRbracket)?;
+        Ok((ExpIndex(ExpIndexData {obj: Box::new(obj),
+                                   index: Box::new(index)}).into(),
+            tk3))
+    }
 }
 
-fn parse_parenthesized_expression(
+fn parse_member_expression(
     itr: &mut FStream,
     tk: Token
     ) -> JsishResult<(Expression, Token)> {
 
+    let (mut obj, tk1) = parse_primary_expression(itr, tk)?;
+    let mut tk_cursor = tk1;
+
+    while tk_cursor == TkDot || tk_cursor == TkLbracket {
+        let (member, tk2) = parse_member_suffix(itr, obj, tk_cursor)?;
+        obj = member;
+        tk_cursor = tk2;
+    }
+
+    Ok((obj, tk_cursor))
+}
+
+// A parameter is written as an identifier, optionally assigned a default, so
+// a parenthesized list is parsed as expressions until the '=>' shows that it
+// was a parameter list.  The comments around a parameter name are added to
+// comments, for the header of the function.
+fn expression_to_param(
+    exp: Expression,
+    comments: &mut Vec<Comment>
+    ) -> JsishResult<Param> {
+
+    let Expression {kind, comments: Comments {before, after, ..}} = exp;
+    comments.extend(before);
+
+    let param = match kind {
+        ExpId(name) => Param {name: name, default: None},
+        ExpAssign(ExpAssignData {lft, rht}) => {
+            let Expression {kind, comments: Comments {before, after, ..}} = *lft;
+            comments.extend(before);
+            comments.extend(after);
+            match kind {
+                ExpId(name) => Param {name: name, default: Some(*rht)},
+                kind => return Err(JsishError::from(
+                        format!("invalid arrow function parameter '{}'",
+                                Expression::from(kind))))
+            }
+        }
+        kind => return Err(JsishError::from(
+                format!("invalid arrow function parameter '{}'",
+                        Expression::from(kind))))
+    };
+
+    comments.extend(after);
+    Ok(param)
+}
+
+// Also returns the comments in the function's header and after the last
+// element of the body
+fn parse_function_body(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Vec<SourceElement>, Comments, Token)> {
+
+    let header = leading_comments(itr);
+    let tk1 = match_tk(itr, tk, TkLbrace)?;
+    let (body, tk2) = parse_repetition(itr,
+                                       tk1,
+                                       &is_source_element,
+                                       &parse_source_element)?;
+    let inside = leading_comments(itr);
+    let tk3 = match_tk(itr, tk2, TkRbrace)?;
+
+    // A function body is not nested in any loop of its caller
+    for se in &body {
+        if let Stmt(ref stmt, _) = *se {
+            check_jump_targets(stmt, false, true)?;
+        }
+    }
+
+    let comments = Comments {header: header, inside: inside,
+                             ..Comments::default()};
+    Ok((body, comments, tk3))
+}
+
+fn parse_parameters(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Vec<Ident>, Token)> {
+
     let tk1 = match_tk(itr, tk, TkLparen)?;
-    let (exp, tk2) = parse_expression(itr, tk1)?;
+
+    let (params, tk2) = match tk1 {
+        TkId(_) => parse_comma_repetition(itr, tk1, &match_ident)?,
+        _ => (Vec::new(), tk1)
+    };
+
     let tk3 = match_tk(itr, tk2, TkRparen)?;
+    Ok((params, tk3))
+}
+
+fn parse_function_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkFunction)?;
+
+    let (name, tk2) = match tk1 {
+        TkId(_) => {
+            let (name, tk2) = match_ident(itr, tk1)?;
+            (Some(name), tk2)
+        }
+        _ => (None, tk1)
+    };
+
+    let (params, tk3) = parse_parameters(itr, tk2)?;
+    let (body, comments, tk4) = parse_function_body(itr, tk3)?;
+
+    let exp = ExpFunction(ExpFunctionData {name: name,
+                                           params: params,
+                                           body: Rc::new(body)});
+    Ok((Expression {kind: exp, comments: comments}, tk4))
+}
+
+fn parse_function_declaration(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(SourceElement, Token)> {
+
+    let line = itr.token_line;
+    let tk1 = match_tk(itr, tk, TkFunction)?;
+    let (name, tk2) = match_ident(itr, tk1)?;
+    let (params, tk3) = parse_parameters(itr, tk2)?;
+    let (body, comments, tk4) = parse_function_body(itr, tk3)?;
+
+    let pos = Position {line: line, comments: comments};
+    Ok((FunctionDecl(FunctionDeclData {name: name,
+                                       params: params,
+                                       body: Rc::new(body)},
+                     pos),
+        tk4))
+}
+
+fn parse_arrow_body(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(FunctionBody, Comments, Token)> {
+
+    if tk == TkLbrace {
+        let (body, comments, tk1) = parse_function_body(itr, tk)?;
+        Ok((FunctionBody::BodyBlock(Rc::new(body)), comments, tk1))
+    }
+    else {
+        let (exp, tk1) = parse_assignment_expression(itr, tk)?;
+        Ok((FunctionBody::BodyExp(Rc::new(exp)), Comments::default(), tk1))
+    }
+}
+
+fn parse_arrow_function(
+    itr: &mut FStream,
+    tk: Token,
+    params: Vec<Param>,
+    rest: Option<Ident>,
+    comments: Vec<Comment>
+    ) -> JsishResult<(Expression, Token)> {
+
+    let names: Vec<&String> = params.iter().map(|p| &p.name.name)
+        .chain(rest.iter().map(|r| &r.name))
+        .collect();
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return Err(JsishError::from(
+                    format!("duplicate parameter name '{}'", name)));
+        }
+    }
+
+    let tk1 = match_tk(itr, tk, TkArrow)?;
+    let (body, mut body_comments, tk2) = parse_arrow_body(itr, tk1)?;
+    body_comments.header.splice(0..0, comments);
 
-    Ok((exp, tk3))
+    let exp = ExpArrow(ExpArrowData {params: Rc::new(params),
+                                     rest: rest,
+                                     body: body});
+    Ok((Expression {kind: exp, comments: body_comments}, tk2))
+}
+
+// Also parses the parameter list of an arrow function, which looks like a
+// parenthesized expression until the '=>' after it
+fn parse_parenthesized_expression(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    let mut tk_cursor = match_tk(itr, tk, TkLparen)?;
+    let mut items = Vec::new();
+    let mut rest = None;
+
+    while tk_cursor != TkRparen || !items.is_empty() {
+        if tk_cursor == TkEllipsis {
+            let tk1 = match_tk(itr, tk_cursor, TkEllipsis)?;
+            let (id, tk2) = match_ident(itr, tk1)?;
+            rest = Some(id);
+            tk_cursor = tk2;
+            break;
+        }
+
+        let (item, tk1) = parse_assignment_expression(itr, tk_cursor)?;
+        items.push(item);
+
+        if tk1 != TkComma {
+            tk_cursor = tk1;
+            break;
+        }
+        tk_cursor = match_tk(itr, tk1, TkComma)?;
+    }
+
+    let tk3 = match_tk(itr, tk_cursor, TkRparen)?;
+
+    if tk3 == TkArrow {
+        let mut comments = Vec::new();
+        let params = items.into_iter()
+            .map(|item| expression_to_param(item, &mut comments))
+            .collect::<JsishResult<Vec<Param>>>()?;
+        return parse_arrow_function(itr, tk3, params, rest, comments);
+    }
+
+    let mut items = items.into_iter();
+    match (items.next(), rest) {
+        (Some(first), None) => {
+            let exp = items.fold(first, |lft, rht| {
+                ExpBinary(ExpBinaryData {opr: BopComma, lft: Box::new(lft),
+                                         rht: Box::new(rht)}).into()
+            });
+            Ok((exp, tk3))
+        }
+        _ => Err(JsishError::from(
+                format!("expected '=>', found '{}'", tk3)))
+    }
+}
+
+fn parse_array_literal(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Expression, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkLbracket)?;
+
+    let (elems, tk2) = if tk1 == TkRbracket {
+        (Vec::new(), tk1)
+    }
+    else {
+        parse_comma_repetition(itr, tk1, &parse_assignment_expression)?
+    };
+
+    let tk3 = match_tk(itr, tk2, TkRbracket)?;
+    Ok((ExpArray(elems).into(), tk3))
+}
+
+// The `}` closing each substitution is followed by template text rather than
+// a token, so it is matched here instead of through match_tk.
+fn parse_template(
+    itr: &mut FStream,
+    head: TemplateChunk
+    ) -> JsishResult<(Expression, Token)> {
+
+    let mut chunks = vec![head];
+    let mut exps = Vec::new();
+
+    loop {
+        let tk = next_token(itr)?;
+        let (exp, tk1) = parse_expression(itr, tk)?;
+        if tk1 != TkRbrace {
+            return Err(JsishError::from(
+                    format!("expected '}}', found '{}'", tk1)));
+        }
+        exps.push(exp);
+
+        match next_template_token(itr)? {
+            TkTemplateMiddle(chunk) => chunks.push(chunk),
+            TkTemplateTail(chunk) => {
+                chunks.push(chunk);
+                break;
+            }
+            _ => panic!("Template continuation is not template text")
+        }
+    }
+
+    let exp = ExpTemplate(ExpTemplateData {chunks: chunks, exps: exps});
+    Ok((exp.into(), next_token(itr)?))
 }
 
 fn parse_primary_expression(
@@ -352,20 +870,48 @@ fn parse_primary_expression(
     if tk == TkLparen {
         parse_parenthesized_expression(itr, TkLparen)
     }
+    else if tk == TkLbracket {
+        parse_array_literal(itr, tk)
+    }
+    else if tk == TkFunction {
+        parse_function_expression(itr, tk)
+    }
+    else if let TkTemplateHead(head) = tk {
+        parse_template(itr, head)
+    }
+    else if tk == TkInUse {
+        let tk1 = match_tk(itr, tk, TkInUse)?;
+        let tk2 = match_tk(itr, tk1, TkLparen)?;
+        let tk3 = match_tk(itr, tk2, TkRparen)?;
+        Ok((ExpInUse.into(), tk3))
+    }
+    else if let TkId(id) = tk {
+        let tk1 = next_token(itr)?;
+        if tk1 == TkArrow {
+            let param = Param {name: Ident::from(id), default: None};
+            parse_arrow_function(itr, tk1, vec![param], None, Vec::new())
+        }
+        else {
+            Ok((ExpId(Ident::from(id)).into(), tk1))
+        }
+    }
     else {
         let exp = match tk {
-            TkId(s) => ExpId(s),
             TkNum(n) => ExpNum(n),
             TkTrue => ExpTrue,
             TkFalse => ExpFalse,
             TkString(s) => ExpString(s),
+            TkTemplate(chunk) =>
+                ExpTemplate(ExpTemplateData {chunks: vec![chunk],
+                                             exps: Vec::new()}),
             TkUndefined => ExpUndefined,
+            TkThis => ExpThis,
             _ => 
                 return Err(JsishError::from(
                         format!("expected 'value', found '{}'", tk)))
         };
 
-        Ok((exp, next_token(itr)?))
+        Ok((exp.into(), next_token(itr)?))
     }
 }
 
@@ -378,7 +924,7 @@ fn parse_print_statement(
     let (exp, tk2) = parse_expression(itr, tk1)?;
     let tk3 = match_tk(itr, tk2, TkSemi)?;
 
-    Ok((StPrint(exp), tk3))
+    Ok((StPrint(exp).into(), tk3))
 }
 
 fn parse_expression_statement(
@@ -389,7 +935,7 @@ fn parse_expression_statement(
     let (exp, tk1) = parse_expression(itr, tk)?;
     let tk2 = match_tk(itr, tk1, TkSemi)?;
 
-    Ok((StExp(exp), tk2))
+    Ok((StExp(exp).into(), tk2))
 }
 
 fn parse_block_statement(
@@ -397,13 +943,21 @@ fn parse_block_statement(
     tk: Token
     ) -> JsishResult<(Statement, Token)> {
 
+    let before = leading_comments(itr);
     let tk1 = match_tk(itr, tk, TkLbrace)?;
-    let (stmts, tk2) = parse_repetition(itr,
+    let (elems, tk2) = parse_repetition(itr,
                                         tk1,
-                                        &is_statement,
-                                        &parse_statement)?;
+                                        &is_block_element,
+                                        &parse_source_element)?;
+    let inside = leading_comments(itr);
     let tk3 = match_tk(itr, tk2, TkRbrace)?;
-    Ok((StBlock(stmts), tk3))
+
+    let mut block = Statement::from(StBlock(elems));
+    block.comments = Comments {before: before,
+                               after: trailing_comments(itr),
+                               inside: inside,
+                               ..Comments::default()};
+    Ok((block, tk3))
 }
 
 fn parse_else(
@@ -416,7 +970,7 @@ fn parse_else(
         parse_block_statement(itr, tk1)
     }
     else {
-        Ok((StBlock(Vec::new()), tk))
+        Ok((StBlock(Vec::new()).into(), tk))
     }
 }
 
@@ -431,7 +985,7 @@ fn parse_if_statement(
     let tk4 = match_tk(itr, tk3, TkRparen)?;
     let (th, tk5) = parse_block_statement(itr, tk4)?;
     let (el, tk6) = parse_else(itr, tk5)?;
-    Ok(((StIf(StIfData {guard: guard, th: Box::new(th), el: Box::new(el)})),
+    Ok((StIf(StIfData {guard: guard, th: Box::new(th), el: Box::new(el)}).into(),
         tk6))
 }
 
@@ -445,7 +999,416 @@ fn parse_while_statement(
     let (guard, tk3) = parse_expression(itr, tk2)?;
     let tk4 = match_tk(itr, tk3, TkRparen)?;
     let (th, tk5) = parse_block_statement(itr, tk4)?;
-    Ok(((StWhile(StWhileData {guard: guard, body: Box::new(th)})), tk5))
+    Ok((StWhile(StWhileData {guard: guard, body: Box::new(th)}).into(), tk5))
+}
+
+fn parse_throw_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkThrow)?;
+    let (exp, tk2) = parse_expression(itr, tk1)?;
+    let tk3 = match_tk(itr, tk2, TkSemi)?;
+
+    Ok((StThrow(exp).into(), tk3))
+}
+
+fn parse_catch(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Option<CatchData>, Token)> {
+
+    if tk == TkCatch {
+        let tk1 = match_tk(itr, tk, TkCatch)?;
+        let tk2 = match_tk(itr, tk1, TkLparen)?;
+        let (param, tk3) = match_ident(itr, tk2)?;
+        let tk4 = match_tk(itr, tk3, TkRparen)?;
+        let (body, tk5) = parse_block_statement(itr, tk4)?;
+        Ok((Some(CatchData {param: param, body: Box::new(body)}), tk5))
+    }
+    else {
+        Ok((None, tk))
+    }
+}
+
+fn parse_finally(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Option<Box<Statement>>, Token)> {
+
+    if tk == TkFinally {
+        let tk1 = match_tk(itr, tk, TkFinally)?;
+        let (body, tk2) = parse_block_statement(itr, tk1)?;
+        Ok((Some(Box::new(body)), tk2))
+    }
+    else {
+        Ok((None, tk))
+    }
+}
+
+fn parse_try_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkTry)?;
+    let (body, tk2) = parse_block_statement(itr, tk1)?;
+    let (catch, tk3) = parse_catch(itr, tk2)?;
+    let (finally, tk4) = parse_finally(itr, tk3)?;
+
+    if catch.is_none() && finally.is_none() {
+        return Err(JsishError::from(
+                format!("expected 'catch' or 'finally', found '{}'", tk4)));
+    }
+
+    Ok((StTry(StTryData {body: Box::new(body), catch: catch,
+                         finally: finally}).into(), tk4))
+}
+
+// The comments after the last statement of a clause are left to the clause
+// after it, or to the switch statement
+fn parse_case_clause(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(CaseData, Token)> {
+
+    let comments = leading_comments(itr);
+    let (test, tk1) = if tk == TkDefault {
+        (None, next_token(itr)?)
+    }
+    else {
+        let tk1 = match_tk(itr, tk, TkCase)?;
+        let (test, tk2) = parse_expression(itr, tk1)?;
+        (Some(test), tk2)
+    };
+
+    let tk2 = match_tk(itr, tk1, TkColon)?;
+    let (body, tk3) = parse_repetition(itr, tk2, &is_statement,
+                                       &parse_statement)?;
+
+    Ok((CaseData {test: test, body: body, comments: comments}, tk3))
+}
+
+fn parse_switch_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkSwitch)?;
+    let tk2 = match_tk(itr, tk1, TkLparen)?;
+    let (disc, tk3) = parse_expression(itr, tk2)?;
+    let tk4 = match_tk(itr, tk3, TkRparen)?;
+    let mut tk_cursor = match_tk(itr, tk4, TkLbrace)?;
+
+    let mut cases: Vec<CaseData> = Vec::new();
+    while tk_cursor == TkCase || tk_cursor == TkDefault {
+        if tk_cursor == TkDefault && cases.iter().any(|c| c.test.is_none()) {
+            return Err(JsishError::from(
+                    "more than one 'default' clause in switch statement"));
+        }
+
+        let (case, tk_temp) = parse_case_clause(itr, tk_cursor)?;
+        cases.push(case);
+        tk_cursor = tk_temp;
+    }
+
+    let inside = leading_comments(itr);
+    let tk5 = match_tk(itr, tk_cursor, TkRbrace)?;
+
+    let mut stmt = Statement::from(StSwitch(StSwitchData {disc: disc,
+                                                          cases: cases}));
+    stmt.comments.inside = inside;
+    Ok((stmt, tk5))
+}
+
+fn parse_return_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkReturn)?;
+
+    let (exp, tk2) = if is_expression(&tk1) {
+        let (exp, tk2) = parse_expression(itr, tk1)?;
+        (Some(exp), tk2)
+    }
+    else {
+        (None, tk1)
+    };
+
+    let tk3 = match_tk(itr, tk2, TkSemi)?;
+    Ok((StReturn(exp).into(), tk3))
+}
+
+fn parse_break_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkBreak)?;
+    let tk2 = match_tk(itr, tk1, TkSemi)?;
+
+    Ok((StBreak.into(), tk2))
+}
+
+fn parse_gc_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkGc)?;
+    let tk2 = match_tk(itr, tk1, TkLparen)?;
+    let tk3 = match_tk(itr, tk2, TkRparen)?;
+    let tk4 = match_tk(itr, tk3, TkSemi)?;
+
+    Ok((StGc.into(), tk4))
+}
+
+fn parse_heap_snapshot_statement(
+    itr: &mut FStream,
+    tk: Token
+    ) -> JsishResult<(Statement, Token)> {
+
+    let tk1 = match_tk(itr, tk, TkHeapSnapshot)?;
+    let tk2 = match_tk(itr, tk1, TkLparen)?;
+    let (exp, tk3) = parse_assignment_expression(itr, tk2)?;
+    let tk4 = match_tk(itr, tk3, TkRparen)?;
+    let tk5 = match_tk(itr, tk4, TkSemi)?;
+
+    Ok((StHeapSnapshot(exp).into(), tk5))
+}
+
+// A break must be nested in a loop or switch, and a return in a function;
+// the grammar alone cannot say so, hence the check after parsing
+fn check_jump_targets(
+    stmt: &Statement,
+    breakable: bool,
+    in_function: bool
+    ) -> JsishResult<()> {
+
+    match stmt.kind {
+        StBreak if !breakable => Err(JsishError::from(
+                "'break' outside of loop or switch statement")),
+        StReturn(_) if !in_function => Err(JsishError::from(
+                "return statements are only valid inside functions")),
+        StBlock(ref elems) => elems.iter()
+            .try_for_each(|se| match *se {
+                Stmt(ref s, _) => check_jump_targets(s, breakable, in_function),
+                _ => Ok(())
+            }),
+        StIf(StIfData {ref th, ref el, ..}) => {
+            check_jump_targets(th, breakable, in_function)?;
+            check_jump_targets(el, breakable, in_function)
+        }
+        StWhile(StWhileData {ref body, ..}) =>
+            check_jump_targets(body, true, in_function),
+        StTry(StTryData {ref body, ref catch, ref finally}) => {
+            check_jump_targets(body, breakable, in_function)?;
+            if let Some(CatchData {ref body, ..}) = *catch {
+                check_jump_targets(body, breakable, in_function)?;
+            }
+            if let Some(ref fin) = *finally {
+                check_jump_targets(fin, breakable, in_function)?;
+            }
+            Ok(())
+        }
+        StSwitch(StSwitchData {ref cases, ..}) => cases.iter()
+            .flat_map(|c| c.body.iter())
+            .try_for_each(|s| check_jump_targets(s, true, in_function)),
+        _ => Ok(())
+    }
+}
+
+// The names declared in each enclosing scope, innermost last, each mapped to
+// whether it is a const
+type DeclScopes = Vec<HashMap<String, bool>>;
+
+fn check_assignment_target(
+    target: &Expression,
+    scopes: &DeclScopes
+    ) -> JsishResult<()> {
+
+    if let ExpId(ref id) = target.kind {
+        let constant = scopes.iter().rev()
+            .filter_map(|scope| scope.get(&id.name))
+            .next();
+        if constant == Some(&true) {
+            return Err(JsishError::from(
+                    format!("assignment to constant variable '{}'", id)));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_expression_decls(
+    exp: &Expression,
+    scopes: &mut DeclScopes
+    ) -> JsishResult<()> {
+
+    match exp.kind {
+        ExpAssign(ExpAssignData {ref lft, ref rht}) |
+        ExpCompoundAssign(ExpCompoundAssignData {ref lft, ref rht, ..}) => {
+            check_assignment_target(lft, scopes)?;
+            check_expression_decls(rht, scopes)
+        }
+        ExpUpdate(ExpUpdateData {ref opnd, ..}) =>
+            check_assignment_target(opnd, scopes),
+        ExpBinary(ExpBinaryData {ref lft, ref rht, ..}) |
+        ExpIndex(ExpIndexData {obj: ref lft, index: ref rht}) => {
+            check_expression_decls(lft, scopes)?;
+            check_expression_decls(rht, scopes)
+        }
+        ExpUnary(ExpUnaryData {ref opnd, ..}) |
+        ExpDot(ExpDotData {obj: ref opnd, ..}) =>
+            check_expression_decls(opnd, scopes),
+        ExpCond(ExpCondData {ref guard, ref then_exp, ref else_exp}) => {
+            check_expression_decls(guard, scopes)?;
+            check_expression_decls(then_exp, scopes)?;
+            check_expression_decls(else_exp, scopes)
+        }
+        ExpTemplate(ExpTemplateData {exps: ref elems, ..}) |
+        ExpArray(ref elems) => elems.iter()
+            .try_for_each(|e| check_expression_decls(e, scopes)),
+        ExpCall(ExpCallData {ref func, ref args}) => {
+            check_expression_decls(func, scopes)?;
+            args.iter().try_for_each(|e| check_expression_decls(e, scopes))
+        }
+        ExpFunction(ExpFunctionData {ref name, ref params, ref body}) => {
+            let names = name.iter().chain(params.iter());
+            scopes.push(names.map(|name| (name.name.clone(), false)).collect());
+            check_declarations(body, scopes)?;
+            scopes.pop();
+            Ok(())
+        }
+        ExpArrow(ExpArrowData {ref params, ref rest, ref body}) => {
+            let names = params.iter().map(|p| &p.name).chain(rest.iter());
+            scopes.push(names.map(|name| (name.name.clone(), false)).collect());
+
+            for default in params.iter().filter_map(|p| p.default.as_ref()) {
+                check_expression_decls(default, scopes)?;
+            }
+            match *body {
+                FunctionBody::BodyExp(ref exp) =>
+                    check_expression_decls(exp, scopes)?,
+                FunctionBody::BodyBlock(ref elems) =>
+                    check_declarations(elems, scopes)?
+            }
+
+            scopes.pop();
+            Ok(())
+        }
+        _ => Ok(())
+    }
+}
+
+fn check_statement_decls(
+    stmt: &Statement,
+    scopes: &mut DeclScopes
+    ) -> JsishResult<()> {
+
+    match stmt.kind {
+        StExp(ref exp) | StPrint(ref exp) | StThrow(ref exp) |
+        StReturn(Some(ref exp)) | StHeapSnapshot(ref exp) =>
+            check_expression_decls(exp, scopes),
+        StBlock(ref elems) => check_declarations(elems, scopes),
+        StIf(StIfData {ref guard, ref th, ref el}) => {
+            check_expression_decls(guard, scopes)?;
+            check_statement_decls(th, scopes)?;
+            check_statement_decls(el, scopes)
+        }
+        StWhile(StWhileData {ref guard, ref body}) => {
+            check_expression_decls(guard, scopes)?;
+            check_statement_decls(body, scopes)
+        }
+        StTry(StTryData {ref body, ref catch, ref finally}) => {
+            check_statement_decls(body, scopes)?;
+            if let Some(CatchData {ref param, ref body}) = *catch {
+                scopes.push(vec![(param.name.clone(), false)].into_iter().collect());
+                check_statement_decls(body, scopes)?;
+                scopes.pop();
+            }
+            if let Some(ref fin) = *finally {
+                check_statement_decls(fin, scopes)?;
+            }
+            Ok(())
+        }
+        StSwitch(StSwitchData {ref disc, ref cases}) => {
+            check_expression_decls(disc, scopes)?;
+            for case in cases {
+                if let Some(ref test) = case.test {
+                    check_expression_decls(test, scopes)?;
+                }
+                for stmt in &case.body {
+                    check_statement_decls(stmt, scopes)?;
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(())
+    }
+}
+
+// Checks a block, function body or program for let and const names declared
+// twice in it and for assignments to const variables.  Scoping is static, so
+// both are reported before running.
+fn check_declarations(
+    elems: &[SourceElement],
+    scopes: &mut DeclScopes
+    ) -> JsishResult<()> {
+
+    // Redeclaring a var or function is allowed, but not as a let or const
+    let mut scope: HashMap<String, bool> = HashMap::new();
+    for se in elems {
+        match *se {
+            VarDecl(ref decls, _) => for decl in decls {
+                scope.insert(decl.id().to_string(), false);
+            },
+            FunctionDecl(FunctionDeclData {ref name, ..}, _) => {
+                scope.insert(name.name.clone(), false);
+            }
+            _ => ()
+        }
+    }
+
+    for se in elems {
+        let (decls, constant) = match *se {
+            LetDecl(ref decls, _) => (decls, false),
+            ConstDecl(ref decls, _) => (decls, true),
+            _ => continue
+        };
+        for decl in decls {
+            if scope.insert(decl.id().to_string(), constant).is_some() {
+                return Err(JsishError::from(
+                        format!("identifier '{}' has already been declared",
+                                decl.id())));
+            }
+        }
+    }
+
+    scopes.push(scope);
+
+    for se in elems {
+        match *se {
+            Stmt(ref stmt, _) => check_statement_decls(stmt, scopes)?,
+            VarDecl(ref decls, _) | LetDecl(ref decls, _) |
+            ConstDecl(ref decls, _) =>
+                for decl in decls {
+                    if let DeclInit(DeclInitData {ref src, ..}) = *decl {
+                        check_expression_decls(src, scopes)?;
+                    }
+                },
+            FunctionDecl(FunctionDeclData {ref params, ref body, ..}, _) => {
+                scopes.push(params.iter().map(|p| (p.name.clone(), false)).collect());
+                check_declarations(body, scopes)?;
+                scopes.pop();
+            }
+        }
+    }
+
+    scopes.pop();
+    Ok(())
 }
 
 fn parse_statement(
@@ -453,7 +1416,9 @@ fn parse_statement(
     tk: Token
     ) -> JsishResult<(Statement, Token)> {
 
-    if tk == TkPrint {
+    let before = leading_comments(itr);
+
+    let (mut stmt, tk1) = if tk == TkPrint {
         parse_print_statement(itr, tk)
     }
     else if tk == TkLbrace {
@@ -465,12 +1430,37 @@ fn parse_statement(
     else if tk == TkWhile {
         parse_while_statement(itr, tk)
     }
+    else if tk == TkThrow {
+        parse_throw_statement(itr, tk)
+    }
+    else if tk == TkTry {
+        parse_try_statement(itr, tk)
+    }
+    else if tk == TkSwitch {
+        parse_switch_statement(itr, tk)
+    }
+    else if tk == TkBreak {
+        parse_break_statement(itr, tk)
+    }
+    else if tk == TkReturn {
+        parse_return_statement(itr, tk)
+    }
+    else if tk == TkGc {
+        parse_gc_statement(itr, tk)
+    }
+    else if tk == TkHeapSnapshot {
+        parse_heap_snapshot_statement(itr, tk)
+    }
     else if is_expression(&tk) {
         parse_expression_statement(itr, tk)
     }
     else {
         Err(JsishError::from("Expected statement"))
-    }
+    }?;
+
+    stmt.comments.before.splice(0..0, before);
+    stmt.comments.after.extend(trailing_comments(itr));
+    Ok((stmt, tk1))
 }
 
 fn parse_variable_element(
@@ -478,7 +1468,7 @@ fn parse_variable_element(
     tk: Token
     ) -> JsishResult<(Declaration, Token)> {
 
-    let (id, tk1) = match_id(itr, tk)?;
+    let (id, tk1) = match_ident(itr, tk)?;
 
     if tk1 == TkAssign {
         let tk2 = match_tk(itr, tk1, TkAssign)?;
@@ -492,10 +1482,11 @@ fn parse_variable_element(
 
 fn parse_variable_elements(
     itr: &mut FStream,
-    tk: Token
+    tk: Token,
+    keyword: Token
     ) -> JsishResult<(Vec<Declaration>, Token)> {
 
-    let tk1 = match_tk(itr, tk, TkVar)?;
+    let tk1 = match_tk(itr, tk, keyword)?;
     let (decl, tk2) = parse_comma_repetition(itr, 
                                              tk1,
                                              &parse_variable_element)?;
@@ -509,14 +1500,38 @@ fn parse_source_element(
     tk: Token
     ) -> JsishResult<(SourceElement, Token)> {
 
-    if tk == TkVar {
-        let (decl, tk1) = parse_variable_elements(itr, tk)?;
-        Ok((VarDecl(decl), tk1))
+    let line = itr.token_line;
+    let before = leading_comments(itr);
+
+    let (mut se, tk1) = if tk == TkVar {
+        let (decl, tk1) = parse_variable_elements(itr, tk, TkVar)?;
+        (VarDecl(decl, Position::from(line)), tk1)
+    }
+    else if tk == TkFunction {
+        parse_function_declaration(itr, tk)?
+    }
+    else if tk == TkLet {
+        let (decl, tk1) = parse_variable_elements(itr, tk, TkLet)?;
+        (LetDecl(decl, Position::from(line)), tk1)
+    }
+    else if tk == TkConst {
+        let (decl, tk1) = parse_variable_elements(itr, tk, TkConst)?;
+        if let Some(decl) = decl.iter().find(|d| matches!(**d, DeclId(_))) {
+            return Err(JsishError::from(
+                    format!("missing initializer in const declaration of \
+                            '{}'", decl)));
+        }
+        (ConstDecl(decl, Position::from(line)), tk1)
     }
     else {
         let (stmt, tk1) = parse_statement(itr, tk)?;
-        Ok((Stmt(stmt), tk1))
-    }
+        (Stmt(Box::new(stmt), Position::from(line)), tk1)
+    };
+
+    let comments = &mut se.position_mut().comments;
+    comments.before = before;
+    comments.after = trailing_comments(itr);
+    Ok((se, tk1))
 }
 
 fn parse_program(
@@ -528,10 +1543,18 @@ fn parse_program(
                                         tk,
                                         &is_source_element,
                                         &parse_source_element)?;
+    let dangling = leading_comments(itr);
 
     let tk2 = match_eof(itr, tk1)?;
 
-    Ok((Prog(elems), tk2))
+    for elem in &elems {
+        if let Stmt(ref stmt, _) = *elem {
+            check_jump_targets(stmt, false, false)?;
+        }
+    }
+    check_declarations(&elems, &mut Vec::new())?;
+
+    Ok((Prog(elems, dangling), tk2))
 }
 
 pub fn parse_stream(itr: &mut FStream) -> JsishResult<Program>{
@@ -0,0 +1,273 @@
+//! Resolution: before a program runs, every variable it declares or uses is
+//! given the slot it will occupy, so the interpreter indexes its scopes
+//! rather than searching them by name.
+//!
+//! The scopes here mirror those the interpreter creates: the global scope;
+//! one per call, holding the parameters and then the declarations of the
+//! body; one holding the name of a named function expression; one per catch
+//! clause; and one per block declaring let or const variables.  A variable
+//! declared nowhere is an implicit global, with a global slot after those of
+//! the declared globals, which reading fails until it is assigned, if it
+//! ever is.
+
+use std::rc::Rc;
+
+use ast::*;
+use ast::ExpressionKind::*;
+use ast::StatementKind::*;
+use ast::SourceElement::*;
+use ast::Program::*;
+
+struct Resolver {
+    /// The names in each enclosing scope, in slot order, innermost last
+    scopes: Vec<Vec<String>>,
+    /// Number of global slots before those of the implicit globals
+    declared_globals: usize
+}
+
+pub fn resolve(prog: &mut Program) {
+    Resolver {scopes: Vec::new(), declared_globals: 0}.resolve_program(prog)
+}
+
+impl Resolver {
+
+    fn lookup(&self, name: &str) -> Option<Slot> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = scope.iter().position(|n| n == name) {
+                let global = depth + 1 == self.scopes.len();
+                return Some(Slot {depth: depth,
+                                  index: index,
+                                  implicit: global &&
+                                      index >= self.declared_globals});
+            }
+        }
+        None
+    }
+
+    // Gives the name a slot in the innermost scope, unless it has one there
+    // already, as a var may share a parameter's
+    fn declare(&mut self, id: &mut Ident) {
+        let scope = self.scopes.last_mut().expect("no scope to declare in");
+        let index = match scope.iter().position(|n| *n == id.name) {
+            Some(index) => index,
+            None => {
+                scope.push(id.name.clone());
+                scope.len() - 1
+            }
+        };
+        id.slot = Some(Slot {depth: 0, index: index, implicit: false});
+    }
+
+    // Reads and assignments alike give a name declared nowhere a global
+    // slot, the first time it is seen
+    fn resolve_name(&mut self, id: &mut Ident) {
+        if self.lookup(&id.name).is_none() {
+            self.scopes[0].push(id.name.clone());
+        }
+        id.slot = self.lookup(&id.name);
+    }
+
+    fn resolve_program(&mut self, prog: &mut Program) {
+        let Prog(ref mut ses, _) = *prog;
+
+        self.scopes.push(Vec::new());
+        self.hoist(ses);
+        self.declared_globals = self.scopes[0].len();
+
+        self.resolve_elements(ses)
+    }
+
+    // Declares the names the interpreter binds on entering a body or block:
+    // its var and function declarations, then its let and const variables
+    fn hoist(&mut self, ses: &mut [SourceElement]) {
+        for se in ses.iter_mut() {
+            match *se {
+                VarDecl(ref mut decls, _) => for decl in decls {
+                    self.declare(decl.ident_mut());
+                },
+                FunctionDecl(FunctionDeclData {ref mut name, ..}, _) =>
+                    self.declare(name),
+                _ => ()
+            }
+        }
+
+        for se in ses.iter_mut() {
+            if let LetDecl(ref mut decls, _) | ConstDecl(ref mut decls, _) = *se {
+                for decl in decls {
+                    self.declare(decl.ident_mut());
+                }
+            }
+        }
+    }
+
+    fn resolve_elements(&mut self, ses: &mut [SourceElement]) {
+        for se in ses {
+            match *se {
+                Stmt(ref mut stmt, _) => self.resolve_statement(stmt),
+                VarDecl(ref mut decls, _) | LetDecl(ref mut decls, _) |
+                ConstDecl(ref mut decls, _) => for decl in decls {
+                    if let Declaration::DeclInit(DeclInitData {ref mut src, ..}) =
+                        *decl {
+                        self.resolve_expression(src);
+                    }
+                },
+                FunctionDecl(FunctionDeclData {ref mut params, ref mut body, ..},
+                             _) =>
+                    self.resolve_function(params, body)
+            }
+        }
+    }
+
+    fn resolve_function(
+        &mut self,
+        params: &mut [Ident],
+        body: &mut Rc<Vec<SourceElement>>
+        ) {
+
+        let body = Rc::make_mut(body);
+        self.scopes.push(Vec::new());
+        for param in params {
+            self.declare(param);
+        }
+        self.hoist(body);
+        self.resolve_elements(body);
+        self.scopes.pop();
+    }
+
+    // Defaults are resolved once every parameter has a slot, as a default
+    // naming a later parameter reads it before it is bound
+    fn resolve_arrow(&mut self, arrow: &mut ExpArrowData) {
+        self.scopes.push(Vec::new());
+
+        let params = Rc::make_mut(&mut arrow.params);
+        for param in params.iter_mut() {
+            self.declare(&mut param.name);
+        }
+        if let Some(ref mut rest) = arrow.rest {
+            self.declare(rest);
+        }
+        for param in params.iter_mut() {
+            if let Some(ref mut default) = param.default {
+                self.resolve_expression(default);
+            }
+        }
+
+        match arrow.body {
+            FunctionBody::BodyExp(ref mut exp) =>
+                self.resolve_expression(Rc::make_mut(exp)),
+            FunctionBody::BodyBlock(ref mut ses) => {
+                let ses = Rc::make_mut(ses);
+                self.hoist(ses);
+                self.resolve_elements(ses);
+            }
+        }
+
+        self.scopes.pop();
+    }
+
+    fn resolve_expression(&mut self, exp: &mut Expression) {
+        match exp.kind {
+            ExpId(ref mut id) => self.resolve_name(id),
+            ExpNum(_) | ExpString(_) | ExpTrue | ExpFalse | ExpUndefined |
+            ExpThis | ExpInUse => (),
+            ExpUnary(ExpUnaryData {ref mut opnd, ..}) |
+            ExpUpdate(ExpUpdateData {ref mut opnd, ..}) |
+            ExpDot(ExpDotData {obj: ref mut opnd, ..}) =>
+                self.resolve_expression(opnd),
+            ExpBinary(ExpBinaryData {ref mut lft, ref mut rht, ..}) |
+            ExpCompoundAssign(ExpCompoundAssignData {ref mut lft,
+                                                     ref mut rht, ..}) |
+            ExpIndex(ExpIndexData {obj: ref mut lft, index: ref mut rht}) => {
+                self.resolve_expression(lft);
+                self.resolve_expression(rht)
+            }
+            ExpAssign(ExpAssignData {ref mut lft, ref mut rht}) => {
+                match lft.kind {
+                    ExpId(ref mut id) => self.resolve_name(id),
+                    _ => self.resolve_expression(lft)
+                }
+                self.resolve_expression(rht)
+            }
+            ExpCond(ExpCondData {ref mut guard, ref mut then_exp,
+                                 ref mut else_exp}) => {
+                self.resolve_expression(guard);
+                self.resolve_expression(then_exp);
+                self.resolve_expression(else_exp)
+            }
+            ExpTemplate(ExpTemplateData {exps: ref mut elems, ..}) |
+            ExpArray(ref mut elems) => for elem in elems {
+                self.resolve_expression(elem);
+            },
+            ExpCall(ExpCallData {ref mut func, ref mut args}) => {
+                self.resolve_expression(func);
+                for arg in args {
+                    self.resolve_expression(arg);
+                }
+            }
+            ExpArrow(ref mut arrow) => self.resolve_arrow(arrow),
+            ExpFunction(ExpFunctionData {ref mut name, ref mut params,
+                                         ref mut body}) => match *name {
+                None => self.resolve_function(params, body),
+                Some(ref mut name) => {
+                    self.scopes.push(Vec::new());
+                    self.declare(name);
+                    self.resolve_function(params, body);
+                    self.scopes.pop();
+                }
+            }
+        }
+    }
+
+    fn resolve_statement(&mut self, stmt: &mut Statement) {
+        match stmt.kind {
+            StExp(ref mut exp) | StPrint(ref mut exp) | StThrow(ref mut exp) |
+            StHeapSnapshot(ref mut exp) | StReturn(Some(ref mut exp)) =>
+                self.resolve_expression(exp),
+            StReturn(None) | StBreak | StGc => (),
+            StBlock(ref mut ses) => {
+                let lexical = ses.iter()
+                    .any(|se| matches!(*se, LetDecl(..) | ConstDecl(..)));
+                if !lexical {
+                    return self.resolve_elements(ses);
+                }
+
+                self.scopes.push(Vec::new());
+                self.hoist(ses);
+                self.resolve_elements(ses);
+                self.scopes.pop();
+            }
+            StIf(StIfData {ref mut guard, ref mut th, ref mut el}) => {
+                self.resolve_expression(guard);
+                self.resolve_statement(th);
+                self.resolve_statement(el)
+            }
+            StWhile(StWhileData {ref mut guard, ref mut body}) => {
+                self.resolve_expression(guard);
+                self.resolve_statement(body)
+            }
+            StTry(StTryData {ref mut body, ref mut catch, ref mut finally}) => {
+                self.resolve_statement(body);
+                if let Some(CatchData {ref mut param, ref mut body}) = *catch {
+                    self.scopes.push(Vec::new());
+                    self.declare(param);
+                    self.resolve_statement(body);
+                    self.scopes.pop();
+                }
+                if let Some(ref mut fin) = *finally {
+                    self.resolve_statement(fin);
+                }
+            }
+            StSwitch(StSwitchData {ref mut disc, ref mut cases}) => {
+                self.resolve_expression(disc);
+                for case in cases {
+                    if let Some(ref mut test) = case.test {
+                        self.resolve_expression(test);
+                    }
+                    for stmt in &mut case.body {
+                        self.resolve_statement(stmt);
+                    }
+                }
+            }
+        }
+    }
+}
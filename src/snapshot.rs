@@ -0,0 +1,142 @@
+//! Heap snapshots: the live values of a running program as a graph, written
+//! as JSON by the `heapSnapshot(file)` statement and the `--heap-snapshot`
+//! flag.
+//!
+//! A snapshot is an object with three arrays, one entry per line:
+//!
+//! ```text
+//! {
+//! "nodes": [
+//!   {"id": 0, "type": "scope", "size": 184},
+//!   {"id": 1, "type": "function", "name": "f", "cell": 0, "size": 152},
+//!   {"id": 2, "type": "array", "cell": 1, "size": 120},
+//!   {"id": 3, "type": "string", "value": "abc", "size": 3}
+//! ],
+//! "edges": [
+//!   {"from": 0, "to": 1, "label": "f"},
+//!   {"from": 1, "to": 0, "label": "scope"},
+//!   {"from": 0, "to": 2, "label": "xs"},
+//!   {"from": 2, "to": 3, "label": "0"}
+//! ],
+//! "roots": [
+//!   {"label": "global", "node": 0}
+//! ]
+//! }
+//! ```
+//!
+//! Node types are `array` and `function` for heap cells, `scope` for the
+//! variables of a block, call or catch clause, and `string` and `error` for
+//! values that own text.  Numbers, booleans and undefined are not nodes.
+//! `size` is an estimate in bytes, as counted by `--gc-stats`; `cell` is the
+//! heap index of an array or function, stable for as long as it stays live,
+//! so the same object can be matched across two snapshots of one run.
+//! `name` is given for functions declared or defined with a name, and `value`
+//! for strings and errors.  Ids number the nodes in the order found from the
+//! roots, and are only meaningful within one snapshot.
+//!
+//! Edges are labelled with the index of an array element, the name of a
+//! variable held by a scope, `parent` from a scope to the one enclosing it,
+//! and `scope` and `this` from a function to what it captured.
+//!
+//! Roots are `global` for the global scope; `frame N` and `frame N this` for
+//! the scope and 'this' of each active call, where frame 0 is the running
+//! code and higher numbers are its callers; and `temporary` for values
+//! computed but not yet stored, such as an operand or argument.  Following
+//! edges back from a node to a root shows what keeps the node alive.
+
+use std::io;
+use std::io::prelude::*;
+
+pub struct Node {
+    pub kind: &'static str,
+    pub name: Option<String>,
+    pub value: Option<String>,
+    pub cell: Option<usize>,
+    pub size: usize
+}
+
+pub struct Snapshot {
+    nodes: Vec<Node>,
+    edges: Vec<(usize, usize, String)>,
+    roots: Vec<(String, usize)>
+}
+
+// JSON only requires quotes, backslashes and control characters be escaped
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 =>
+                escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot {nodes: Vec::new(), edges: Vec::new(), roots: Vec::new()}
+    }
+
+    /// Adds a node, returning its id
+    pub fn add_node(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    pub fn add_edge(&mut self, from: usize, to: usize, label: String) {
+        self.edges.push((from, to, label));
+    }
+
+    pub fn add_root(&mut self, label: String, node: usize) {
+        self.roots.push((label, node));
+    }
+
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{{")?;
+
+        writeln!(out, "\"nodes\": [")?;
+        for (id, node) in self.nodes.iter().enumerate() {
+            write!(out, "  {{\"id\": {}, \"type\": \"{}\"", id, node.kind)?;
+            if let Some(ref name) = node.name {
+                write!(out, ", \"name\": {}", json_string(name))?;
+            }
+            if let Some(ref value) = node.value {
+                write!(out, ", \"value\": {}", json_string(value))?;
+            }
+            if let Some(cell) = node.cell {
+                write!(out, ", \"cell\": {}", cell)?;
+            }
+            write!(out, ", \"size\": {}}}", node.size)?;
+            writeln!(out, "{}", if id + 1 < self.nodes.len() { "," } else { "" })?;
+        }
+        writeln!(out, "],")?;
+
+        writeln!(out, "\"edges\": [")?;
+        for (i, &(from, to, ref label)) in self.edges.iter().enumerate() {
+            write!(out, "  {{\"from\": {}, \"to\": {}, \"label\": {}}}",
+                   from, to, json_string(label))?;
+            writeln!(out, "{}", if i + 1 < self.edges.len() { "," } else { "" })?;
+        }
+        writeln!(out, "],")?;
+
+        writeln!(out, "\"roots\": [")?;
+        for (i, &(ref label, node)) in self.roots.iter().enumerate() {
+            write!(out, "  {{\"label\": {}, \"node\": {}}}",
+                   json_string(label), node)?;
+            writeln!(out, "{}", if i + 1 < self.roots.len() { "," } else { "" })?;
+        }
+        writeln!(out, "]")?;
+
+        writeln!(out, "}}")
+    }
+}
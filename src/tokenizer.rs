@@ -1,10 +1,13 @@
 use types::{JsishResult, JsishError, FStream};
 
+use ast::{Comment, TemplateChunk};
+
 use std::fmt;
 use std::fs::File;
-use std::io::prelude::*;
 use std::collections::HashMap;
 
+use unicode_xid::UnicodeXID;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     TkLbrace,
@@ -18,14 +21,31 @@ pub enum Token {
     TkQuestion,
     TkColon,
     TkDot,
+    TkEllipsis,
+    TkArrow,
     TkPlus,
     TkMinus,
     TkTimes,
     TkDivide,
     TkMod,
+    TkPow,
+    TkBitAnd,
+    TkBitOr,
+    TkBitXor,
+    TkBitNot,
+    TkLshift,
+    TkRshift,
+    TkUrshift,
     TkAnd,
     TkOr,
     TkAssign,
+    TkPlusAssign,
+    TkMinusAssign,
+    TkTimesAssign,
+    TkDivideAssign,
+    TkModAssign,
+    TkIncrement,
+    TkDecrement,
     TkEq,
     TkLt,
     TkLe,
@@ -33,6 +53,8 @@ pub enum Token {
     TkGe,
     TkNot,
     TkNe,
+    TkStrictEq,
+    TkStrictNe,
     TkElse,
     TkFalse,
     TkFunction,
@@ -45,12 +67,31 @@ pub enum Token {
     TkTypeof,
     TkUndefined,
     TkVar,
+    TkLet,
+    TkConst,
     TkWhile,
     TkGc,
     TkInUse,
+    TkHeapSnapshot,
+    TkThrow,
+    TkTry,
+    TkCatch,
+    TkFinally,
+    TkSwitch,
+    TkCase,
+    TkDefault,
+    TkBreak,
     TkNum(i64),
     TkId(String),
     TkString(String),
+    /// A template literal without substitutions
+    TkTemplate(TemplateChunk),
+    /// The text of a template literal up to its first `${`
+    TkTemplateHead(TemplateChunk),
+    /// The text between a `}` and the next `${`
+    TkTemplateMiddle(TemplateChunk),
+    /// The text between the last `}` and the closing backquote
+    TkTemplateTail(TemplateChunk),
     TkEof
 }
 
@@ -68,14 +109,31 @@ impl fmt::Display for Token {
             TkQuestion => "?",
             TkColon => ":",
             TkDot => ".",
+            TkEllipsis => "...",
+            TkArrow => "=>",
             TkPlus => "+",
             TkMinus => "-",
             TkTimes => "*",
             TkDivide => "/",
             TkMod => "%",
+            TkPow => "**",
+            TkBitAnd => "&",
+            TkBitOr => "|",
+            TkBitXor => "^",
+            TkBitNot => "~",
+            TkLshift => "<<",
+            TkRshift => ">>",
+            TkUrshift => ">>>",
             TkAnd => "&&",
             TkOr => "||",
             TkAssign => "=",
+            TkPlusAssign => "+=",
+            TkMinusAssign => "-=",
+            TkTimesAssign => "*=",
+            TkDivideAssign => "/=",
+            TkModAssign => "%=",
+            TkIncrement => "++",
+            TkDecrement => "--",
             TkEq => "==",
             TkLt => "<",
             TkLe => "<=",
@@ -83,6 +141,8 @@ impl fmt::Display for Token {
             TkGe => ">=",
             TkNot => "!",
             TkNe => "!=",
+            TkStrictEq => "===",
+            TkStrictNe => "!==",
             TkElse => "else",
             TkFalse => "false",
             TkFunction => "function",
@@ -95,13 +155,28 @@ impl fmt::Display for Token {
             TkTypeof => "typeof",
             TkUndefined => "undefined",
             TkVar => "var",
+            TkLet => "let",
+            TkConst => "const",
             TkWhile => "while",
             TkGc => "gc",
-            TkInUse => "InUse",
+            TkInUse => "inUse",
+            TkHeapSnapshot => "heapSnapshot",
+            TkThrow => "throw",
+            TkTry => "try",
+            TkCatch => "catch",
+            TkFinally => "finally",
+            TkSwitch => "switch",
+            TkCase => "case",
+            TkDefault => "default",
+            TkBreak => "break",
             TkEof => "eof",
             TkNum(n) => return write!(f, "{}", n),
             TkId(ref s) => return write!(f, "{}", s),
-            TkString(ref s) => return write!(f, "{}", s)
+            TkString(ref s) => return write!(f, "{}", s),
+            TkTemplate(ref c) => return write!(f, "`{}`", c.raw),
+            TkTemplateHead(ref c) => return write!(f, "`{}${{", c.raw),
+            TkTemplateMiddle(ref c) => return write!(f, "}}{}${{", c.raw),
+            TkTemplateTail(ref c) => return write!(f, "}}{}`", c.raw)
         };
 
         write!(f, "{}", c)
@@ -113,83 +188,90 @@ use tokenizer::Token::*;
 fn lookahead (itr: &mut FStream) -> JsishResult<char> {
     // Try and just read the file
     if let Some(&Ok(c)) = itr.peek() {
-        return Ok(c as char);
+        return Ok(c);
     }
 
     // Something went wrong, so we have to figure out what
     match itr.next() {
         None => Err(JsishError::from("Unexpected EOF")),
-        Some(Err(err)) => Err(JsishError::from(err)),
+        Some(Err(err)) => Err(err),
         _ => panic!("Peek and Next have divergent state")
     }
 }
 
-fn tokenize_symbol(itr: &mut FStream) -> JsishResult<Token> {
-    let mut single_symbols: HashMap<char, Token> =
-       vec![
-          ('{', 	TkLbrace),
-          ('}', 	TkRbrace),
-          ('(', 	TkLparen),
-          (')', 	TkRparen),
-          ('[', 	TkLbracket),
-          (']', 	TkRbracket),
-          (',', 	TkComma),
-          (';', 	TkSemi),
-          ('?', 	TkQuestion),
-          (':', 	TkColon),
-          ('.', 	TkDot),
-          ('+', 	TkPlus),
-          ('-', 	TkMinus),
-          ('*', 	TkTimes),
-          ('/', 	TkDivide),
-          ('%', 	TkMod),
-        ].into_iter().collect()
-    ;
-
-    let mut opt_eq_symbols: HashMap<char, (Token, Token)> =
-        vec![
-          ('=', (TkAssign, TkEq)),
-          ('<',	(TkLt, TkLe)),
-          ('>',	(TkGt, TkGe)),
-          ('!', (TkNot, TkNe)),
-       ].into_iter().collect()
-    ;
-
-    let c = lookahead(itr)?;
-
-    if let Some(tk) = single_symbols.remove(&c) {
-        itr.next();
-        return Ok(tk);
-    }
+fn symbol_table() -> HashMap<&'static str, Token> {
+    vec![
+      ("{",     TkLbrace),
+      ("}",     TkRbrace),
+      ("(",     TkLparen),
+      (")",     TkRparen),
+      ("[",     TkLbracket),
+      ("]",     TkRbracket),
+      (",",     TkComma),
+      (";",     TkSemi),
+      ("?",     TkQuestion),
+      (":",     TkColon),
+      (".",     TkDot),
+      ("+",     TkPlus),
+      ("-",     TkMinus),
+      ("*",     TkTimes),
+      ("**",    TkPow),
+      ("/",     TkDivide),
+      ("%",     TkMod),
+      ("&&",    TkAnd),
+      ("||",    TkOr),
+      ("&",     TkBitAnd),
+      ("|",     TkBitOr),
+      ("^",     TkBitXor),
+      ("~",     TkBitNot),
+      ("<<",    TkLshift),
+      (">>",    TkRshift),
+      (">>>",   TkUrshift),
+      ("=",     TkAssign),
+      ("=>",    TkArrow),
+      ("+=",    TkPlusAssign),
+      ("-=",    TkMinusAssign),
+      ("*=",    TkTimesAssign),
+      ("/=",    TkDivideAssign),
+      ("%=",    TkModAssign),
+      ("++",    TkIncrement),
+      ("--",    TkDecrement),
+      ("==",    TkEq),
+      ("===",   TkStrictEq),
+      ("<",     TkLt),
+      ("<=",    TkLe),
+      (">",     TkGt),
+      (">=",    TkGe),
+      ("!",     TkNot),
+      ("!=",    TkNe),
+      ("!==",   TkStrictNe),
+    ].into_iter().collect()
+}
 
-    if c == '&' {
-        itr.next();
-        if lookahead(itr)? == '&' {
-            itr.next();
-            return Ok(TkAnd);
-        }
-    }
+// Every prefix of a multi-character symbol is itself a symbol, so the
+// longest match can be found one character at a time.  The exception is
+// '...', which is matched separately since '..' is not a symbol.
+fn tokenize_symbol(itr: &mut FStream) -> JsishResult<Token> {
+    let mut symbols = symbol_table();
+    let mut symbol = String::new();
 
-    if c == '|' {
-        itr.next();
-        if lookahead(itr)? == '|' {
-            itr.next();
-            return Ok(TkOr);
-        }
-    }
+    while itr.peek().is_some() {
+        let mut longer = symbol.clone();
+        longer.push(lookahead(itr)?);
 
-    if let Some((wo_eq, w_eq)) = opt_eq_symbols.remove(&c) {
-        itr.next();
-        if itr.peek().is_some() && lookahead(itr)? == '=' {
+        if symbols.contains_key(longer.as_str()) {
             itr.next();
-            return Ok(w_eq);
+            symbol = longer;
         }
         else {
-            return Ok(wo_eq);
+            break;
         }
     }
 
-    Err(JsishError::from("Unknown token type"))
+    match symbols.remove(symbol.as_str()) {
+        Some(tk) => Ok(tk),
+        None => Err(JsishError::from("Unknown token type"))
+    }
 }
 
 fn recognize_keywords(tk_str: &str) -> Token {
@@ -206,9 +288,20 @@ fn recognize_keywords(tk_str: &str) -> Token {
       "typeof" => 	    TkTypeof,
       "undefined" =>    TkUndefined,
       "var" => 		    TkVar,
+      "let" =>          TkLet,
+      "const" =>        TkConst,
       "while" => 	    TkWhile,
       "gc" => 		    TkGc,
       "inUse" => 	    TkInUse,
+      "heapSnapshot" => TkHeapSnapshot,
+      "throw" =>        TkThrow,
+      "try" =>          TkTry,
+      "catch" =>        TkCatch,
+      "finally" =>      TkFinally,
+      "switch" =>       TkSwitch,
+      "case" =>         TkCase,
+      "default" =>      TkDefault,
+      "break" =>        TkBreak,
       tk_str =>         TkId(String::from(tk_str))
     }
 }
@@ -218,92 +311,354 @@ fn build_token(
     is_valid: &Fn (char) -> bool
     ) -> JsishResult<String> {
 
-    let mut token_vec = Vec::new();
+    let mut token = String::new();
 
     loop {
         if is_valid(lookahead(itr)?) {
-            token_vec.push(itr.next().expect("Itr Failure")? as u8);
+            token.push(itr.next().expect("Itr Failure")?);
         }
         else {
             break;
         }
     }
 
-    match String::from_utf8(token_vec) {
-        Ok(s) => Ok(s),
-        Err(err) => Err(JsishError::from(err))
-    }
+    Ok(token)
+}
+
+// Identifiers follow ECMAScript's ID_Start and ID_Continue rules
+fn is_id_start(c: char) -> bool {
+    UnicodeXID::is_xid_start(c) || c == '$' || c == '_'
+}
+
+fn is_id_continue(c: char) -> bool {
+    UnicodeXID::is_xid_continue(c) || c == '$' || c == '\u{200c}' ||
+        c == '\u{200d}'
 }
 
 fn tokenize_identifier(itr: &mut FStream) -> JsishResult<Token> {
-    let id_token = build_token(itr, &(|x| x.is_alphanumeric()))?;
+    let id_token = build_token(itr, &is_id_continue)?;
 
     Ok(recognize_keywords(&id_token))
 }
 
+fn radix_name(radix: u32) -> &'static str {
+    match radix {
+        2 => "binary",
+        8 => "octal",
+        16 => "hexadecimal",
+        _ => "decimal"
+    }
+}
+
+// Reads digits of the given radix, dropping the '_' separators allowed
+// between them
+fn read_digits(itr: &mut FStream, radix: u32) -> JsishResult<String> {
+    let mut digits = String::new();
+
+    while let Some(&Ok(c)) = itr.peek() {
+        if c.is_digit(radix) {
+            digits.push(c);
+            itr.next();
+        }
+        else if c == '_' {
+            itr.next();
+            let next_is_digit = match itr.peek() {
+                Some(&Ok(d)) => d.is_digit(radix),
+                _ => false
+            };
+            if digits.is_empty() || !next_is_digit {
+                return Err(JsishError::from(
+                    "numeric separator '_' must be between digits"));
+            }
+            // As in JavaScript, where 0_1 would read as a legacy octal
+            if radix == 10 && digits == "0" {
+                return Err(JsishError::from(
+                    "numeric separator '_' is not allowed after a leading 0"));
+            }
+        }
+        else if c.is_ascii_digit() {
+            return Err(JsishError::from(format!(
+                "invalid digit '{}' in {} literal", c, radix_name(radix))));
+        }
+        else if is_id_continue(c) {
+            return Err(JsishError::from(
+                "identifier starts immediately after numeric literal"));
+        }
+        else {
+            break;
+        }
+    }
+
+    Ok(digits)
+}
+
 fn tokenize_digits(itr: &mut FStream) -> JsishResult<Token> {
-    let num_token = build_token(itr, &(|x| x.is_digit(10)))?;
+    let prefix = if peek_is(itr, '0') {
+        match itr.peek_second() {
+            Some(&Ok(c @ 'x')) | Some(&Ok(c @ 'X')) => Some((c, 16)),
+            Some(&Ok(c @ 'o')) | Some(&Ok(c @ 'O')) => Some((c, 8)),
+            Some(&Ok(c @ 'b')) | Some(&Ok(c @ 'B')) => Some((c, 2)),
+            _ => None
+        }
+    }
+    else {
+        None
+    };
+
+    let radix = match prefix {
+        Some((c, radix)) => {
+            itr.next();
+            itr.next();
+            let no_digits = match itr.peek() {
+                Some(&Ok(d)) => !d.is_digit(radix) && !d.is_ascii_digit() &&
+                    d != '_',
+                _ => true
+            };
+            if no_digits {
+                return Err(JsishError::from(
+                    format!("missing digits after '0{}'", c)));
+            }
+            radix
+        }
+        None => 10
+    };
+
+    let num_token = read_digits(itr, radix)?;
+
+    // The digits are already validated, so only overflow can fail here
+    i64::from_str_radix(&num_token, radix)
+        .map(TkNum)
+        .map_err(|_| JsishError::from("numeric literal out of range"))
+}
+
+fn escape_error() -> JsishError {
+    JsishError::from("Invalid Escape Sequence")
+}
+
+fn read_hex_digit(itr: &mut FStream) -> JsishResult<u32> {
+    match itr.next() {
+        Some(Ok(c)) => c.to_digit(16).ok_or_else(escape_error),
+        Some(Err(err)) => Err(err),
+        None => Err(JsishError::from("Invalid String"))
+    }
+}
+
+fn read_hex_digits(itr: &mut FStream, count: usize) -> JsishResult<u32> {
+    let mut code = 0;
+    for _ in 0..count {
+        code = code * 16 + read_hex_digit(itr)?;
+    }
+    Ok(code)
+}
+
+// The code unit or code point of a \u escape, after the 'u'
+fn read_unicode_escape(itr: &mut FStream) -> JsishResult<u32> {
+    if !peek_is(itr, '{') {
+        return read_hex_digits(itr, 4);
+    }
+
+    itr.next();
+    let mut code = read_hex_digit(itr)?;
+    while !peek_is(itr, '}') {
+        code = code * 16 + read_hex_digit(itr)?;
+        if code > 0x10ffff {
+            return Err(escape_error());
+        }
+    }
+    itr.next();
+
+    Ok(code)
+}
+
+// A high surrogate escape must be followed by a low surrogate escape, since
+// strings cannot hold unpaired surrogates.
+fn parse_unicode_escape(itr: &mut FStream) -> JsishResult<char> {
+    let code = read_unicode_escape(itr)?;
+
+    if (0xd800..0xdc00).contains(&code) && peek_is(itr, '\\') &&
+        second_is(itr, 'u') {
+
+        itr.next();
+        itr.next();
+        let low = read_unicode_escape(itr)?;
+        if (0xdc00..0xe000).contains(&low) {
+            let pair = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+            return ::std::char::from_u32(pair).ok_or_else(escape_error);
+        }
+    }
+
+    ::std::char::from_u32(code).ok_or_else(escape_error)
+}
 
-    Ok(TkNum(i64::from_str_radix(&num_token, 10)?))
+fn is_line_terminator(c: char) -> bool {
+    c == '\n' || c == '\r' || c == '\u{2028}' || c == '\u{2029}'
 }
 
-fn parse_escape(itr: &mut FStream) -> JsishResult<char> {
+// Returns None for a line continuation, which contributes nothing to the
+// string
+fn parse_escape(itr: &mut FStream) -> JsishResult<Option<char>> {
     itr.next();
     match itr.next() {
         None => Err(JsishError::from("Invalid String")),
-        Some(Err(err)) => Err(JsishError::from(err)),
-        Some(Ok(c)) => match c as char {
-            '\\' => Ok('\\'),
-            '\"' => Ok('"'),
-            'n' => Ok('\n'),
-            'r' => Ok('\r'),
-            't' => Ok('\t'),
-            'b' => Ok('\x08'),
-            'v' => Ok('\x0b'),
-            'f' => Ok('\x0c'),
-            _ => Err(JsishError::from("Invalid Escape Sequence"))
+        Some(Err(err)) => Err(err),
+        Some(Ok(c)) => match c {
+            'n' => Ok(Some('\n')),
+            'r' => Ok(Some('\r')),
+            't' => Ok(Some('\t')),
+            'b' => Ok(Some('\x08')),
+            'v' => Ok(Some('\x0b')),
+            'f' => Ok(Some('\x0c')),
+            '0' if !lookahead_is_digit(itr) => Ok(Some('\0')),
+            'x' => {
+                let code = read_hex_digits(itr, 2)?;
+                Ok(::std::char::from_u32(code))
+            }
+            'u' => Ok(Some(parse_unicode_escape(itr)?)),
+            '\r' => {
+                if peek_is(itr, '\n') {
+                    itr.next();
+                }
+                Ok(None)
+            }
+            c if is_line_terminator(c) => Ok(None),
+            // Legacy octal escapes are not supported
+            c if c.is_ascii_digit() => Err(escape_error()),
+            c => Ok(Some(c))
         }
     }
 }
+
+fn lookahead_is_digit(itr: &mut FStream) -> bool {
+    match itr.peek() {
+        Some(&Ok(c)) => c.is_ascii_digit(),
+        _ => false
+    }
+}
+
 fn tokenize_string(itr: &mut FStream) -> JsishResult<Token> {
-    let mut token_vec = Vec::new();
-    itr.next();
+    let mut token = String::new();
+    let quote = itr.next().expect("Itr Failure")?;
 
     loop {
         if let Some(&Ok(c)) = itr.peek() {
-            match c as char {
-                '\\' => token_vec.push(parse_escape(itr)? as u8),
-                '\"' => {itr.next(); break},
-                _ => token_vec.push(itr.next().expect("Itr Failure")? as u8)
+            match c {
+                '\\' => token.extend(parse_escape(itr)?),
+                c if c == quote => {itr.next(); break},
+                _ => token.push(itr.next().expect("Itr Failure")?)
             }
         }
         else {
             match itr.next() {
                 None => return Err(JsishError::from("Invalid String")),
-                Some(Err(err)) => return Err(JsishError::from(err)),
+                Some(Err(err)) => return Err(err),
+                _ => panic!("Peek and Next have divergent state")
+            }
+        }
+    }
+
+    Ok(TkString(token))
+}
+
+// Reads template text up to and including the closing backquote or the `${`
+// of a substitution, reporting which one ended it. The raw text keeps escapes
+// as written; both forms normalize line terminators to '\n'.
+fn read_template_chunk(
+    itr: &mut FStream,
+    (line, column): (usize, usize)
+    ) -> JsishResult<(TemplateChunk, bool)> {
+
+    let mut cooked = String::new();
+    itr.start_recording();
+
+    let complete = loop {
+        let next_char = match itr.peek() {
+            Some(&Ok(c)) => Some(c),
+            _ => None
+        };
+
+        match next_char {
+            Some('`') => break true,
+            Some('$') if second_is(itr, '{') => break false,
+            Some('\\') => cooked.extend(parse_escape(itr)?),
+            Some('\r') => {
+                itr.next();
+                if peek_is(itr, '\n') {
+                    itr.next();
+                }
+                cooked.push('\n');
+            }
+            Some(_) => cooked.push(itr.next().expect("Itr Failure")?),
+            None => match itr.next() {
+                None => return Err(JsishError::from(
+                        format!("unterminated template literal starting at \
+                                line {}, column {}", line, column))),
+                Some(Err(err)) => return Err(err),
                 _ => panic!("Peek and Next have divergent state")
             }
         }
+    };
+
+    let raw = itr.stop_recording()
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    itr.next();
+    if !complete {
+        itr.next();
     }
 
-    match String::from_utf8(token_vec) {
-        Ok(s) => Ok(TkString(s)),
-        Err(err) => Err(JsishError::from(err))
+    Ok((TemplateChunk {cooked: cooked, raw: raw}, complete))
+}
+
+fn tokenize_template(itr: &mut FStream) -> JsishResult<Token> {
+    let start = (itr.line, itr.column);
+    itr.next();
+    let (chunk, complete) = read_template_chunk(itr, start)?;
+
+    Ok(if complete { TkTemplate(chunk) } else { TkTemplateHead(chunk) })
+}
+
+/// Continues a template literal after the `}` that closes a substitution.
+/// The parser calls this in place of `next_token`, since the text after the
+/// `}` is template text rather than tokens.
+pub fn next_template_token(itr: &mut FStream) -> JsishResult<Token> {
+    let start = (itr.line, itr.column);
+    let (chunk, complete) = read_template_chunk(itr, start)?;
+    itr.tokens += 1;
+
+    Ok(if complete { TkTemplateTail(chunk) } else { TkTemplateMiddle(chunk) })
+}
+
+fn tokenize_ellipsis(itr: &mut FStream) -> JsishResult<Token> {
+    itr.next();
+    itr.next();
+
+    if peek_is(itr, '.') {
+        itr.next();
+        Ok(TkEllipsis)
+    }
+    else {
+        Err(JsishError::from("Unknown token type"))
     }
 }
 
 fn diversify_tokens(itr: &mut FStream) -> JsishResult<Token> {
     let next_char = lookahead(itr)?;
 
-    if next_char.is_alphabetic() {
+    if is_id_start(next_char) {
         tokenize_identifier(itr)
     }
     else if next_char.is_digit(10) {
         tokenize_digits(itr)
     }
-    else if next_char == '"' {
+    else if next_char == '"' || next_char == '\'' {
         tokenize_string(itr)
     }
+    else if next_char == '`' {
+        tokenize_template(itr)
+    }
+    else if next_char == '.' && second_is(itr, '.') {
+        tokenize_ellipsis(itr)
+    }
     else {
         tokenize_symbol(itr)
     }
@@ -317,16 +672,19 @@ fn recognize_first_token(itr: &mut FStream) -> JsishResult<Token> {
     else {
         match itr.next() {
             None => Ok(TkEof),
-            Some(Err(err)) => Err(JsishError::from(err)),
+            Some(Err(err)) => Err(err),
             _ => panic!("Peek and Next have divergent state")
         }
     }
 }
 
-fn clear_whitespace(itr: &mut FStream) -> () {
+fn clear_whitespace(itr: &mut FStream) -> bool {
+    let mut saw_newline = false;
+
     loop {
         if let Some(&Ok(c)) = itr.peek() {
-            if (c as char).is_whitespace() {
+            if c.is_whitespace() {
+                saw_newline = saw_newline || c == '\n';
                 itr.next();
             }
             else {
@@ -337,16 +695,104 @@ fn clear_whitespace(itr: &mut FStream) -> () {
             break;
         }
     }
+
+    saw_newline
+}
+
+fn peek_is(itr: &mut FStream, c: char) -> bool {
+    match itr.peek() {
+        Some(&Ok(p)) => p == c,
+        _ => false
+    }
+}
+
+fn second_is(itr: &mut FStream, c: char) -> bool {
+    match itr.peek_second() {
+        Some(&Ok(p)) => p == c,
+        _ => false
+    }
+}
+
+fn read_line_comment(itr: &mut FStream) -> JsishResult<String> {
+    let mut comment = String::new();
+
+    while itr.peek().is_some() && !peek_is(itr, '\n') {
+        comment.push(itr.next().expect("Itr Failure")?);
+    }
+
+    Ok(comment)
+}
+
+fn read_block_comment(itr: &mut FStream) -> JsishResult<String> {
+    let (line, column) = (itr.line, itr.column);
+    let mut comment = String::new();
+    comment.push(itr.next().expect("Itr Failure")?);
+    comment.push(itr.next().expect("Itr Failure")?);
+
+    while !(peek_is(itr, '*') && second_is(itr, '/')) {
+        match itr.next() {
+            Some(c) => comment.push(c?),
+            None => return Err(JsishError::from(
+                    format!("unterminated comment starting at line {}, \
+                            column {}", line, column)))
+        }
+    }
+
+    comment.push(itr.next().expect("Itr Failure")?);
+    comment.push(itr.next().expect("Itr Failure")?);
+
+    Ok(comment)
+}
+
+// Skips whitespace and comments before the next token, saving the comments
+// for the parser to attach to the surrounding statements.
+fn skip_trivia(itr: &mut FStream) -> JsishResult<()> {
+    let mut own_line = itr.tokens == 0;
+
+    loop {
+        own_line = clear_whitespace(itr) || own_line;
+
+        let text = if peek_is(itr, '/') && second_is(itr, '/') {
+            read_line_comment(itr)?
+        }
+        else if peek_is(itr, '/') && second_is(itr, '*') {
+            read_block_comment(itr)?
+        }
+        else {
+            return Ok(());
+        };
+
+        let index = itr.tokens;
+        let multi_line = text.contains('\n');
+        itr.comments.push_back((index, Comment {text: text,
+                                                own_line: own_line}));
+        own_line = own_line || multi_line;
+    }
 }
 
 pub fn next_token(itr: &mut FStream) -> JsishResult<Token> {
-    clear_whitespace(itr);
-    recognize_first_token(itr)
+    skip_trivia(itr)?;
+    itr.token_line = itr.line;
+    let tk = recognize_first_token(itr)?;
+    itr.tokens += 1;
+    Ok(tk)
+}
+
+/// Removes and returns the pending comments that precede the token with the
+/// given index.
+pub fn take_comments(itr: &mut FStream, index: usize) -> Vec<Comment> {
+    let mut comments = Vec::new();
+
+    while itr.comments.front().is_some_and(|&(i, _)| i <= index) {
+        comments.push(itr.comments.pop_front().expect("Comment Failure").1);
+    }
+
+    comments
 }
 
 pub fn create_file_stream(filename: &str) -> JsishResult<FStream> {
     match File::open(filename) {
-        Ok(f) => Ok(f.bytes().peekable()),
+        Ok(f) => Ok(FStream::new(f)),
         Err(e) => Err(JsishError::from(e))
     }
 }
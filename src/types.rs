@@ -5,10 +5,13 @@ use std::str;
 use std::string;
 use std::num;
 
-use std::iter::Peekable;
-use std::io::Bytes;
+use std::collections::VecDeque;
+use std::io::{Bytes, BufReader};
+use std::io::prelude::*;
 use std::fs::File;
 
+use ast::Comment;
+
 #[derive(Debug)]
 pub enum JsishError {
     Message(String),
@@ -16,7 +19,163 @@ pub enum JsishError {
 }
 
 pub type JsishResult<T> = Result<T, JsishError>;
-pub type FStream = Peekable<Bytes<File>>;
+
+/// How sure a diagnostic is: an error is raised whenever its code runs, a
+/// warning only for some of the values that may reach it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+/// A problem found in a program without running it, at the line of the
+/// source element it is in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        write!(f, "line {}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+/// The characters of a UTF-8 source file, with two characters of lookahead,
+/// the position of the next character, and the comments skipped over by the
+/// tokenizer. While recording, consumed characters are also kept, so the
+/// tokenizer can recover the raw source of a token.
+pub struct FStream {
+    bytes: Bytes<BufReader<File>>,
+    ahead: VecDeque<JsishResult<char>>,
+    /// Byte offset of the first byte not yet decoded
+    offset: usize,
+    pub line: usize,
+    pub column: usize,
+    /// Line on which the last token produced began
+    pub token_line: usize,
+    /// Number of tokens produced so far
+    pub tokens: usize,
+    /// Comments not yet claimed by the parser, each tagged with the index of
+    /// the token that follows it
+    pub comments: VecDeque<(usize, Comment)>,
+    recording: Option<String>
+}
+
+impl FStream {
+    pub fn new(file: File) -> FStream {
+        FStream {
+            bytes: BufReader::new(file).bytes(),
+            ahead: VecDeque::new(),
+            offset: 0,
+            line: 1,
+            column: 1,
+            token_line: 1,
+            tokens: 0,
+            comments: VecDeque::new(),
+            recording: None
+        }
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(String::new());
+    }
+
+    pub fn stop_recording(&mut self) -> String {
+        self.recording.take().unwrap_or_default()
+    }
+
+    fn next_byte(&mut self) -> Option<JsishResult<u8>> {
+        let b = self.bytes.next();
+        if let Some(Ok(_)) = b {
+            self.offset += 1;
+        }
+        b.map(|r| r.map_err(JsishError::from))
+    }
+
+    fn decode(&mut self) -> Option<JsishResult<char>> {
+        let start = self.offset;
+        let invalid = || Err(JsishError::from(
+                format!("invalid UTF-8 at byte offset {}", start)));
+
+        let first = match self.next_byte()? {
+            Ok(b) => b,
+            Err(err) => return Some(Err(err))
+        };
+
+        let width = match first {
+            0x00..=0x7f => return Some(Ok(first as char)),
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return Some(invalid())
+        };
+
+        let mut buf = vec![first];
+        while buf.len() < width {
+            match self.next_byte() {
+                Some(Ok(b)) if b & 0xc0 == 0x80 => buf.push(b),
+                Some(Err(err)) => return Some(Err(err)),
+                _ => return Some(invalid())
+            }
+        }
+
+        // Rejects overlong encodings and surrogates
+        match str::from_utf8(&buf) {
+            Ok(s) => s.chars().next().map(Ok),
+            Err(_) => Some(invalid())
+        }
+    }
+
+    fn fill(&mut self, n: usize) {
+        while self.ahead.len() < n {
+            match self.decode() {
+                Some(c) => self.ahead.push_back(c),
+                None => break
+            }
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<&JsishResult<char>> {
+        self.fill(1);
+        self.ahead.front()
+    }
+
+    pub fn peek_second(&mut self) -> Option<&JsishResult<char>> {
+        self.fill(2);
+        self.ahead.get(1)
+    }
+}
+
+impl Iterator for FStream {
+    type Item = JsishResult<char>;
+
+    fn next(&mut self) -> Option<JsishResult<char>> {
+        self.fill(1);
+        let c = self.ahead.pop_front();
+
+        if let Some(Ok(c)) = c {
+            if let Some(ref mut recorded) = self.recording {
+                recorded.push(c);
+            }
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            }
+            else {
+                self.column += 1;
+            }
+        }
+
+        c
+    }
+}
 
 impl error::Error for JsishError {
     fn description(&self) -> &str {